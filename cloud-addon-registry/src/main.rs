@@ -4,7 +4,8 @@
 pub mod responder_type;
 pub mod routes;
 
-use cloud_addon_registry::create_rocket;
+use cloud_addon_registry::{create_rocket, google_stores};
+use cloud_auth_lib::config::ConfigSource;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use signal_hook::{iterator::Signals, SIGHUP, SIGINT, SIGKILL, SIGQUIT, SIGTERM};
@@ -24,6 +25,8 @@ pub fn main() -> Result<(), failure::Error> {
             std::process::exit(1);
         }
     });
-    create_rocket(5u32)?.launch();
+    let config_source = ConfigSource::from_env();
+    let (documents, tokens) = google_stores(&config_source)?;
+    create_rocket(5u32, &config_source, documents, tokens)?.launch();
     Ok(())
 }
@@ -64,6 +64,12 @@ impl From<firestore_db_and_auth::errors::FirebaseError> for MyResponder {
     }
 }
 
+impl From<cloud_auth_lib::CloudAuthError> for MyResponder {
+    fn from(err: cloud_auth_lib::CloudAuthError) -> MyResponder {
+        MyResponder::InternalError(err.to_string())
+    }
+}
+
 impl From<reqwest::Error> for MyResponder {
     fn from(err: reqwest::Error) -> MyResponder {
         MyResponder::InternalError(format!("{:?}", err))
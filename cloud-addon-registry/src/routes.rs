@@ -6,11 +6,11 @@ use ohx_addon_publish::addons;
 
 // External, controlled libraries
 use cloud_vault::{
-    guard_oauth_jwt_access, guard_rate_limiter::RateLimiter,
-};
-use firestore_db_and_auth::{
-    documents, sessions::service_account::Session as SASession,
+    guard_oauth_jwt_access, guard_rate_limiter::{IdentityRateLimiter, RateLimiter},
 };
+use cloud_auth_lib::dto::oauth::SCOPE_ADMIN;
+use cloud_auth_lib::{audit::AuditEvent, guard_audit::Auditor};
+use cloud_auth_lib::storage::DocumentStore;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
@@ -20,14 +20,10 @@ use rocket::{delete, get, post};
 
 // std
 use rocket_contrib::json::Json;
-use std::ops::Deref;
-use std::sync::Mutex;
+use std::sync::Arc;
 use std::collections::HashMap;
 use ohx_addon_publish::addons::{AddonRegistryEntry, AddonDetailedInfo};
 
-const CREDENTIALS_GOOGLE_SERVICE_ACCOUNT_INDEX: usize = 0;
-const CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX: usize = 1;
-
 /// Empty default route
 #[get("/")]
 pub fn index() -> &'static str {
@@ -41,11 +37,12 @@ pub struct RatingAccumulator {
     pub downloads: i64,
 }
 
-fn accumulate_ratings(session: &SASession, documents_to_remove: &mut Vec<String>, addon_stats_to_update: &mut HashMap<String, RatingAccumulator>) -> Result<(), MyResponder> {
-    let list: documents::List<db::RatingsInDB, _> = documents::list(session, "ratings");
-    for metadata_and_doc in list {
-        let (doc, metadata) = metadata_and_doc?;
-        // let name = documents::abs_to_rel(metadata.name.as_ref().unwrap());
+/// Collection and id of a processed document
+type DocumentPath = (&'static str, String);
+
+fn accumulate_ratings(store: &dyn DocumentStore, documents_to_remove: &mut Vec<DocumentPath>, addon_stats_to_update: &mut HashMap<String, RatingAccumulator>) -> Result<(), MyResponder> {
+    let list: Vec<(String, db::RatingsInDB)> = store.list("ratings")?;
+    for (id, doc) in list {
         info!("Found rating {} - {}", doc.addon_id, doc.rate);
 
         match addon_stats_to_update.get_mut(&doc.addon_id) {
@@ -68,17 +65,15 @@ fn accumulate_ratings(session: &SASession, documents_to_remove: &mut Vec<String>
             }
         };
 
-        documents_to_remove.push(metadata.name);
+        documents_to_remove.push(("ratings", id));
     }
     Ok(())
 }
 
 
-fn accumulate_downloads(session: &SASession, documents_to_remove: &mut Vec<String>, addon_stats_to_update: &mut HashMap<String, RatingAccumulator>) -> Result<(), MyResponder> {
-    let list: documents::List<db::DownloadsInDB, _> = documents::list(session, "downloads");
-    for metadata_and_doc in list {
-        let (doc, metadata) = metadata_and_doc?;
-        // let name = documents::abs_to_rel(metadata.name.as_ref().unwrap());
+fn accumulate_downloads(store: &dyn DocumentStore, documents_to_remove: &mut Vec<DocumentPath>, addon_stats_to_update: &mut HashMap<String, RatingAccumulator>) -> Result<(), MyResponder> {
+    let list: Vec<(String, db::DownloadsInDB)> = store.list("downloads")?;
+    for (id, doc) in list {
         info!("Found download entry {} - {}", doc.addon_id, doc.installed);
 
         match addon_stats_to_update.get_mut(&doc.addon_id) {
@@ -94,7 +89,7 @@ fn accumulate_downloads(session: &SASession, documents_to_remove: &mut Vec<Strin
             }
         };
 
-        documents_to_remove.push(metadata.name);
+        documents_to_remove.push(("downloads", id));
     }
     Ok(())
 }
@@ -146,29 +141,26 @@ fn update_stats_for_repo(stats: &mut addons::AddonMapStats, github_client: &gith
 #[get("/update_stats")]
 pub fn update_stats(
     oauth_user: guard_oauth_jwt_access::OAuthIdentity,
-    firebase: rocket::State<Mutex<SASession>>,
+    store: rocket::State<Arc<dyn DocumentStore>>,
     github_client: rocket::State<github::GithubClient>,
 ) -> Result<String, MyResponder> {
-    // Only the google account is allowed to call this endpoint
-    if oauth_user.credentials_index != CREDENTIALS_GOOGLE_SERVICE_ACCOUNT_INDEX {
-        return Err(MyResponder::access_denied("ONLY_SERVICE_ACCOUNT",
-                                              "Only the google CI account is allowed to call this endpoint",
+    // Only service accounts (client credentials grant) with the admin scope are allowed to call this endpoint
+    if !oauth_user.scopes.contains(SCOPE_ADMIN) {
+        return Err(MyResponder::access_denied("ADMIN_SCOPE_REQUIRED",
+                                              "Requires the admin scope",
         ));
     }
 
     let timestamp = chrono::Utc::now().timestamp_millis();
 
-    let session_mutex = firebase.lock()?;
-    let session: &SASession = session_mutex.deref();
-
-    let mut documents_to_remove: Vec<String> = Vec::new();
+    let mut documents_to_remove: Vec<DocumentPath> = Vec::new();
     let mut addon_stats_to_update: HashMap<String, RatingAccumulator> = HashMap::new();
 
     // Fetch ratings and accumulate into documents_to_remove and addon_stats_to_update
-    accumulate_ratings(session, &mut documents_to_remove, &mut addon_stats_to_update)?;
+    accumulate_ratings(&**store, &mut documents_to_remove, &mut addon_stats_to_update)?;
 
     // Fetch downloads and accumulate into documents_to_remove and addon_stats_to_update
-    accumulate_downloads(session, &mut documents_to_remove, &mut addon_stats_to_update)?;
+    accumulate_downloads(&**store, &mut documents_to_remove, &mut addon_stats_to_update)?;
 
     let (mut stats, sha) = github::get_metadata_content(&github_client)?;
 
@@ -216,9 +208,9 @@ pub fn update_stats(
         github::put_metadata_file(&github_client, &sha, &stats, "Statistics update")?;
     }
 
-    for path in documents_to_remove {
-        match documents::delete(session, documents::abs_to_rel(&path), false) {
-            Err(e) => warn!("Error while removing firestore document at {} - {}", &path, e),
+    for (collection, id) in documents_to_remove {
+        match store.delete(collection, &id) {
+            Err(e) => warn!("Error while removing document at {}/{} - {}", collection, &id, e),
             _ => {}
         };
     }
@@ -236,10 +228,11 @@ pub fn addon_put(
     request: Json<addons::AddonFileEntryPlusStats>,
     github_client: rocket::State<github::GithubClient>,
     oauth_user: guard_oauth_jwt_access::OAuthIdentity,
-    _rate_limiter: RateLimiter,
+    auditor: Auditor,
+    _rate_limiter: IdentityRateLimiter,
 ) -> Result<(), MyResponder> {
-    // Only the google account is allowed to call this endpoint
-    if oauth_user.credentials_index != CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX || oauth_user.user_id.is_none() {
+    // Only users are allowed to call this endpoint, not service accounts
+    if oauth_user.user_id.is_none() {
         return Err(MyResponder::access_denied("OHX_ACCOUNT_ONLY",
                                               "Only an OHX account is allowed to call this endpoint",
         ));
@@ -251,6 +244,8 @@ pub fn addon_put(
     let addon = addons.get(&request.x_ohx_registry.id);
     let commit_reason = if let Some(addon) = addon {
         if addon.owner != user_id {
+            auditor.record(AuditEvent::failure("addon.publish").with_user(Some(&user_id))
+                .with_client(oauth_user.client_id.as_ref()).with_target(request.x_ohx_registry.id.as_str()));
             return Err(MyResponder::bad_request("WRONG_OWNER",
                                                 "You are not the author of this Addon",
             ));
@@ -291,13 +286,16 @@ pub fn addon_put(
 
     github::put_data_detail_file(&github_client, &request.x_ohx_registry.id, sha_details, &addon_detail, &commit_reason)?;
 
-    addons.insert(request.x_ohx_registry.id.clone(), AddonRegistryEntry {
+    let addon_id = request.x_ohx_registry.id.clone();
+    addons.insert(addon_id.clone(), AddonRegistryEntry {
         entry: request.into_inner().x_ohx_registry,
         owner: user_id.clone(),
         last_updated: chrono::Utc::now().timestamp_millis(),
     });
 
     github::put_data_file(&github_client, &sha, &addons, &commit_reason)?;
+    auditor.record(AuditEvent::success("addon.publish").with_user(Some(&user_id))
+        .with_client(oauth_user.client_id.as_ref()).with_target(addon_id));
 
     Ok(())
 }
@@ -308,11 +306,12 @@ pub fn addon_delete(
     force: Option<bool>,
     github_client: rocket::State<github::GithubClient>,
     oauth_user: guard_oauth_jwt_access::OAuthIdentity,
-    _rate_limiter: RateLimiter,
+    auditor: Auditor,
+    _rate_limiter: IdentityRateLimiter,
 ) -> Result<(), MyResponder> {
 
-    // Only the google account is allowed to call this endpoint
-    if oauth_user.credentials_index != CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX || oauth_user.user_id.is_none() {
+    // Only users are allowed to call this endpoint, not service accounts
+    if oauth_user.user_id.is_none() {
         return Err(MyResponder::access_denied("OHX_ACCOUNT_ONLY",
                                               "Only an OHX account is allowed to call this endpoint",
         ));
@@ -324,6 +323,8 @@ pub fn addon_delete(
     let addon = addons.get_mut(&addon_id);
     if let Some(addon) = addon {
         if addon.owner != user_id {
+            auditor.record(AuditEvent::failure("addon.delete").with_user(Some(&user_id))
+                .with_client(oauth_user.client_id.as_ref()).with_target(addon_id));
             return Err(MyResponder::bad_request("WRONG_OWNER",
                                                 "You are not the author of this Addon",
             ));
@@ -337,6 +338,8 @@ pub fn addon_delete(
         }
 
         github::put_data_file(&github_client, &sha, &addons, &format!("Addon removed: {}", &addon_id))?;
+        auditor.record(AuditEvent::success("addon.delete").with_user(Some(&user_id))
+            .with_client(oauth_user.client_id.as_ref()).with_target(addon_id));
         return Ok(());
     }
 
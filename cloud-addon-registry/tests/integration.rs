@@ -1,8 +1,8 @@
 #![feature(proc_macro_hygiene, decl_macro)]
 
-use cloud_addon_registry::create_rocket;
+use cloud_addon_registry::{create_rocket, GITHUB_CREDENTIALS, OHX_ADMIN_ACCOUNT, OHX_AUTH_JWKS};
 use cloud_addon_lib::{dto::{db,addons}, github};
-use cloud_auth_lib::Credentials;
+use cloud_auth_lib::config::ConfigSource;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
@@ -13,7 +13,8 @@ use cloud_vault::jwt::create_jwt_encoded_for_user;
 
 use chrono::Duration;
 
-use firestore_db_and_auth::{credentials::Credentials as DBCredentials, ServiceSession, documents};
+use cloud_auth_lib::storage::{DocumentStore, MemoryDocumentStore, MemoryTokenStore};
+use std::sync::Arc;
 
 impl From<String> for ErrorResult {
     fn from(str: String) -> Self {
@@ -23,7 +24,7 @@ impl From<String> for ErrorResult {
 
 #[test]
 fn parse_extensions_github_file() {
-    let client = github::create_client().unwrap();
+    let client = github::create_client(&ConfigSource::from_env().get(GITHUB_CREDENTIALS).unwrap()).unwrap();
     let url =
         "https://raw.githubusercontent.com/openhab-nodes/addons-registry/master/extensions.json";
     let r = client.0.get(url).send().unwrap().text().unwrap();
@@ -56,6 +57,33 @@ fn delete_tests(client: &rocket::local::Client, access_token: &str) {
     r.dispatch();
 }
 
+fn cors_tests(client: &rocket::local::Client) {
+    // Preflight of an allowed origin and method
+    let response = client.options("/addon/ohx-ci-test-addon")
+        .header(Header::new("Origin", "https://www.openhabx.com"))
+        .header(Header::new("Access-Control-Request-Method", "DELETE"))
+        .header(Header::new("Access-Control-Request-Headers", "authorization"))
+        .dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+    assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), Some("https://www.openhabx.com"));
+    assert_eq!(response.headers().get_one("Access-Control-Allow-Methods"), Some("DELETE"));
+    assert!(response.headers().get_one("Access-Control-Allow-Credentials").is_none());
+
+    // Method not allowed for this route
+    let response = client.options("/addon/ohx-ci-test-addon")
+        .header(Header::new("Origin", "https://www.openhabx.com"))
+        .header(Header::new("Access-Control-Request-Method", "PUT"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    assert!(response.headers().get_one("Access-Control-Allow-Origin").is_none());
+
+    // Foreign origin
+    let response = client.get("/")
+        .header(Header::new("Origin", "https://example.com"))
+        .dispatch();
+    assert!(response.headers().get_one("Access-Control-Allow-Origin").is_none());
+}
+
 fn add_addon_tests(client: &rocket::local::Client, addons_file: &mut addons::AddonFileEntryPlusStats, access_token: &str) {
 
     // Test add addon - Fail not preprocessed
@@ -137,8 +165,8 @@ fn update_addon_tests(client: &rocket::local::Client, addons_file: &mut addons::
     assert_eq!(response.status(), Status::Ok);
 }
 
-fn stats_tests(client: &rocket::local::Client, firebase: &ServiceSession, google_access_token: &str) {
-    let github_client = github::create_client().unwrap();
+fn stats_tests(client: &rocket::local::Client, store: &dyn DocumentStore, admin_access_token: &str) {
+    let github_client = github::create_client(&ConfigSource::from_env().get(GITHUB_CREDENTIALS).unwrap()).unwrap();
 
     // Get rating from before
     let (rating, _sha) = github::get_metadata_content(&github_client).unwrap();
@@ -156,23 +184,23 @@ fn stats_tests(client: &rocket::local::Client, firebase: &ServiceSession, google
     };
 
     // Write ratings and downloads document
-    documents::write(firebase, "ratings", Some("ci_demo"), &stat_update, documents::WriteOptions::default()).unwrap();
-    documents::write(firebase, "downloads", Some("ci_demo"), &dl_update, documents::WriteOptions::default()).unwrap();
+    store.write("ratings", "ci_demo", &stat_update).unwrap();
+    store.write("downloads", "ci_demo", &dl_update).unwrap();
 
     let mut request = client.get("/update_stats");
     request.add_header(Header::new(
         "Authorization",
-        format!("Bearer {}", google_access_token),
+        format!("Bearer {}", admin_access_token),
     ));
     let mut response = request.dispatch();
     println!("Update Ok: {}", response.body_string().unwrap_or_default());
     assert_eq!(response.status(), Status::Ok);
 
     // Check that ratings and downloads document have been used
-    let r: Result<db::RatingsInDB, _> = documents::read(firebase, "ratings", "ci_demo");
-    assert!(r.is_err());
-    let r: Result<db::DownloadsInDB, _> = documents::read(firebase, "downloads", "ci_demo");
-    assert!(r.is_err());
+    let r: Option<db::RatingsInDB> = store.read("ratings", "ci_demo").unwrap();
+    assert!(r.is_none());
+    let r: Option<db::DownloadsInDB> = store.read("downloads", "ci_demo").unwrap();
+    assert!(r.is_none());
 
     // Check that rating is updated
     if let Some(rating) = rating.get("ohx-ci-test-addon") {
@@ -186,23 +214,15 @@ fn stats_tests(client: &rocket::local::Client, firebase: &ServiceSession, google
 
 #[test]
 fn integration() -> Result<(), failure::Error> {
-    let rocket = create_rocket(100)?;
-
-    let (_, google_access_token, _) = Credentials::load_and_check(
-        include_str!("../secrets/google-ci-key.json"),
-        &[
-            include_str!("../secrets/securetoken@system.gserviceaccount.com.json"),
-            include_str!("../secrets/travisci-deployer@openhabx.iam.gserviceaccount.com.json"),
-        ],
-        None::<&[&str]>,
-    )?;
-
-    let (credentials, _, _) = Credentials::load_and_check(
-        include_str!("../secrets/ohx_admin_account.json"),
-        &[
-            include_str!("../secrets/ohx_oauth_key.json"),
-        ],
-        None::<&[&str]>,
+    let store = Arc::new(MemoryDocumentStore::new());
+    let config_source = ConfigSource::from_env();
+    let rocket = create_rocket(100, &config_source, store.clone(), Arc::new(MemoryTokenStore::new()))?;
+
+    // Like an access token of the client credentials grant of the auth service
+    let (credentials, admin_access_token, _) = config_source.credentials(
+        OHX_ADMIN_ACCOUNT,
+        &[OHX_AUTH_JWKS],
+        Some(&["admin"]),
     )?;
 
     let client = rocket::local::Client::new(rocket).expect("valid rocket instance");
@@ -217,16 +237,6 @@ fn integration() -> Result<(), failure::Error> {
     let access_token_other_user = create_jwt_encoded_for_user(&credentials, None::<&[&str]>, Duration::hours(1), Some(credentials.client_id.clone()),
                                                               "demo_user_2".to_owned(), "email".to_owned())?;
 
-    let firebase_credentials = DBCredentials::new(
-        include_str!("../secrets/firebase-account.json"),
-        &[
-            include_str!("../secrets/openhabx-device@openhabx.iam.gserviceaccount.com.json"),
-            include_str!("../secrets/securetoken@system.gserviceaccount.com.json"),
-        ],
-    )?;
-
-    let firebase = ServiceSession::new(firebase_credentials)?;
-
     let addons_file = addons::open_validate_addons_file("tests/addon.yml").unwrap();
     let mut addons_file = addons::AddonFileEntryPlusStats {
         services: addons_file.services,
@@ -236,9 +246,10 @@ fn integration() -> Result<(), failure::Error> {
         size: 112,
     };
 
+    cors_tests(&client);
     delete_tests(&client, &access_token);
     add_addon_tests(&client, &mut addons_file, &access_token);
-    stats_tests(&client, &firebase, &google_access_token);
+    stats_tests(&client, &*store, &admin_access_token);
     update_addon_tests(&client, &mut addons_file, &access_token, &access_token_other_user);
 
     // Remove test addon
@@ -0,0 +1,259 @@
+//! # Audit log
+//! Security relevant actions (code grants, token refreshes, revocations, secret reads, addon publishes, ...)
+//! are recorded as structured [`AuditEvent`]s: Who (user id, oauth client, ip) did what (action) to which target
+//! with which outcome.
+//!
+//! Events are written to an [`AuditSink`]. The sink of a service is selected by "audit_sink.txt"
+//! of the runtime configuration:
+//! * "documents": The "audit_events" collection of the document store (Firestore). The default if the service has one.
+//! * "stdout": One json line per event on stdout, for Stackdriver. The default otherwise.
+//! * "file:<path>": One json line per event, appended to the given file.
+//!
+//! Actions are named "{object}.{verb}", for example "token.refresh" or "addon.publish".
+//! The [`crate::guard_audit::Auditor`] request guard adds the client ip to the events of a request.
+
+use crate::config::ConfigSource;
+use crate::storage::{DocumentStore, QueryOperator};
+use crate::CloudAuthError;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// The runtime configuration file that selects the audit sink
+pub const AUDIT_SINK_FILE: &str = "audit_sink.txt";
+/// The document store collection of [`DocumentAuditSink`]
+pub const AUDIT_COLLECTION: &str = "audit_events";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditEvent {
+    pub id: String,
+    /// Unix timestamp
+    pub timestamp: i64,
+    /// The emitting service, like "cloud-auth"
+    #[serde(default)]
+    pub service: String,
+    /// What happened, like "token.refresh"
+    pub action: String,
+    pub outcome: AuditOutcome,
+    /// The user that performed the action or that is affected by it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<String>,
+    /// The oauth client that performed the action
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// The affected object, like a session id, a secret name or an addon id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// The client ip address of the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(action: &str, outcome: AuditOutcome) -> AuditEvent {
+        AuditEvent {
+            id: uuid::Uuid::new_v4().to_string(),
+            timestamp: chrono::Utc::now().timestamp(),
+            service: String::new(),
+            action: action.to_owned(),
+            outcome,
+            uid: None,
+            client_id: None,
+            target: None,
+            ip: None,
+        }
+    }
+
+    pub fn success(action: &str) -> AuditEvent {
+        AuditEvent::new(action, AuditOutcome::Success)
+    }
+
+    pub fn failure(action: &str) -> AuditEvent {
+        AuditEvent::new(action, AuditOutcome::Failure)
+    }
+
+    pub fn with_user<S: Into<String>>(mut self, uid: Option<S>) -> AuditEvent {
+        self.uid = uid.map(|f| f.into());
+        self
+    }
+
+    pub fn with_client<S: Into<String>>(mut self, client_id: Option<S>) -> AuditEvent {
+        self.client_id = client_id.map(|f| f.into());
+        self
+    }
+
+    pub fn with_target<S: Into<String>>(mut self, target: S) -> AuditEvent {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn with_ip<S: Into<String>>(mut self, ip: Option<S>) -> AuditEvent {
+        self.ip = ip.map(|f| f.into());
+        self
+    }
+}
+
+pub trait AuditSink: Send + Sync {
+    fn write(&self, event: &AuditEvent) -> Result<(), CloudAuthError>;
+    /// The most recent events of the given user, newest first. Not all sinks support queries.
+    fn query_user(&self, uid: &str, limit: usize) -> Result<Vec<AuditEvent>, CloudAuthError>;
+}
+
+/// Sorts newest first and keeps the first `limit` events
+fn newest(mut events: Vec<AuditEvent>, limit: usize) -> Vec<AuditEvent> {
+    events.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    events.truncate(limit);
+    events
+}
+
+/// Writes events as json lines to stdout. Stackdriver picks up the "severity" and "message" fields.
+pub struct StdoutAuditSink;
+
+impl AuditSink for StdoutAuditSink {
+    fn write(&self, event: &AuditEvent) -> Result<(), CloudAuthError> {
+        let mut line = serde_json::to_value(event)?;
+        line["severity"] = "NOTICE".into();
+        line["message"] = format!("audit {} {:?}", event.action, event.outcome).into();
+        println!("{}", line);
+        Ok(())
+    }
+
+    fn query_user(&self, _uid: &str, _limit: usize) -> Result<Vec<AuditEvent>, CloudAuthError> {
+        Err(CloudAuthError::Generic("The stdout audit sink does not support queries"))
+    }
+}
+
+/// Appends events as json lines to a file
+pub struct JsonLinesAuditSink {
+    path: PathBuf,
+    file: Mutex<std::fs::File>,
+}
+
+impl JsonLinesAuditSink {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Result<JsonLinesAuditSink, CloudAuthError> {
+        let path = path.into();
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(JsonLinesAuditSink { path, file: Mutex::new(file) })
+    }
+}
+
+impl AuditSink for JsonLinesAuditSink {
+    fn write(&self, event: &AuditEvent) -> Result<(), CloudAuthError> {
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        let mut file = self.file.lock().map_err(|_| CloudAuthError::Generic("Audit log file poisoned"))?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    fn query_user(&self, uid: &str, limit: usize) -> Result<Vec<AuditEvent>, CloudAuthError> {
+        let content = std::fs::read_to_string(&self.path)?;
+        let events = content.lines()
+            .filter_map(|line| serde_json::from_str::<AuditEvent>(line).ok())
+            .filter(|event| event.uid.as_ref().map(|f| f.as_str()) == Some(uid))
+            .collect();
+        Ok(newest(events, limit))
+    }
+}
+
+/// Stores events in the "audit_events" collection of a document store
+pub struct DocumentAuditSink {
+    documents: Arc<dyn DocumentStore>,
+}
+
+impl DocumentAuditSink {
+    pub fn new(documents: Arc<dyn DocumentStore>) -> DocumentAuditSink {
+        DocumentAuditSink { documents }
+    }
+}
+
+impl AuditSink for DocumentAuditSink {
+    fn write(&self, event: &AuditEvent) -> Result<(), CloudAuthError> {
+        self.documents.write(AUDIT_COLLECTION, &event.id, event)
+    }
+
+    fn query_user(&self, uid: &str, limit: usize) -> Result<Vec<AuditEvent>, CloudAuthError> {
+        let events: Vec<(String, AuditEvent)> = self.documents.query(AUDIT_COLLECTION, "uid", QueryOperator::Equal, uid)?;
+        Ok(newest(events.into_iter().map(|(_, event)| event).collect(), limit))
+    }
+}
+
+/// The audit log of a service. Must be managed by the rocket instance for the [`crate::guard_audit::Auditor`] guard.
+pub struct AuditLog {
+    service: String,
+    sink: Arc<dyn AuditSink>,
+}
+
+impl AuditLog {
+    pub fn new(service: &str, sink: Arc<dyn AuditSink>) -> AuditLog {
+        AuditLog { service: service.to_owned(), sink }
+    }
+
+    /// Selects the sink by [`AUDIT_SINK_FILE`]. Without a configuration the given document store is used,
+    /// or stdout if the service has no document store.
+    pub fn from_config(config_source: &ConfigSource, service: &str, documents: Option<Arc<dyn DocumentStore>>) -> Result<AuditLog, CloudAuthError> {
+        let configured = config_source.optional(AUDIT_SINK_FILE)?.map(|f| f.trim().to_owned());
+        let sink: Arc<dyn AuditSink> = match (configured.as_ref().map(|f| f.as_str()), documents) {
+            (None, Some(documents)) | (Some("documents"), Some(documents)) => Arc::new(DocumentAuditSink::new(documents)),
+            (None, None) | (Some("stdout"), _) => Arc::new(StdoutAuditSink),
+            (Some("documents"), None) => return Err(CloudAuthError::Generic("The audit sink \"documents\" requires a document store")),
+            (Some(value), _) if value.starts_with("file:") => Arc::new(JsonLinesAuditSink::new(&value["file:".len()..])?),
+            (Some(value), _) => return Err(CloudAuthError::GenericOwned(format!("Unknown audit sink {}", value)))
+        };
+        Ok(AuditLog::new(service, sink))
+    }
+
+    /// Writes the event. A failing sink is logged, but does not fail the request.
+    pub fn record(&self, mut event: AuditEvent) {
+        event.service = self.service.clone();
+        if let Err(e) = self.sink.write(&event) {
+            error!("Failed to write audit event {} {:?}: {}", event.action, event.target, e);
+        }
+    }
+
+    /// The most recent events of the given user, newest first
+    pub fn query_user(&self, uid: &str, limit: usize) -> Result<Vec<AuditEvent>, CloudAuthError> {
+        self.sink.query_user(uid, limit)
+    }
+}
+
+#[test]
+fn audit_log_test() {
+    use crate::storage::MemoryDocumentStore;
+
+    let documents: Arc<dyn DocumentStore> = Arc::new(MemoryDocumentStore::new());
+    let log = AuditLog::new("test", Arc::new(DocumentAuditSink::new(documents.clone())));
+    let mut older = AuditEvent::success("token.refresh").with_user(Some("a")).with_client(Some("client")).with_target("family");
+    older.timestamp -= 10;
+    log.record(older);
+    log.record(AuditEvent::failure("vault.retrieve").with_user(Some("a")).with_ip(Some("127.0.0.1")));
+    log.record(AuditEvent::success("token.refresh").with_user(Some("b")));
+
+    let events = log.query_user("a", 10).unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].action, "vault.retrieve");
+    assert_eq!(events[0].outcome, AuditOutcome::Failure);
+    assert_eq!(events[0].service, "test");
+    assert_eq!(events[1].target, Some("family".to_owned()));
+    assert_eq!(log.query_user("a", 1).unwrap().len(), 1);
+
+    let path = std::env::temp_dir().join(format!("audit_log_test_{}.jsonl", uuid::Uuid::new_v4()));
+    let sink = JsonLinesAuditSink::new(&path).unwrap();
+    sink.write(&events[0]).unwrap();
+    sink.write(&AuditEvent::success("addon.publish").with_user(Some("b"))).unwrap();
+    assert_eq!(sink.query_user("a", 10).unwrap(), vec![events[0].clone()]);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(StdoutAuditSink.query_user("a", 10).is_err());
+}
@@ -0,0 +1,121 @@
+//! # Authorization codes
+//! Granted authorization codes of the code grant and device flow are stored in the [`TokenStore`] (Redis)
+//! under "authcode.{code}" until they are exchanged into tokens or expire.
+//!
+//! The tokens, the client, the redirect uri, the PKCE code challenge and the OpenID Connect ID token of a code
+//! are stored as one value.
+//! A code is redeemed with [`AuthorizationCodeStore::take`], which reads and removes the value atomically.
+//! Of concurrent token requests with the same code only one receives the tokens.
+
+use crate::storage::TokenStore;
+use crate::CloudAuthError;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// The token store key prefix of all authorization codes
+pub const AUTHORIZATION_CODE_NAMESPACE: &str = "authcode.";
+
+/// The token store key of an authorization code
+pub fn authorization_code_key(code: &str) -> String {
+    format!("{}{}", AUTHORIZATION_CODE_NAMESPACE, code)
+}
+
+/// The signed tokens of a granted authorization request
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuthorizationGrant {
+    /// The client of the authorization request. Only this client can redeem the code.
+    #[serde(default)]
+    pub client_id: String,
+    /// The redirect uri of the authorization request, if the client passed one.
+    /// The token request must contain the same uri (RFC 6749, 4.1.3).
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub redirect_uri: Option<String>,
+    pub access_token: String,
+    /// Only issued for the "offline_access" scope
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub refresh_token: Option<String>,
+    /// The PKCE code challenge (RFC 7636) of the authorization request
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub code_challenge: Option<String>,
+    /// Only issued for the "openid" scope
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub id_token: Option<String>,
+    /// Incremental authorization: The refresh token family that is replaced by the refresh token of this grant
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub family_id: Option<String>,
+}
+
+pub struct AuthorizationCodeStore {
+    tokens: Arc<dyn TokenStore>,
+}
+
+impl AuthorizationCodeStore {
+    pub fn new(tokens: Arc<dyn TokenStore>) -> AuthorizationCodeStore {
+        AuthorizationCodeStore { tokens }
+    }
+
+    /// Stores the grant for the given code for `expires_in` seconds.
+    /// Returns false if the code has already been granted.
+    pub fn insert(&self, code: &str, grant: &AuthorizationGrant, expires_in: usize) -> Result<bool, CloudAuthError> {
+        let value = serde_json::to_string(grant)?;
+        self.tokens.set_if_absent(&authorization_code_key(code), &value, expires_in)
+    }
+
+    /// Returns true if the code has been granted and is not yet redeemed
+    pub fn contains(&self, code: &str) -> Result<bool, CloudAuthError> {
+        Ok(self.tokens.ttl(&authorization_code_key(code))?.is_some())
+    }
+
+    /// Returns and removes the grant of the given code. A code can only be taken once.
+    pub fn take(&self, code: &str) -> Result<Option<AuthorizationGrant>, CloudAuthError> {
+        match self.tokens.take(&authorization_code_key(code))? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// All codes that are granted and not yet redeemed, together with their grants
+    pub fn list(&self) -> Result<Vec<(String, AuthorizationGrant)>, CloudAuthError> {
+        let mut result = Vec::new();
+        for key in self.tokens.keys(AUTHORIZATION_CODE_NAMESPACE)? {
+            // Might have been redeemed or expired in the meantime
+            if let Some(value) = self.tokens.get(&key)? {
+                let code = key[AUTHORIZATION_CODE_NAMESPACE.len()..].to_owned();
+                result.push((code, serde_json::from_str(&value)?));
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[test]
+fn authorization_code_store_test() {
+    use crate::storage::MemoryTokenStore;
+
+    let tokens: Arc<dyn TokenStore> = Arc::new(MemoryTokenStore::new());
+    tokens.set("unrelated", "value", 60).unwrap();
+    let codes = AuthorizationCodeStore::new(tokens.clone());
+
+    let grant = AuthorizationGrant {
+        client_id: "client".to_owned(),
+        redirect_uri: Some("https://example.com/oauth".to_owned()),
+        access_token: "access".to_owned(),
+        refresh_token: None,
+        code_challenge: Some("challenge".to_owned()),
+        id_token: None,
+        family_id: None,
+    };
+    assert!(codes.insert("code", &grant, 60).unwrap());
+    assert!(!codes.insert("code", &grant, 60).unwrap());
+    assert!(codes.contains("code").unwrap());
+    assert!(tokens.get("authcode.code").unwrap().is_some());
+    assert_eq!(codes.list().unwrap(), vec![("code".to_owned(), grant.clone())]);
+
+    assert_eq!(codes.take("code").unwrap(), Some(grant));
+    assert!(codes.take("code").unwrap().is_none());
+    assert!(!codes.contains("code").unwrap());
+    assert!(codes.list().unwrap().is_empty());
+}
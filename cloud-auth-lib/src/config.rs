@@ -0,0 +1,153 @@
+//! # Runtime configuration
+//! Credentials and other secrets are read at startup instead of being compiled into the binaries.
+//! A build therefore does not require the secrets directory and a rotated secret only requires a restart.
+//!
+//! A value is identified by its file name, for example "redis.txt". It is looked up in this order:
+//! 1. The environment variable with the upper cased name, non alphanumeric characters replaced by "_" ("REDIS_TXT").
+//!    Binary values (see [`ConfigSource::bytes`]) are expected to be base64 encoded.
+//! 2. The file that the environment variable with a "_FILE" suffix points to ("REDIS_TXT_FILE").
+//! 3. The file with the given name in the secrets directory. That is "secrets" in the working directory,
+//!    or the directory given by the `SECRETS_DIR` environment variable, for example a mounted secret volume.
+
+use crate::jwt::TokenValidationResult;
+use crate::{CloudAuthError, Credentials};
+use std::path::PathBuf;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// The secrets directory if `SECRETS_DIR` is not set
+const DEFAULT_SECRETS_DIR: &str = "secrets";
+
+pub struct ConfigSource {
+    secrets_dir: PathBuf,
+}
+
+/// The environment variable name of a value: "redis.txt" -> "REDIS_TXT"
+pub fn env_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect()
+}
+
+impl ConfigSource {
+    pub fn new<P: Into<PathBuf>>(secrets_dir: P) -> ConfigSource {
+        ConfigSource { secrets_dir: secrets_dir.into() }
+    }
+
+    /// Uses the secrets directory given by the `SECRETS_DIR` environment variable or "secrets"
+    pub fn from_env() -> ConfigSource {
+        ConfigSource::new(std::env::var("SECRETS_DIR").unwrap_or(DEFAULT_SECRETS_DIR.to_owned()))
+    }
+
+    pub fn secrets_dir(&self) -> &PathBuf {
+        &self.secrets_dir
+    }
+
+    /// Names must not be able to escape the secrets directory
+    fn checked_name(name: &str) -> Result<&str, CloudAuthError> {
+        if name.is_empty() || name.starts_with('.') || name.contains(|c| c == '/' || c == '\\') {
+            return Err(CloudAuthError::GenericOwned(format!("Invalid configuration name {}", name)));
+        }
+        Ok(name)
+    }
+
+    /// Returns the raw value and whether it has been read from an environment variable
+    fn lookup(&self, name: &str) -> Result<Option<(Vec<u8>, bool)>, CloudAuthError> {
+        let env_name = env_name(Self::checked_name(name)?);
+        if let Ok(value) = std::env::var(&env_name) {
+            return Ok(Some((value.into_bytes(), true)));
+        }
+        let path = match std::env::var(format!("{}_FILE", env_name)) {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => self.secrets_dir.join(name)
+        };
+        match std::fs::read(&path) {
+            Ok(content) => Ok(Some((content, false))),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(CloudAuthError::GenericOwned(format!("Failed to read {}: {}", path.display(), e)))
+        }
+    }
+
+    fn missing(&self, name: &str) -> CloudAuthError {
+        CloudAuthError::GenericOwned(format!("Configuration {} not found. Set {} or {}_FILE or provide {}",
+                                             name, env_name(name), env_name(name), self.secrets_dir.join(name).display()))
+    }
+
+    /// Returns the text value with the given name or None if it is not configured
+    pub fn optional(&self, name: &str) -> Result<Option<String>, CloudAuthError> {
+        match self.lookup(name)? {
+            Some((value, _)) => Ok(Some(String::from_utf8(value)
+                .map_err(|_| CloudAuthError::GenericOwned(format!("Configuration {} is not valid utf8", name)))?)),
+            None => Ok(None)
+        }
+    }
+
+    /// Returns the text value with the given name
+    pub fn get(&self, name: &str) -> Result<String, CloudAuthError> {
+        self.optional(name)?.ok_or_else(|| self.missing(name))
+    }
+
+    /// Returns the binary value with the given name. Environment variables are base64 decoded.
+    pub fn bytes(&self, name: &str) -> Result<Vec<u8>, CloudAuthError> {
+        match self.lookup(name)? {
+            Some((value, true)) => Ok(base64::decode(&value)?),
+            Some((value, false)) => Ok(value),
+            None => Err(self.missing(name))
+        }
+    }
+
+    /// The names of all files in the secrets directory
+    pub fn list(&self) -> Result<Vec<String>, CloudAuthError> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.secrets_dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if Self::checked_name(name).is_ok() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Loads the credentials with the given name and the public keys of the given jwks names
+    /// and validates them with [`Credentials::load_and_check`].
+    pub fn credentials<L, T>(&self, credentials_name: &str, jwks_names: &[&str], scope: Option<L>) -> Result<(Credentials, String, TokenValidationResult), CloudAuthError>
+        where L: IntoIterator<Item=T>, T: AsRef<str> {
+        let credentials_file = self.get(credentials_name)?;
+        let jwks_files = jwks_names.iter().map(|name| self.get(name)).collect::<Result<Vec<_>, _>>()?;
+        let jwks_files: Vec<&str> = jwks_files.iter().map(|f| f.as_str()).collect();
+        Credentials::load_and_check(&credentials_file, &jwks_files, scope)
+            .map_err(|e| CloudAuthError::GenericOwned(format!("Invalid credentials {}: {}", credentials_name, e)))
+    }
+}
+
+#[test]
+fn config_source_test() {
+    let id = uuid::Uuid::new_v4().to_simple().to_string();
+    let directory = std::env::temp_dir().join(format!("config_source_test_{}", id));
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("file_value.txt"), "from file").unwrap();
+    // Unique names: The process environment is shared with tests that run in parallel
+    let env_value = format!("env_value_{}.txt", id);
+    let env_binary = format!("env_binary_{}.bin", id);
+    std::env::set_var(env_name(&env_value), "from env");
+    std::env::set_var(env_name(&env_binary), base64::encode(&[1u8, 2, 3]));
+
+    let config = ConfigSource::new(&directory);
+    assert_eq!(config.get("file_value.txt").unwrap(), "from file");
+    assert_eq!(config.get(&env_value).unwrap(), "from env");
+    assert_eq!(config.bytes(&env_binary).unwrap(), vec![1u8, 2, 3]);
+    assert_eq!(config.bytes("file_value.txt").unwrap(), b"from file".to_vec());
+    assert!(config.optional("missing.txt").unwrap().is_none());
+    assert!(config.get("missing.txt").is_err());
+    assert!(config.get("../file_value.txt").is_err());
+    assert_eq!(config.list().unwrap(), vec!["file_value.txt".to_owned()]);
+
+    std::env::remove_var(env_name(&env_value));
+    std::env::remove_var(env_name(&env_binary));
+    std::fs::remove_dir_all(&directory).unwrap();
+}
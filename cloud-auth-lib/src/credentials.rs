@@ -2,17 +2,73 @@ use serde::{Deserialize, Serialize};
 
 use std::collections::BTreeMap;
 use std::sync::Arc;
-use chrono::Duration;
+use chrono::{Duration, Utc};
+use ring::signature::KeyPair;
 
 use crate::jwt::{create_jwt_encoded, verify_access_token, JWKSetDTO, TokenValidationResult, create_jwt_encoded_for_user};
 use crate::CloudAuthError;
 use crate::pem_to_der;
 
+/// The signature algorithm of an own key. It is derived from the key material.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyAlgorithm {
+    RS256,
+    ES256,
+    EdDSA,
+}
+
+impl Default for KeyAlgorithm {
+    fn default() -> Self {
+        KeyAlgorithm::RS256
+    }
+}
+
+impl KeyAlgorithm {
+    /// The jws algorithm. EdDSA is not supported by biscuit and handled separately.
+    pub fn signature_algorithm(&self) -> Option<biscuit::jwa::SignatureAlgorithm> {
+        use biscuit::jwa::SignatureAlgorithm;
+        match self {
+            KeyAlgorithm::RS256 => Some(SignatureAlgorithm::RS256),
+            KeyAlgorithm::ES256 => Some(SignatureAlgorithm::ES256),
+            KeyAlgorithm::EdDSA => None
+        }
+    }
+
+    /// The "alg" name (RFC 7518, RFC 8037)
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyAlgorithm::RS256 => "RS256",
+            KeyAlgorithm::ES256 => "ES256",
+            KeyAlgorithm::EdDSA => "EdDSA"
+        }
+    }
+}
+
 /// This is not defined in the json file and computed
 #[derive(Default)]
 pub(crate) struct Keys {
     pub pub_key: BTreeMap<String, Arc<biscuit::jws::Secret>>,
+    /// The algorithm of each public key. Keys that are not listed (for example keys from a jwks file) are RSA keys.
+    pub pub_key_algorithm: BTreeMap<String, KeyAlgorithm>,
+    /// The signing secret for RS256 and ES256
     pub secret: Option<Arc<biscuit::jws::Secret>>,
+    /// The signing key for EdDSA
+    pub ed25519: Option<Arc<ring::signature::Ed25519KeyPair>>,
+    /// The algorithm of the active signing key
+    pub algorithm: KeyAlgorithm,
+    /// Retirement times of key ids (seconds since the unix epoch)
+    pub retire_at: BTreeMap<String, i64>,
+}
+
+/// A previous signing key. It is not used for signing anymore,
+/// but tokens signed with it are still accepted until the key is retired.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct PreviousKey {
+    pub private_key_id: String,
+    pub private_key: String,
+    /// Seconds since the unix epoch. The key is neither published nor used for verification after this time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retire_at: Option<i64>,
 }
 
 /// Service account credentials
@@ -21,6 +77,7 @@ pub(crate) struct Keys {
 /// for verifying Google Cloud tokens.
 ///
 /// The private key is used for signing java web tokens (jwk).
+/// Previous private keys (see [`PreviousKey`]) are only used for verification until they are retired.
 #[derive(Serialize, Deserialize, Default, Clone)]
 pub struct Credentials {
     pub project_id: String,
@@ -28,13 +85,90 @@ pub struct Credentials {
     pub private_key: String,
     pub client_email: String,
     pub client_id: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previous_keys: Vec<PreviousKey>,
     #[serde(default, skip)]
     pub(crate) keys: Keys,
 }
 
 impl Clone for Keys {
     fn clone(&self) -> Self {
-        Self { pub_key: Default::default(), secret: None }
+        Self {
+            pub_key: Default::default(),
+            pub_key_algorithm: Default::default(),
+            secret: None,
+            ed25519: None,
+            algorithm: Default::default(),
+            retire_at: Default::default(),
+        }
+    }
+}
+
+/// A parsed private key
+enum KeyMaterial {
+    Rsa(ring::signature::RsaKeyPair),
+    Ecdsa(ring::signature::EcdsaKeyPair),
+    Ed25519(ring::signature::Ed25519KeyPair),
+}
+
+impl KeyMaterial {
+    /// Parses a PEM encoded PKCS#8 RSA, P-256 or Ed25519 private key
+    fn new(private_key: &str) -> Result<KeyMaterial, CloudAuthError> {
+        use ring::signature::{RsaKeyPair, EcdsaKeyPair, Ed25519KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+
+        let der = pem_to_der(private_key)?;
+        if let Ok(key_pair) = RsaKeyPair::from_pkcs8(&der) {
+            return Ok(KeyMaterial::Rsa(key_pair));
+        }
+        if let Ok(key_pair) = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &der) {
+            return Ok(KeyMaterial::Ecdsa(key_pair));
+        }
+        let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der)
+            .map_err(|_| CloudAuthError::Generic("Invalid private key in credentials file. Must be a RSA, P-256 or Ed25519 key."))?;
+        Ok(KeyMaterial::Ed25519(key_pair))
+    }
+
+    fn algorithm(&self) -> KeyAlgorithm {
+        match self {
+            KeyMaterial::Rsa(_) => KeyAlgorithm::RS256,
+            KeyMaterial::Ecdsa(_) => KeyAlgorithm::ES256,
+            KeyMaterial::Ed25519(_) => KeyAlgorithm::EdDSA,
+        }
+    }
+
+    /// The public key for jwt verification: A DER encoded RSAPublicKey, an uncompressed P-256 point
+    /// or the 32 byte Ed25519 public key.
+    fn public_key(&self) -> Vec<u8> {
+        match self {
+            KeyMaterial::Rsa(key_pair) => key_pair.public_key().as_ref().to_vec(),
+            KeyMaterial::Ecdsa(key_pair) => key_pair.public_key().as_ref().to_vec(),
+            KeyMaterial::Ed25519(key_pair) => key_pair.public_key().as_ref().to_vec(),
+        }
+    }
+
+    /// The public part of the key pair as JSON Web Key
+    fn jwk(&self, key_id: &str) -> serde_json::Value {
+        use base64::{encode_config, Config, CharacterSet};
+
+        let config = Config::new(CharacterSet::UrlSafe, false);
+        match self {
+            KeyMaterial::Rsa(key_pair) => {
+                let e = encode_config(key_pair.public_key().exponent().big_endian_without_leading_zero(), config.clone());
+                let n = encode_config(key_pair.public_key().modulus().big_endian_without_leading_zero(), config);
+                serde_json::json!({ "kid": key_id, "e": e, "n": n, "kty": "RSA", "alg": "RS256", "use": "sig" })
+            }
+            KeyMaterial::Ecdsa(key_pair) => {
+                // An uncompressed point: 0x04 | x (32 bytes) | y (32 bytes)
+                let point = key_pair.public_key().as_ref();
+                let x = encode_config(&point[1..33], config.clone());
+                let y = encode_config(&point[33..65], config);
+                serde_json::json!({ "kid": key_id, "crv": "P-256", "x": x, "y": y, "kty": "EC", "alg": "ES256", "use": "sig" })
+            }
+            KeyMaterial::Ed25519(key_pair) => {
+                let x = encode_config(key_pair.public_key().as_ref(), config);
+                serde_json::json!({ "kid": key_id, "crv": "Ed25519", "x": x, "kty": "OKP", "alg": "EdDSA", "use": "sig" })
+            }
+        }
     }
 }
 
@@ -64,8 +198,13 @@ impl Credentials {
     }
 
     /// Find the secret in the jwt set that matches the given key id, if any.
-    /// Used for jws validation
+    /// Used for jws validation. Retired keys are not returned.
     pub fn decode_secret(&self, kid: &str) -> Option<Arc<biscuit::jws::Secret>> {
+        if let Some(retire_at) = self.keys.retire_at.get(kid) {
+            if *retire_at <= Utc::now().timestamp() {
+                return None;
+            }
+        }
         self.keys.pub_key.get(kid).and_then(|f| Some(f.clone()))
     }
 
@@ -73,17 +212,66 @@ impl Credentials {
         self.keys.secret.as_ref()
     }
 
-    /// Compute the Rsa keypair. It is not used for jwt verification, but for creating own jwts and sign them.
+    /// The signature algorithm of the active signing key
+    pub fn signing_algorithm(&self) -> KeyAlgorithm {
+        self.keys.algorithm
+    }
+
+    /// The signature algorithm for verifying tokens with the given key id
+    pub fn verification_algorithm(&self, kid: &str) -> KeyAlgorithm {
+        self.keys.pub_key_algorithm.get(kid).cloned().unwrap_or_default()
+    }
+
+    /// Compute the keypair for creating own jwts and sign them. The signature algorithm
+    /// (RS256, ES256 or EdDSA) depends on the type of the private key.
+    ///
+    /// The public keys of the active and all previous, not yet retired keys are added for jwt verification.
     pub fn compute_secret(&mut self) -> Result<(), CloudAuthError> {
-        use ring::signature;
         use biscuit::jws::Secret;
 
-        let vec = pem_to_der(&self.private_key)?;
-        let key_pair = signature::RsaKeyPair::from_pkcs8(&vec).map_err(|f| CloudAuthError::Generic(f.description_()))?;
-        self.keys.secret = Some(Arc::new(Secret::RsaKeyPair(Arc::new(key_pair))));
+        let key_material = KeyMaterial::new(&self.private_key)?;
+        self.add_own_pub_key(self.private_key_id.clone(), &key_material);
+        self.keys.algorithm = key_material.algorithm();
+        match key_material {
+            KeyMaterial::Rsa(key_pair) => self.keys.secret = Some(Arc::new(Secret::RsaKeyPair(Arc::new(key_pair)))),
+            KeyMaterial::Ecdsa(key_pair) => self.keys.secret = Some(Arc::new(Secret::EcdsaKeyPair(Arc::new(key_pair)))),
+            KeyMaterial::Ed25519(key_pair) => self.keys.ed25519 = Some(Arc::new(key_pair)),
+        };
+
+        let now = Utc::now().timestamp();
+        for previous_key in self.previous_keys.clone().iter() {
+            if let Some(retire_at) = previous_key.retire_at {
+                self.keys.retire_at.insert(previous_key.private_key_id.clone(), retire_at);
+                if retire_at <= now {
+                    continue;
+                }
+            }
+            let key_material = KeyMaterial::new(&previous_key.private_key)?;
+            self.add_own_pub_key(previous_key.private_key_id.clone(), &key_material);
+        }
         Ok(())
     }
 
+    fn add_own_pub_key(&mut self, key_id: String, key_material: &KeyMaterial) {
+        use biscuit::jws::Secret;
+        self.keys.pub_key_algorithm.insert(key_id.clone(), key_material.algorithm());
+        self.keys.pub_key.insert(key_id, Arc::new(Secret::PublicKey(key_material.public_key())));
+    }
+
+    /// The JSON Web Key Set (JWKS) of the active and all previous, not yet retired keys.
+    pub fn public_jwks(&self) -> Result<serde_json::Value, CloudAuthError> {
+        let mut keys = vec![KeyMaterial::new(&self.private_key)?.jwk(&self.private_key_id)];
+
+        let now = Utc::now().timestamp();
+        for previous_key in self.previous_keys.iter() {
+            if previous_key.retire_at.map_or(false, |retire_at| retire_at <= now) {
+                continue;
+            }
+            keys.push(KeyMaterial::new(&previous_key.private_key)?.jwk(&previous_key.private_key_id));
+        }
+        Ok(serde_json::json!({ "keys": keys }))
+    }
+
     /// There hereby added key in pkcs8-der format is used for jwt verification.
     /// This is similar to the keys added by [add_jwks].
     #[allow(dead_code)]
@@ -0,0 +1,106 @@
+//! # Token deny-list
+//! Revoked access tokens are identified by their unique id ("jti" claim) and stored in the [`TokenStore`] (Redis)
+//! until they would expire anyway.
+//!
+//! Lookups are cached in-process: Denied ids until the token expires, not denied ids for a few seconds.
+//! The [`crate::guard_oauth_jwt_access::OAuthIdentity`] guard consults the deny-list. Every service that accepts
+//! access tokens must manage one, sharing the token store of the auth service. The guard fails otherwise.
+
+use crate::storage::TokenStore;
+use crate::CloudAuthError;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// A not denied token id is checked against the token store again after this amount of seconds
+const NOT_DENIED_CACHE_SECS: i64 = 10;
+/// Outdated cache entries are removed if the cache grows beyond this size
+const CACHE_PRUNE_SIZE: usize = 1000;
+
+/// The token store key of a revoked access token id
+pub fn deny_list_key(jti: &str) -> String {
+    format!("revoked.{}", jti)
+}
+
+enum CacheEntry {
+    /// Denied until the token expiry
+    Denied(i64),
+    /// Not denied, checked at the given time
+    NotDenied(i64),
+}
+
+pub struct TokenDenyList {
+    tokens: Arc<dyn TokenStore>,
+    cache: RwLock<HashMap<String, CacheEntry>>,
+}
+
+impl TokenDenyList {
+    pub fn new(tokens: Arc<dyn TokenStore>) -> TokenDenyList {
+        TokenDenyList { tokens, cache: RwLock::new(HashMap::new()) }
+    }
+
+    /// Deny the token with the given id until the given expiry (unix timestamp).
+    /// Already expired tokens are ignored.
+    pub fn deny(&self, jti: &str, expiry: i64) -> Result<(), CloudAuthError> {
+        let now = chrono::Utc::now().timestamp();
+        let remaining = expiry - now;
+        if remaining <= 0 {
+            return Ok(());
+        }
+        self.tokens.set(&deny_list_key(jti), "revoked", remaining as usize)?;
+        self.cache_insert(jti, CacheEntry::Denied(expiry), now);
+        Ok(())
+    }
+
+    fn cache_insert(&self, jti: &str, entry: CacheEntry, now: i64) {
+        if let Ok(mut cache) = self.cache.write() {
+            if cache.len() > CACHE_PRUNE_SIZE {
+                cache.retain(|_, entry| match entry {
+                    CacheEntry::Denied(expiry) => *expiry > now,
+                    CacheEntry::NotDenied(checked_at) => *checked_at + NOT_DENIED_CACHE_SECS > now
+                });
+            }
+            cache.insert(jti.to_owned(), entry);
+        }
+    }
+
+    /// Returns true if the token with the given id has been revoked.
+    /// An error is returned if the token store is not reachable. Callers must treat that as denied.
+    pub fn is_denied(&self, jti: &str) -> Result<bool, CloudAuthError> {
+        let now = chrono::Utc::now().timestamp();
+        if let Ok(cache) = self.cache.read() {
+            match cache.get(jti) {
+                Some(CacheEntry::Denied(expiry)) if *expiry > now => return Ok(true),
+                Some(CacheEntry::NotDenied(checked_at)) if *checked_at + NOT_DENIED_CACHE_SECS > now => return Ok(false),
+                _ => {}
+            }
+        }
+
+        let entry = match self.tokens.ttl(&deny_list_key(jti))? {
+            None => CacheEntry::NotDenied(now),
+            Some(ttl) => CacheEntry::Denied(now + ttl)
+        };
+        let denied = match entry {
+            CacheEntry::Denied(_) => true,
+            CacheEntry::NotDenied(_) => false
+        };
+        self.cache_insert(jti, entry, now);
+        Ok(denied)
+    }
+}
+
+#[test]
+fn deny_list_test() {
+    use crate::storage::MemoryTokenStore;
+
+    let deny_list = TokenDenyList::new(Arc::new(MemoryTokenStore::new()));
+    let now = chrono::Utc::now().timestamp();
+    assert!(!deny_list.is_denied("jti").unwrap());
+    deny_list.deny("jti", now + 60).unwrap();
+    assert!(deny_list.is_denied("jti").unwrap());
+    // Expired tokens are not stored
+    deny_list.deny("expired", now - 1).unwrap();
+    assert!(!deny_list.is_denied("expired").unwrap());
+}
@@ -1,6 +1,10 @@
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeSet;
 
+/// A refresh token, stored under the hash of the token.
+///
+/// Refresh tokens are rotated on each use. All tokens that descend from the same authorization
+/// share a family id. A superseded token is kept to detect a reuse, which revokes the whole family.
 #[derive(Serialize, Deserialize)]
 pub struct AccessTokenInDB {
     pub uid: String,
@@ -8,4 +12,58 @@ pub struct AccessTokenInDB {
     pub client_id: String,
     pub scopes: BTreeSet<String>,
     pub issued_at: i64,
+    /// Empty for tokens issued before rotation was introduced. The token hash is the family id then.
+    #[serde(default)]
+    pub family_id: String,
+    /// Set if this token has been exchanged for a new one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub superseded_at: Option<i64>,
+    /// The hash of the refresh token that replaced this one by a rotation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub successor: Option<String>,
+    /// The id (jti) and expiry of the access token that has been issued together with this refresh token.
+    /// It is added to the deny-list if the token family is revoked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_token_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_token_expiry: Option<i64>,
+    /// Unix timestamp of the last refresh. Carried over to the rotated token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<i64>,
+    /// The client ip address of the last refresh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_ip: Option<String>,
+    /// How often the token family has been used to refresh an access token
+    #[serde(default)]
+    pub use_count: u32,
+}
+
+impl AccessTokenInDB {
+    /// The time of the last refresh or the issue time for never used tokens
+    pub fn last_used(&self) -> i64 {
+        self.last_used_at.unwrap_or(self.issued_at)
+    }
+}
+
+/// The scopes that a user granted to a client, stored under [`ConsentInDB::id`].
+/// Granting further scopes to the same client extends the scope set.
+#[derive(Serialize, Deserialize)]
+pub struct ConsentInDB {
+    pub uid: String,
+    pub client_id: String,
+    pub scopes: BTreeSet<String>,
+    /// Unix timestamp of the last grant
+    pub granted_at: i64,
+}
+
+impl ConsentInDB {
+    /// The document id of the consent of the given user for the given client
+    pub fn id(uid: &str, client_id: &str) -> String {
+        format!("{}.{}", uid, client_id)
+    }
+
+    /// Returns true if the user already granted all of the given scopes
+    pub fn covers(&self, scopes: &BTreeSet<String>) -> bool {
+        scopes.is_subset(&self.scopes)
+    }
 }
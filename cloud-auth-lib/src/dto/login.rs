@@ -56,6 +56,8 @@ pub struct DeviceFlowResponse {
     pub device_code: String,
     pub user_code: String,
     pub verification_uri: String,
+    #[serde(default)]
+    pub verification_uri_complete: Option<String>,
     pub interval: u32,
     pub expires_in: i64,
 }
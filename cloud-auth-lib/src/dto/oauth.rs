@@ -1,4 +1,5 @@
 use crate::tools::{scope_serialize, scope_deserialize};
+use crate::rate_limit::Quota;
 
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeSet};
@@ -8,6 +9,9 @@ use rocket::response::{content, Redirect};
 use rocket::Responder;
 
 pub const SCOPE_OFFLINE_ACCESS: &str = "offline_access";
+pub const SCOPE_OPENID: &str = "openid";
+/// Management endpoints, like the client registration, require this scope
+pub const SCOPE_ADMIN: &str = "admin";
 
 #[derive(UriDisplayQuery, FromForm)]
 pub struct GenerateCodeDTO {
@@ -18,13 +22,19 @@ pub struct GenerateCodeDTO {
     pub response_type: String, // "code" or "device"
     pub scope: Option<String>, // offline_access -> return a refresh_token
     pub state: Option<String>,
+    pub code_challenge: Option<String>, // PKCE (RFC 7636)
+    pub code_challenge_method: Option<String>, // Only "S256" is supported
+    pub nonce: Option<String>, // OpenID Connect: Returned within the id_token
+    pub prompt: Option<String>, // OpenID Connect: "none", "login" or "consent"
+    pub max_age: Option<i64>, // OpenID Connect: Maximum seconds since the last login of the user
+    pub include_granted_scopes: Option<bool>, // Add the scopes that the user already granted to the client
 }
 
 pub type GenerateTokenRequest = LenientForm<GenerateCodeDTO>;
 
 use rocket::UriDisplayQuery;
 
-#[derive(Deserialize, UriDisplayQuery)]
+#[derive(Serialize, Deserialize, UriDisplayQuery)]
 pub struct AuthPageRedirectUri {
     pub client_id: String,
     pub client_secret: Option<String>,
@@ -33,6 +43,9 @@ pub struct AuthPageRedirectUri {
     pub response_type: String, // "code" or "device"
     pub scope: Option<String>, // offline_access -> return a refresh_token
     pub state: Option<String>,
+    /// "login" if the UI must ask the user to login again, "consent" if the UI must show the consent screen
+    /// even if the user already granted the scopes
+    pub prompt: Option<String>,
     // Add
     pub code: String,
     pub unsigned: String,
@@ -50,6 +63,12 @@ pub struct TokenDTO {
     pub username: Option<String>,      // for grant_type "password"
     #[serde(skip_serializing_if = "Option::is_none")]
     pub password: Option<String>,      // for grant_type "password"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code_verifier: Option<String>, // for grant_type "authorization_code" if a code_challenge was given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,         // for grant_type "client_credentials" (defaults to all client scopes) and "refresh_token" (narrows the access token)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_uri: Option<String>,  // for grant_type "authorization_code" if the authorization request contained one
 
     pub client_id: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -59,26 +78,165 @@ pub struct TokenDTO {
 
 pub type TokenRequest = LenientForm<TokenDTO>;
 
+/// A device authorization request (RFC 8628, 3.1)
+#[derive(Default, Serialize, Deserialize, FromForm, UriDisplayQuery)]
+pub struct DeviceAuthorizationDTO {
+    pub client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+pub type DeviceAuthorizationRequest = LenientForm<DeviceAuthorizationDTO>;
+
 #[derive(Deserialize, Serialize)]
 pub struct GrantRequest {
+    /// The client of the authorization request. The "unsigned" token is bound to it.
+    pub client_id: String,
     pub unsigned: String,
     pub code: String,
     pub scopes: BTreeSet<String>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct RevokeDTO {
+/// A token revocation request (RFC 7009)
+#[derive(Default, Serialize, Deserialize, FromForm, UriDisplayQuery)]
+pub struct RevokeDTO {
     pub client_id: String,
     pub token: String,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type_hint: Option<String>, // "access_token" or "refresh_token"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+
+pub type RevokeRequest = LenientForm<RevokeDTO>;
+
+/// A token introspection request (RFC 7662). Only confidential clients are allowed to introspect tokens.
+/// The client credentials are optional, if the client authenticates with HTTP Basic instead.
+#[derive(Default, Serialize, Deserialize, FromForm, UriDisplayQuery)]
+pub struct IntrospectDTO {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    pub token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type_hint: Option<String>, // "access_token" or "refresh_token"
+}
+
+pub type IntrospectRequest = LenientForm<IntrospectDTO>;
+
+/// The token introspection response (RFC 7662). Only "active" is set for inactive tokens.
+#[derive(Default, Serialize, Deserialize)]
+pub struct IntrospectionResponse {
+    pub active: bool,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty", deserialize_with = "scope_deserialize", serialize_with = "scope_serialize")]
+    pub scope: BTreeSet<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+}
+
+/// Client metadata of a dynamic client registration request (RFC 7591)
+#[derive(Default, Serialize, Deserialize)]
+pub struct ClientRegistrationDTO {
+    /// A random client id is generated if none is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    pub client_name: String,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub logo_uri: String,
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty", deserialize_with = "scope_deserialize", serialize_with = "scope_serialize")]
+    pub scope: BTreeSet<String>,
+    /// "none" for public clients. Confidential clients ("client_secret_post", the default) get a generated secret.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_endpoint_auth_method: Option<String>,
+    /// Authorization requests must contain a "state" parameter
+    #[serde(default)]
+    pub requires_state: bool,
+    /// The rate limit per user for requests with tokens of this client. The configured quota applies if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<Quota>,
+}
+
+/// The client information response of a dynamic client registration (RFC 7591).
+/// The secret is only returned on registration.
+#[derive(Default, Serialize, Deserialize)]
+pub struct ClientRegistrationResponse {
+    pub client_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub client_secret: Option<String>,
+    pub client_id_issued_at: i64,
+    pub client_secret_expires_at: i64, // 0: Does not expire
+    pub token_endpoint_auth_method: String,
+    pub client_name: String,
+    pub author: String,
+    pub logo_uri: String,
+    pub redirect_uris: Vec<String>,
+    #[serde(default, deserialize_with = "scope_deserialize", serialize_with = "scope_serialize")]
+    pub scope: BTreeSet<String>,
+    #[serde(default)]
+    pub requires_state: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<Quota>,
+}
+
+/// A session of a user: An authorization of a client that issued a refresh token.
+/// The id is the refresh token family id and stays the same if the refresh token is rotated.
+#[derive(Serialize, Deserialize)]
+pub struct SessionDTO {
+    pub id: String,
+    pub client_id: String,
+    pub client_name: String,
+    pub logo_uri: String,
+    #[serde(default, deserialize_with = "scope_deserialize", serialize_with = "scope_serialize")]
+    pub scope: BTreeSet<String>,
+    /// Unix timestamp of the authorization
+    pub created_at: i64,
+    /// Unix timestamp of the last token refresh
+    pub last_used_at: i64,
+    /// The client ip address of the last token refresh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_ip: Option<String>,
+    /// The amount of token refreshes
+    #[serde(default)]
+    pub use_count: u32,
+}
+
+/// A consent of a user: The scopes that the user granted to a client.
+/// An authorization request of that client for a subset of those scopes does not show the consent screen again.
+#[derive(Serialize, Deserialize)]
+pub struct ConsentDTO {
+    pub client_id: String,
+    pub client_name: String,
+    pub logo_uri: String,
+    #[serde(default, deserialize_with = "scope_deserialize", serialize_with = "scope_serialize")]
+    pub scope: BTreeSet<String>,
+    /// Unix timestamp of the last grant
+    pub granted_at: i64,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct DeviceFlowResponse {
     pub device_code: String,
+    /// A short code like "WDJB-MJHT" that the user enters on the verification page
     pub user_code: String,
     pub verification_uri: String,
+    /// The verification uri including the user code, for example for QR codes
+    pub verification_uri_complete: String,
+    /// The minimum amount of seconds between two token requests
     pub interval: u32,
     pub expires_in: u32,
 }
@@ -94,6 +252,9 @@ pub struct OAuthTokenResponse {
     /// converts this into a set.
     #[serde(skip_serializing_if = "BTreeSet::is_empty", deserialize_with = "scope_deserialize", serialize_with = "scope_serialize")]
     pub scope: BTreeSet<String>,
+    /// An OpenID Connect ID token. Only set if the "openid" scope has been granted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
 }
 
 impl OAuthTokenResponse {
@@ -107,7 +268,8 @@ impl OAuthTokenResponse {
             refresh_token,
             expires_in: 3600,
             token_type: "bearer".to_string(),
-            scope
+            scope,
+            id_token: None,
         }
     }
 }
@@ -116,4 +278,6 @@ impl OAuthTokenResponse {
 pub enum RedirectOrResponseAuthorize {
     Json(content::Json<String>),
     ToOhxLoginPage(Redirect),
+    /// An error response (RFC 6749, 4.1.2.1) to the redirect uri of the client
+    ToClient(Redirect),
 }
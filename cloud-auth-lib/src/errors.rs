@@ -90,6 +90,18 @@ impl std::convert::From<biscuit::errors::ValidationError> for CloudAuthError {
     }
 }
 
+impl std::convert::From<redis::RedisError> for CloudAuthError {
+    fn from(error: redis::RedisError) -> Self {
+        CloudAuthError::GenericOwned(error.to_string())
+    }
+}
+
+impl std::convert::From<firestore_db_and_auth::errors::FirebaseError> for CloudAuthError {
+    fn from(error: firestore_db_and_auth::errors::FirebaseError) -> Self {
+        CloudAuthError::GenericOwned(error.to_string())
+    }
+}
+
 impl fmt::Display for CloudAuthError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -0,0 +1,160 @@
+//! # User identities
+//! Users log in on the website with Firebase Authentication. Services verify the session of a user
+//! (the Firebase ID token of the website) and look up profile information via an [`IdentityProvider`].
+//!
+//! [`FirebaseIdentityProvider`] is used in production. [`MemoryIdentityProvider`] allows to run a service
+//! without Google credentials, for example in tests.
+//!
+//! Services manage an `Arc<dyn IdentityProvider>` as rocket state. The [`crate::guard_user_session::UserSessionGuard`]
+//! resolves the session of a request with it.
+
+use crate::CloudAuthError;
+use firestore_db_and_auth::{credentials::Credentials as DBCredentials, UserSession};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// The profile of a user. Serialized with the field names of the Firebase user info.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserInfo {
+    #[serde(rename = "localId")]
+    pub user_id: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub email_verified: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub photo_url: Option<String>,
+    /// The last login in milliseconds since the unix epoch
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub last_login_at: Option<i64>,
+}
+
+pub trait IdentityProvider: Send + Sync {
+    /// Returns the user id of a valid session token or None if the token is invalid or expired
+    fn verify_session(&self, session_token: &str) -> Result<Option<String>, CloudAuthError>;
+    /// Returns the profile of the given user or None if the user does not exist
+    fn user_info(&self, user_id: &str) -> Result<Option<UserInfo>, CloudAuthError>;
+    /// Removes the given user
+    fn remove_user(&self, user_id: &str) -> Result<(), CloudAuthError>;
+}
+
+/// Users of Firebase Authentication. The service account credentials allow to act on behalf of a user.
+pub struct FirebaseIdentityProvider {
+    credentials: DBCredentials,
+}
+
+impl FirebaseIdentityProvider {
+    pub fn new(credentials: DBCredentials) -> FirebaseIdentityProvider {
+        FirebaseIdentityProvider { credentials }
+    }
+}
+
+impl IdentityProvider for FirebaseIdentityProvider {
+    fn verify_session(&self, session_token: &str) -> Result<Option<String>, CloudAuthError> {
+        match UserSession::by_access_token(&self.credentials, session_token) {
+            Ok(user_session) => Ok(Some(user_session.user_id)),
+            Err(e) => {
+                debug!("Invalid user session: {:?}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    fn user_info(&self, user_id: &str) -> Result<Option<UserInfo>, CloudAuthError> {
+        let user_session = UserSession::by_user_id(&self.credentials, user_id, false)?;
+        let user = match firestore_db_and_auth::users::user_info(&user_session)?.users.into_iter().next() {
+            Some(user) => user,
+            None => return Ok(None)
+        };
+        Ok(Some(UserInfo {
+            user_id: user_id.to_owned(),
+            email: user.email,
+            email_verified: user.emailVerified,
+            display_name: user.displayName,
+            photo_url: user.photoUrl,
+            // Firebase returns the milliseconds as string
+            last_login_at: user.lastLoginAt.and_then(|f| f.parse::<i64>().ok()),
+        }))
+    }
+
+    fn remove_user(&self, user_id: &str) -> Result<(), CloudAuthError> {
+        let user_session = UserSession::by_user_id(&self.credentials, user_id, false)?;
+        Ok(firestore_db_and_auth::users::user_remove(&user_session)?)
+    }
+}
+
+/// Users and session tokens that are kept in memory. The content is lost on restart.
+#[derive(Default)]
+pub struct MemoryIdentityProvider {
+    users: RwLock<HashMap<String, UserInfo>>,
+    /// The user id per session token
+    sessions: RwLock<HashMap<String, String>>,
+}
+
+const POISONED: CloudAuthError = CloudAuthError::Generic("Memory identity provider poisoned");
+
+impl MemoryIdentityProvider {
+    pub fn new() -> MemoryIdentityProvider {
+        MemoryIdentityProvider::default()
+    }
+
+    /// Adds or replaces a user
+    pub fn add_user(&self, user: UserInfo) -> Result<(), CloudAuthError> {
+        self.users.write().map_err(|_| POISONED)?.insert(user.user_id.clone(), user);
+        Ok(())
+    }
+
+    /// Creates a new session token for the given user, like a login on the website
+    pub fn create_session(&self, user_id: &str) -> Result<String, CloudAuthError> {
+        let session_token = uuid::Uuid::new_v4().to_simple().to_string();
+        self.sessions.write().map_err(|_| POISONED)?.insert(session_token.clone(), user_id.to_owned());
+        Ok(session_token)
+    }
+}
+
+impl IdentityProvider for MemoryIdentityProvider {
+    fn verify_session(&self, session_token: &str) -> Result<Option<String>, CloudAuthError> {
+        let user_id = match self.sessions.read().map_err(|_| POISONED)?.get(session_token) {
+            Some(user_id) => user_id.clone(),
+            None => return Ok(None)
+        };
+        // The sessions of removed users are invalid
+        match self.users.read().map_err(|_| POISONED)?.contains_key(&user_id) {
+            true => Ok(Some(user_id)),
+            false => Ok(None)
+        }
+    }
+
+    fn user_info(&self, user_id: &str) -> Result<Option<UserInfo>, CloudAuthError> {
+        Ok(self.users.read().map_err(|_| POISONED)?.get(user_id).cloned())
+    }
+
+    fn remove_user(&self, user_id: &str) -> Result<(), CloudAuthError> {
+        self.users.write().map_err(|_| POISONED)?.remove(user_id);
+        Ok(())
+    }
+}
+
+#[test]
+fn memory_identity_provider_test() {
+    let identities = MemoryIdentityProvider::new();
+    identities.add_user(UserInfo { user_id: "user".to_owned(), email: Some("user@example.com".to_owned()), ..Default::default() }).unwrap();
+    let session_token = identities.create_session("user").unwrap();
+
+    assert_eq!(identities.verify_session(&session_token).unwrap(), Some("user".to_owned()));
+    assert!(identities.verify_session("unknown").unwrap().is_none());
+    assert_eq!(identities.user_info("user").unwrap().unwrap().email, Some("user@example.com".to_owned()));
+    let json = serde_json::to_value(identities.user_info("user").unwrap().unwrap()).unwrap();
+    assert_eq!(json, serde_json::json!({"localId": "user", "email": "user@example.com"}));
+
+    identities.remove_user("user").unwrap();
+    assert!(identities.user_info("user").unwrap().is_none());
+    assert!(identities.verify_session(&session_token).unwrap().is_none());
+}
@@ -6,13 +6,20 @@ pub mod token;
 pub mod jwt;
 pub mod tools;
 pub mod login;
+pub mod deny_list;
+pub mod authorization_codes;
+pub mod audit;
+pub mod storage;
+pub mod config;
+pub mod rate_limit;
+pub mod identity;
 mod credentials;
 mod rocket_helper;
 mod errors;
 
 pub use rocket_helper::*;
 pub use errors::CloudAuthError;
-pub use credentials::Credentials;
+pub use credentials::{Credentials, PreviousKey, KeyAlgorithm};
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
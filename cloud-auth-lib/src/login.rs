@@ -50,7 +50,12 @@ pub fn refresh_token(mut session: UserSession) -> Result<UserSession, CloudAuthE
 pub struct LoginDeviceFlow {
     pub client_id: String,
     pub device_code: String,
+    /// The code that the user must enter on the verification page
+    pub user_code: String,
     pub verification_uri: String,
+    pub verification_uri_complete: Option<String>,
+    /// Minimum seconds between two token requests. Increased on a "slow_down" response.
+    pub interval: u32,
     expires_in: i64,
     pub expires_time: i64,
 
@@ -62,11 +67,11 @@ pub struct LoginDeviceFlow {
 impl LoginDeviceFlow {
     pub fn new(token_request: AuthRequest) -> Result<LoginDeviceFlow, CloudAuthError> {
         let data = serde_urlencoded::to_string(token_request).expect("AuthRequest encoding");
-        let response = ureq::post("https://oauth.openhabx.com/authorize")
+        let response = ureq::post("https://oauth.openhabx.com/device_authorization")
             .set("Content-Type", "application/x-www-form-urlencoded")
             .send_string(&data);
         if response.error() {
-            return Err(CloudAuthError::HttpError("https://oauth.openhabx.com/authorize".into(), response.status_line().into()));
+            return Err(CloudAuthError::HttpError("https://oauth.openhabx.com/device_authorization".into(), response.status_line().into()));
         }
         if response.status() != 200 {
             let message = match response.status() {
@@ -81,7 +86,10 @@ impl LoginDeviceFlow {
         Ok(LoginDeviceFlow {
             client_id: token_request.client_id,
             device_code: device_flow_response.device_code,
+            user_code: device_flow_response.user_code,
             verification_uri: device_flow_response.verification_uri,
+            verification_uri_complete: device_flow_response.verification_uri_complete,
+            interval: device_flow_response.interval,
             expires_in: device_flow_response.expires_in,
             expires_time: device_flow_response.expires_in + chrono::Utc::now().timestamp(),
             access_token: None,
@@ -94,9 +102,15 @@ impl LoginDeviceFlow {
         self.expires_time - chrono::Utc::now().timestamp()
     }
 
+    /// Shows the user code and verification uri and polls the token endpoint until the user has granted
+    /// or denied access. The token endpoint is not polled more often than the server given interval.
     pub fn wait_for_user_blocking(&mut self, check_every: Duration) -> Result<(), CloudAuthError> {
+        println!("Open {} and enter the code: {}", &self.verification_uri, &self.user_code);
+        if let Some(verification_uri_complete) = self.verification_uri_complete.as_ref() {
+            println!("Or open {}", verification_uri_complete);
+        }
         loop {
-            thread::sleep(check_every);
+            thread::sleep(std::cmp::max(check_every, Duration::from_secs(self.interval as u64)));
 
             let token_request = TokenRequestForDevice {
                 device_code: self.device_code.clone(),
@@ -123,8 +137,11 @@ impl LoginDeviceFlow {
                 }
                 400 => {
                     let response = serde_json::from_value::<ErrorResult>(response.into_json()?)?;
-                    if &response.error != "authorization_pending" {
-                        return Err(CloudAuthError::GenericOwned(format!("Server response: {}", &response.error)));
+                    match &response.error[..] {
+                        "authorization_pending" => {}
+                        // RFC 8628, 3.5: Increase the interval by 5 seconds
+                        "slow_down" => self.interval += 5,
+                        _ => return Err(CloudAuthError::GenericOwned(format!("Server response: {}", &response.error)))
                     }
                 }
                 _ => {
@@ -1,12 +1,36 @@
 //! # Oauth Clients
-//! OAuth clients are read in on application start. Only registered clients (client_id, [client_secret])
+//! OAuth clients are persisted in the "oauth_clients" collection of the document store and managed at runtime
+//! via the dynamic client registration endpoints. Only registered clients (client_id, [client_secret])
 //! can use this oauth service.
+//!
+//! The compiled-in oauth_clients.json is only used as a seed for an empty collection. It does not contain
+//! secrets: Its confidential clients have an empty `secret_hash` and cannot authenticate, until their secret
+//! is configured via the oauth_clients.json of the runtime configuration.
+//!
+//! Client secrets are not persisted, only their hash. The plain secret is known at registration time only.
+//! Secrets of the seed take precedence over stored ones, so a secret is rotated by changing it in the seed.
+
+use crate::dto::oauth::{ClientRegistrationDTO, ClientRegistrationResponse};
+use crate::rate_limit::Quota;
+use crate::storage::DocumentStore;
+use crate::token::hash_of_token;
+use crate::CloudAuthError;
 
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
 
 pub type OAuthClients = HashMap<String, OAuthClient>;
 
+/// The Firestore collection of registered clients
+pub const OAUTH_CLIENTS_COLLECTION: &str = "oauth_clients";
+
+/// Clients are re-read from the database after this amount of seconds
+const CACHE_REFRESH_SECS: i64 = 60;
+
 pub fn new(json: &str) -> Result<OAuthClients, serde_json::Error> {
     Ok(serde_json::from_str(json)?)
 }
@@ -14,7 +38,12 @@ pub fn new(json: &str) -> Result<OAuthClients, serde_json::Error> {
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct OAuthClient {
     pub id: String,
+    /// The plain secret. Never persisted: Only set for seed clients and right after a registration.
+    #[serde(default, skip_serializing)]
     pub secret: Option<String>,
+    /// The hash of the secret of a confidential client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_hash: Option<String>,
     pub title: String,
     pub author: String,
     pub logo_url: String,
@@ -22,4 +51,299 @@ pub struct OAuthClient {
     pub redirect_uri: Vec<String>,
     #[serde(default)]
     pub scopes: HashSet<String>,
+    /// Authorization requests must contain a "state" parameter
+    #[serde(default)]
+    pub requires_state: bool,
+    /// Unix timestamp of the registration. Not set for seeded clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issued_at: Option<i64>,
+    /// Requests with tokens of this client are limited to this quota per user.
+    /// The configured client or user quota applies if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<Quota>,
+}
+
+impl OAuthClient {
+    /// Confidential clients (with a secret) must provide the matching secret.
+    /// Public clients are always authenticated.
+    pub fn authenticate(&self, client_secret: Option<&str>) -> bool {
+        match &self.secret_hash {
+            Some(secret_hash) => client_secret.map_or(false, |client_secret| {
+                let computed = hash_of_token(client_secret.as_bytes());
+                ring::constant_time::verify_slices_are_equal(computed.as_bytes(), secret_hash.as_bytes()).is_ok()
+            }),
+            None => true
+        }
+    }
+
+    /// Confidential clients have a secret
+    pub fn is_confidential(&self) -> bool {
+        self.secret_hash.is_some()
+    }
+
+    /// Sets a new secret. The plain secret is kept for the registration response, but is not persisted.
+    pub fn set_secret(&mut self, secret: String) {
+        self.secret_hash = Some(hash_of_token(secret.as_bytes()));
+        self.secret = Some(secret);
+    }
+
+    /// Replaces a plain secret (seed clients, clients stored before secrets were hashed) by its hash
+    fn hash_secret(&mut self) {
+        if let Some(secret) = self.secret.take() {
+            self.secret_hash = Some(hash_of_token(secret.as_bytes()));
+        }
+    }
+
+    /// Returns the redirect uri for an authorization request or None if the requested uri is not registered.
+    ///
+    /// A requested uri must exactly match a registered one. For registered loopback uris
+    /// ("http://localhost", "http://127.0.0.1") any port is accepted (RFC 8252, 7.3).
+    /// If no uri is requested, the registered uri is used if there is exactly one.
+    pub fn resolve_redirect_uri(&self, requested: Option<&str>) -> Option<String> {
+        let requested = match requested {
+            Some(requested) => requested,
+            None if self.redirect_uri.len() == 1 => return Some(self.redirect_uri[0].clone()),
+            None => return None
+        };
+        if self.redirect_uri.iter().any(|registered| registered == requested) {
+            return Some(requested.to_owned());
+        }
+        let requested_url = url::Url::parse(requested).ok()?;
+        let is_loopback = |uri: &url::Url| uri.scheme() == "http" &&
+            (uri.host_str() == Some("localhost") || uri.host_str() == Some("127.0.0.1"));
+        if !is_loopback(&requested_url) {
+            return None;
+        }
+        self.redirect_uri.iter()
+            .filter_map(|registered| url::Url::parse(registered).ok())
+            .find(|registered| is_loopback(registered) && registered.host_str() == requested_url.host_str() &&
+                registered.path() == requested_url.path() && registered.query() == requested_url.query())
+            .map(|_| requested.to_owned())
+    }
+
+    /// Apply the given registration metadata. The client id and a generated secret are set by the caller.
+    pub fn apply_registration(&mut self, registration: ClientRegistrationDTO) {
+        self.title = registration.client_name;
+        self.author = registration.author;
+        self.logo_url = registration.logo_uri;
+        self.redirect_uri = registration.redirect_uris;
+        self.scopes = registration.scope.into_iter().collect();
+        self.requires_state = registration.requires_state;
+        self.rate_limit = registration.rate_limit;
+    }
+
+    /// The registration response (RFC 7591). The secret is only included if `with_secret` is set.
+    pub fn registration_response(&self, with_secret: bool) -> ClientRegistrationResponse {
+        ClientRegistrationResponse {
+            client_id: self.id.clone(),
+            client_secret: self.secret.as_ref().filter(|_| with_secret).cloned(),
+            client_id_issued_at: self.issued_at.unwrap_or(0),
+            client_secret_expires_at: 0,
+            token_endpoint_auth_method: match self.secret_hash {
+                Some(_) => "client_secret_post".to_owned(),
+                None => "none".to_owned()
+            },
+            client_name: self.title.clone(),
+            author: self.author.clone(),
+            logo_uri: self.logo_url.clone(),
+            redirect_uris: self.redirect_uri.clone(),
+            scope: self.scopes.iter().cloned().collect(),
+            requires_state: self.requires_state,
+            rate_limit: self.rate_limit,
+        }
+    }
+}
+
+struct ClientCache {
+    clients: OAuthClients,
+    refreshed_at: i64,
+}
+
+/// A cached view on the registered clients in the database.
+///
+/// Lookups are answered from memory. The cache is refreshed if it is older than a minute.
+/// Modifications via this store are written to the database and the cache immediately.
+pub struct OAuthClientStore {
+    cache: RwLock<ClientCache>,
+}
+
+impl OAuthClientStore {
+    /// Creates a store with the given seed clients in the cache.
+    /// Call [`OAuthClientStore::seed`] to persist them if the database does not contain any clients yet.
+    pub fn new(mut seed: OAuthClients) -> OAuthClientStore {
+        for client in seed.values_mut() {
+            client.hash_secret();
+        }
+        OAuthClientStore {
+            cache: RwLock::new(ClientCache { clients: seed, refreshed_at: 0 })
+        }
+    }
+
+    /// Writes the cached seed clients to the database, if the database does not contain any client yet.
+    /// The cache is replaced by the database content otherwise. The secrets of seed clients replace
+    /// the secrets of the stored clients with the same id.
+    pub fn seed(&self, store: &dyn DocumentStore) -> Result<(), CloudAuthError> {
+        let mut stored = Self::read_all(store)?;
+        let mut cache = self.cache.write().map_err(|_| CloudAuthError::Generic("OAuth client cache poisoned"))?;
+        if stored.is_empty() {
+            for (id, client) in cache.clients.iter() {
+                info!("Seed oauth client {}", id);
+                store.write(OAUTH_CLIENTS_COLLECTION, id, client)?;
+            }
+        } else {
+            for (id, seed_client) in cache.clients.iter() {
+                let secret_hash = match &seed_client.secret_hash {
+                    Some(secret_hash) if !secret_hash.is_empty() => secret_hash,
+                    _ => continue
+                };
+                if let Some(client) = stored.get_mut(id).filter(|client| client.secret_hash.as_ref() != Some(secret_hash)) {
+                    info!("Rotate the secret of oauth client {}", id);
+                    client.secret_hash = Some(secret_hash.clone());
+                    store.write(OAUTH_CLIENTS_COLLECTION, id, &*client)?;
+                }
+            }
+            cache.clients = stored;
+        }
+        cache.refreshed_at = chrono::Utc::now().timestamp();
+        Ok(())
+    }
+
+    fn read_all(store: &dyn DocumentStore) -> Result<OAuthClients, CloudAuthError> {
+        let list: Vec<(String, OAuthClient)> = store.list(OAUTH_CLIENTS_COLLECTION)?;
+        let mut clients = OAuthClients::new();
+        for (_, mut client) in list {
+            // Stored with a plain secret: Replace it by the hash
+            if client.secret.is_some() {
+                info!("Hash the secret of oauth client {}", &client.id);
+                client.hash_secret();
+                store.write(OAUTH_CLIENTS_COLLECTION, &client.id, &client)?;
+            }
+            clients.insert(client.id.clone(), client);
+        }
+        Ok(clients)
+    }
+
+    /// Re-read all clients if the cache is outdated. The old cache is kept if the database is not reachable.
+    fn refresh_if_outdated(&self, store: &dyn DocumentStore) {
+        let now = chrono::Utc::now().timestamp();
+        match self.cache.read() {
+            Ok(cache) if cache.refreshed_at + CACHE_REFRESH_SECS > now => return,
+            _ => {}
+        }
+        match Self::read_all(store) {
+            Ok(clients) => {
+                if let Ok(mut cache) = self.cache.write() {
+                    cache.clients = clients;
+                    cache.refreshed_at = now;
+                }
+            }
+            Err(e) => warn!("Failed to refresh oauth clients: {:?}", e)
+        }
+    }
+
+    /// Returns the client with the given id
+    pub fn get(&self, store: &dyn DocumentStore, client_id: &str) -> Option<OAuthClient> {
+        self.refresh_if_outdated(store);
+        self.cache.read().ok().and_then(|cache| cache.clients.get(client_id).cloned())
+    }
+
+    /// Returns all registered clients
+    pub fn list(&self, store: &dyn DocumentStore) -> Result<Vec<OAuthClient>, CloudAuthError> {
+        let clients = Self::read_all(store)?;
+        let mut list: Vec<OAuthClient> = clients.values().cloned().collect();
+        list.sort_by(|a, b| a.id.cmp(&b.id));
+        if let Ok(mut cache) = self.cache.write() {
+            cache.clients = clients;
+            cache.refreshed_at = chrono::Utc::now().timestamp();
+        }
+        Ok(list)
+    }
+
+    /// Adds or replaces a client. A plain secret is not stored, only its hash.
+    pub fn store(&self, store: &dyn DocumentStore, mut client: OAuthClient) -> Result<(), CloudAuthError> {
+        client.hash_secret();
+        store.write(OAUTH_CLIENTS_COLLECTION, &client.id, &client)?;
+        if let Ok(mut cache) = self.cache.write() {
+            cache.clients.insert(client.id.clone(), client);
+        }
+        Ok(())
+    }
+
+    /// Removes a client. Already issued tokens are not revoked.
+    pub fn remove(&self, store: &dyn DocumentStore, client_id: &str) -> Result<(), CloudAuthError> {
+        store.delete(OAUTH_CLIENTS_COLLECTION, client_id)?;
+        if let Ok(mut cache) = self.cache.write() {
+            cache.clients.remove(client_id);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn resolve_redirect_uri_test() {
+    let client = OAuthClient {
+        redirect_uri: vec!["https://example.com/callback".to_owned(), "http://localhost/callback".to_owned()],
+        ..Default::default()
+    };
+    assert_eq!(client.resolve_redirect_uri(Some("https://example.com/callback")).unwrap(), "https://example.com/callback");
+    assert_eq!(client.resolve_redirect_uri(Some("http://localhost:8123/callback")).unwrap(), "http://localhost:8123/callback");
+    assert!(client.resolve_redirect_uri(Some("https://example.com/callback/other")).is_none());
+    assert!(client.resolve_redirect_uri(Some("http://localhost:8123/other")).is_none());
+    assert!(client.resolve_redirect_uri(Some("https://localhost:8123/callback")).is_none());
+    // Ambiguous
+    assert!(client.resolve_redirect_uri(None).is_none());
+
+    let client = OAuthClient {
+        redirect_uri: vec!["https://example.com/callback".to_owned()],
+        ..Default::default()
+    };
+    assert_eq!(client.resolve_redirect_uri(None).unwrap(), "https://example.com/callback");
+}
+
+#[test]
+fn client_secret_test() {
+    use crate::storage::MemoryDocumentStore;
+
+    let mut client = OAuthClient { id: "client".to_owned(), ..Default::default() };
+    assert!(client.authenticate(None));
+    client.set_secret("secret".to_owned());
+    assert!(client.is_confidential());
+    assert!(client.authenticate(Some("secret")));
+    assert!(!client.authenticate(Some("other")));
+    assert!(!client.authenticate(None));
+
+    // Only the hash is persisted
+    let memory = MemoryDocumentStore::new();
+    let documents: &dyn DocumentStore = &memory;
+    let clients = OAuthClientStore::new(OAuthClients::new());
+    clients.store(documents, client).unwrap();
+    let stored: serde_json::Value = documents.read(OAUTH_CLIENTS_COLLECTION, "client").unwrap().unwrap();
+    assert!(stored.get("secret").is_none());
+    assert!(clients.get(documents, "client").unwrap().authenticate(Some("secret")));
+
+    // Plain secrets of seed clients are hashed
+    let seed = new(r#"{"seed": {"id": "seed", "secret": "seed_secret", "title": "", "author": "", "logo_url": ""}}"#).unwrap();
+    let clients = OAuthClientStore::new(seed);
+    let memory = MemoryDocumentStore::new();
+    let documents: &dyn DocumentStore = &memory;
+    clients.seed(documents).unwrap();
+    let stored: serde_json::Value = documents.read(OAUTH_CLIENTS_COLLECTION, "seed").unwrap().unwrap();
+    assert!(stored.get("secret").is_none());
+    assert!(clients.get(documents, "seed").unwrap().authenticate(Some("seed_secret")));
+
+    // A changed secret in the seed replaces the stored one
+    let seed = new(r#"{"seed": {"id": "seed", "secret": "rotated", "title": "", "author": "", "logo_url": ""}}"#).unwrap();
+    let clients = OAuthClientStore::new(seed);
+    clients.seed(documents).unwrap();
+    assert!(clients.get(documents, "seed").unwrap().authenticate(Some("rotated")));
+    assert!(!clients.get(documents, "seed").unwrap().authenticate(Some("seed_secret")));
+
+    // An empty secret hash (compiled-in seed) never authenticates and does not replace a stored secret
+    let seed = new(r#"{"seed": {"id": "seed", "secret_hash": "", "title": "", "author": "", "logo_url": ""}}"#).unwrap();
+    let seed_client = seed["seed"].clone();
+    assert!(seed_client.is_confidential());
+    assert!(!seed_client.authenticate(Some("")));
+    let clients = OAuthClientStore::new(seed);
+    clients.seed(documents).unwrap();
+    assert!(clients.get(documents, "seed").unwrap().authenticate(Some("rotated")));
 }
@@ -0,0 +1,334 @@
+//! # Rate limiting
+//! Requests are counted per key (client ip, user id or client id) with the generic cell rate algorithm (GCRA).
+//! The only state per key is the "theoretical arrival time" (TAT) of the next request, which makes it
+//! cheap to share across instances via Redis ([`RedisRateLimitBackend`]).
+//! A single instance can use the [`MemoryRateLimitBackend`].
+//!
+//! Quotas are configured per key kind and optionally per route or oauth client ([`RateLimits`]).
+//! The [`crate::guard_rate_limiter::RateLimiter`] (client ip) and
+//! [`crate::guard_rate_limiter::IdentityRateLimiter`] (user or client of an access token) guards apply them.
+
+use crate::config::ConfigSource;
+use crate::CloudAuthError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// The name of the optional rate limit configuration file, see [`RateLimits::from_json`]
+pub const RATE_LIMITS_FILE: &str = "rate_limits.json";
+
+/// Outdated in-memory entries are removed if the map grows beyond this size
+const MEMORY_PRUNE_SIZE: usize = 10000;
+
+/// Allows `limit` requests per `period_secs`. All of them may happen at once (burst).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub limit: u32,
+    pub period_secs: u32,
+}
+
+impl Quota {
+    pub fn per_second(limit: u32) -> Quota {
+        Quota { limit, period_secs: 1 }
+    }
+
+    /// The time between two requests in milliseconds if requests are evenly spaced
+    fn emission_interval(&self) -> i64 {
+        (self.period_ms() / self.limit.max(1) as i64).max(1)
+    }
+
+    fn period_ms(&self) -> i64 {
+        self.period_secs as i64 * 1000
+    }
+}
+
+/// What a rate limit is counted for
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyKind {
+    Ip,
+    User,
+    Client,
+}
+
+impl KeyKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            KeyKind::Ip => "ip",
+            KeyKind::User => "user",
+            KeyKind::Client => "client",
+        }
+    }
+}
+
+/// The outcome of a rate limit check. Durations are in milliseconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the full quota is available again
+    pub reset_after: i64,
+    /// Time until the next request would be allowed. Only set for rejected requests.
+    pub retry_after: Option<i64>,
+}
+
+/// Applies the GCRA. `tat` is the stored theoretical arrival time, if any.
+/// Returns the decision and the new arrival time that must be stored if the request is allowed.
+fn gcra(tat: Option<i64>, now: i64, quota: &Quota) -> (RateLimitDecision, i64) {
+    let interval = quota.emission_interval();
+    let tolerance = quota.period_ms();
+    let tat = tat.unwrap_or(now).max(now);
+    let new_tat = tat + interval;
+
+    if new_tat - now > tolerance {
+        let decision = RateLimitDecision {
+            allowed: false,
+            limit: quota.limit,
+            remaining: 0,
+            reset_after: tat - now,
+            retry_after: Some(new_tat - now - tolerance),
+        };
+        return (decision, tat);
+    }
+
+    let decision = RateLimitDecision {
+        allowed: true,
+        limit: quota.limit,
+        remaining: ((tolerance - (new_tat - now)) / interval) as u32,
+        reset_after: new_tat - now,
+        retry_after: None,
+    };
+    (decision, new_tat)
+}
+
+fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+pub trait RateLimitBackend: Send + Sync {
+    /// Counts a request for the given key and returns whether it is allowed
+    fn check(&self, key: &str, quota: &Quota) -> Result<RateLimitDecision, CloudAuthError>;
+}
+
+/// Keeps the arrival times in memory. Each instance counts on its own.
+#[derive(Default)]
+pub struct MemoryRateLimitBackend {
+    arrival_times: Mutex<HashMap<String, i64>>,
+}
+
+impl MemoryRateLimitBackend {
+    pub fn new() -> MemoryRateLimitBackend {
+        MemoryRateLimitBackend::default()
+    }
+}
+
+impl RateLimitBackend for MemoryRateLimitBackend {
+    fn check(&self, key: &str, quota: &Quota) -> Result<RateLimitDecision, CloudAuthError> {
+        let now = now_millis();
+        let mut arrival_times = self.arrival_times.lock().map_err(|_| CloudAuthError::Generic("Rate limiter poisoned"))?;
+        // An arrival time in the past is equivalent to no entry at all
+        if arrival_times.len() > MEMORY_PRUNE_SIZE {
+            arrival_times.retain(|_, tat| *tat > now);
+        }
+        let (decision, new_tat) = gcra(arrival_times.get(key).cloned(), now, quota);
+        if decision.allowed {
+            arrival_times.insert(key.to_owned(), new_tat);
+        }
+        Ok(decision)
+    }
+}
+
+/// Evaluates the GCRA atomically in Redis. Returns the effective arrival time before this request.
+/// The key expires as soon as the full quota is available again.
+const GCRA_SCRIPT: &str = r"
+local now = tonumber(ARGV[1])
+local interval = tonumber(ARGV[2])
+local tolerance = tonumber(ARGV[3])
+local tat = tonumber(redis.call('GET', KEYS[1]) or now)
+if tat < now then
+    tat = now
+end
+local new_tat = tat + interval
+if new_tat - now <= tolerance then
+    redis.call('SET', KEYS[1], new_tat, 'PX', new_tat - now)
+end
+return tat
+";
+
+/// Shares the arrival times across instances via Redis.
+/// If Redis is not reachable, requests are counted per instance in memory instead of being allowed unlimited.
+pub struct RedisRateLimitBackend {
+    client: redis::Client,
+    script: redis::Script,
+    fallback: MemoryRateLimitBackend,
+}
+
+impl RedisRateLimitBackend {
+    pub fn new(client: redis::Client) -> RedisRateLimitBackend {
+        RedisRateLimitBackend { client, script: redis::Script::new(GCRA_SCRIPT), fallback: MemoryRateLimitBackend::new() }
+    }
+
+    fn check_redis(&self, key: &str, quota: &Quota, now: i64) -> Result<RateLimitDecision, CloudAuthError> {
+        let mut connection = self.client.get_connection()?;
+        let tat: i64 = self.script
+            .key(format!("ratelimit.{}", key))
+            .arg(now)
+            .arg(quota.emission_interval())
+            .arg(quota.period_ms())
+            .invoke(&mut connection)?;
+        // The script applied the same algorithm to the same input
+        Ok(gcra(Some(tat), now, quota).0)
+    }
+}
+
+impl RateLimitBackend for RedisRateLimitBackend {
+    fn check(&self, key: &str, quota: &Quota) -> Result<RateLimitDecision, CloudAuthError> {
+        match self.check_redis(key, quota, now_millis()) {
+            Ok(decision) => Ok(decision),
+            Err(e) => {
+                error!("Rate limit backend Redis failed, counting in memory: {}", e);
+                self.fallback.check(key, quota)
+            }
+        }
+    }
+}
+
+/// Quotas per key kind. Route specific quotas (by route function name) replace the default quota of a kind
+/// and are counted separately. Client specific quotas apply to requests with access tokens of that client,
+/// see [`RateLimits::resolve_identity`].
+///
+/// Json representation:
+/// `{"default": {"ip": {"limit": 5, "period_secs": 1}}, "routes": {"token": {"client": {"limit": 60, "period_secs": 60}}},
+///   "clients": {"addoncli": {"limit": 30, "period_secs": 60}}}`
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct RateLimits {
+    #[serde(default)]
+    default: HashMap<KeyKind, Quota>,
+    #[serde(default)]
+    routes: HashMap<String, HashMap<KeyKind, Quota>>,
+    #[serde(default)]
+    clients: HashMap<String, Quota>,
+}
+
+impl RateLimits {
+    /// Allows `limit` requests per second and client ip on all routes
+    pub fn per_second(limit: u32) -> RateLimits {
+        RateLimits::default().with_default(KeyKind::Ip, Quota::per_second(limit))
+    }
+
+    pub fn from_json(json: &str) -> Result<RateLimits, CloudAuthError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Reads [`RATE_LIMITS_FILE`] from the runtime configuration.
+    /// Allows `limit` requests per second and client ip if it is not configured.
+    pub fn from_config(config_source: &ConfigSource, limit: u32) -> Result<RateLimits, CloudAuthError> {
+        match config_source.optional(RATE_LIMITS_FILE)? {
+            Some(json) => RateLimits::from_json(&json),
+            None => Ok(RateLimits::per_second(limit))
+        }
+    }
+
+    pub fn with_default(mut self, kind: KeyKind, quota: Quota) -> RateLimits {
+        self.default.insert(kind, quota);
+        self
+    }
+
+    pub fn with_route(mut self, route: &str, kind: KeyKind, quota: Quota) -> RateLimits {
+        self.routes.entry(route.to_owned()).or_default().insert(kind, quota);
+        self
+    }
+
+    pub fn with_client(mut self, client_id: &str, quota: Quota) -> RateLimits {
+        self.clients.insert(client_id.to_owned(), quota);
+        self
+    }
+
+    /// Returns the quota and the key to count a request with an access token for.
+    /// The key is the user, or the client for tokens without a user (client credentials grant).
+    ///
+    /// The quota of the registered client (`registered`) takes precedence over a configured client quota.
+    /// Both are counted per client and over all routes. The quota of the key kind applies otherwise.
+    pub fn resolve_identity(&self, route: Option<&str>, user_id: Option<&str>, client_id: Option<&str>, registered: Option<Quota>) -> Option<(Quota, String)> {
+        let client_quota = client_id.and_then(|client_id| registered.or_else(|| self.clients.get(client_id).cloned()));
+        match (user_id, client_id, client_quota) {
+            (Some(user_id), Some(client_id), Some(quota)) =>
+                Some((quota, format!("{}.{}.{}.{}", KeyKind::Client.as_str(), client_id, KeyKind::User.as_str(), user_id))),
+            (Some(user_id), _, None) => self.resolve(route, KeyKind::User, user_id),
+            (None, Some(client_id), Some(quota)) => Some((quota, format!("{}.{}", KeyKind::Client.as_str(), client_id))),
+            (None, Some(client_id), None) => self.resolve(route, KeyKind::Client, client_id),
+            _ => None
+        }
+    }
+
+    /// Returns the quota and the key to count the request for, if a quota is configured
+    pub fn resolve(&self, route: Option<&str>, kind: KeyKind, id: &str) -> Option<(Quota, String)> {
+        if let Some(route) = route {
+            if let Some(quota) = self.routes.get(route).and_then(|quotas| quotas.get(&kind)) {
+                return Some((*quota, format!("{}.{}.{}", route, kind.as_str(), id)));
+            }
+        }
+        self.default.get(&kind).map(|quota| (*quota, format!("{}.{}", kind.as_str(), id)))
+    }
+}
+
+#[test]
+fn gcra_test() {
+    let quota = Quota { limit: 2, period_secs: 10 };
+    let now = 1_000_000;
+
+    let (first, tat) = gcra(None, now, &quota);
+    assert!(first.allowed);
+    assert_eq!(first.remaining, 1);
+    let (second, tat) = gcra(Some(tat), now, &quota);
+    assert!(second.allowed);
+    assert_eq!(second.remaining, 0);
+    assert_eq!(second.reset_after, 10_000);
+
+    let (third, _) = gcra(Some(tat), now, &quota);
+    assert!(!third.allowed);
+    assert_eq!(third.retry_after, Some(5_000));
+
+    // One emission interval later a single request is allowed again
+    let (later, _) = gcra(Some(tat), now + 5_000, &quota);
+    assert!(later.allowed);
+    assert_eq!(later.remaining, 0);
+}
+
+#[test]
+fn memory_rate_limit_backend_test() {
+    let backend = MemoryRateLimitBackend::new();
+    let quota = Quota::per_second(2);
+    assert!(backend.check("ip.127.0.0.1", &quota).unwrap().allowed);
+    assert!(backend.check("ip.127.0.0.1", &quota).unwrap().allowed);
+    let rejected = backend.check("ip.127.0.0.1", &quota).unwrap();
+    assert!(!rejected.allowed);
+    assert!(rejected.retry_after.unwrap() > 0);
+    assert!(backend.check("ip.127.0.0.2", &quota).unwrap().allowed);
+}
+
+#[test]
+fn rate_limits_test() {
+    let limits = RateLimits::from_json(r#"{
+        "default": {"ip": {"limit": 5, "period_secs": 1}},
+        "routes": {"token": {"ip": {"limit": 10, "period_secs": 60}}}
+    }"#).unwrap();
+    assert_eq!(limits.resolve(Some("index"), KeyKind::Ip, "1.2.3.4"), Some((Quota::per_second(5), "ip.1.2.3.4".to_owned())));
+    assert_eq!(limits.resolve(Some("token"), KeyKind::Ip, "1.2.3.4"),
+               Some((Quota { limit: 10, period_secs: 60 }, "token.ip.1.2.3.4".to_owned())));
+    assert!(limits.resolve(None, KeyKind::User, "user").is_none());
+    assert!(limits.resolve_identity(None, Some("user"), Some("addoncli"), None).is_none());
+
+    let limits = limits.with_default(KeyKind::User, Quota::per_second(10)).with_client("amazon_echo", Quota::per_second(100));
+    assert_eq!(limits.resolve_identity(None, Some("user"), Some("addoncli"), None),
+               Some((Quota::per_second(10), "user.user".to_owned())));
+    assert_eq!(limits.resolve_identity(None, Some("user"), Some("amazon_echo"), None),
+               Some((Quota::per_second(100), "client.amazon_echo.user.user".to_owned())));
+    assert_eq!(limits.resolve_identity(None, None, Some("amazon_echo"), Some(Quota::per_second(50))),
+               Some((Quota::per_second(50), "client.amazon_echo".to_owned())));
+    assert!(limits.resolve_identity(None, None, Some("addoncli"), None).is_none());
+}
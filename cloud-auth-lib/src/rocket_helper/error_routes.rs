@@ -1,4 +1,6 @@
 use rocket::{catch,response::Responder};
+use super::guard_rate_limiter::{set_rate_limit_headers, RateLimitStatus};
+use crate::rate_limit::RateLimitDecision;
 
 #[catch(404)]
 pub fn not_found(_req: &rocket::Request) -> &'static str {
@@ -30,8 +32,22 @@ pub fn not_authorized(_req: &rocket::Request) -> MyOtherResponder {
     }
 }
 
+/// A 429 response with the rate limit headers of the rejected request, if known
+pub struct RateLimitedResponder(Option<RateLimitDecision>);
+
+impl<'r> Responder<'r> for RateLimitedResponder {
+    fn respond_to(self, req: &rocket::Request) -> rocket::response::Result<'r> {
+        let mut response = "429: Rate limited. This service is rated limited to prevent brute force attacks!".respond_to(req)?;
+        response.set_status(rocket::http::Status::TooManyRequests);
+        if let Some(ref decision) = self.0 {
+            set_rate_limit_headers(&mut response, decision);
+        }
+        Ok(response)
+    }
+}
+
 #[catch(429)]
-pub fn error_rate_limit(_req: &rocket::Request) -> &'static str {
-    "429: Rate limited. This service is rated limited to prevent brute force attacks!"
+pub fn error_rate_limit(req: &rocket::Request) -> RateLimitedResponder {
+    RateLimitedResponder(req.local_cache(|| RateLimitStatus(None)).0.clone())
 }
 
@@ -1,42 +1,310 @@
+//! # Cross origin requests
+//! Browsers only allow cross origin requests to a service if it responds with the matching CORS headers.
+//! The [`CorsFairing`] adds them for origins that are allowed by the service's [`CorsPolicy`] and answers
+//! preflight requests (`OPTIONS` with an `Access-Control-Request-Method` header).
+//!
+//! Allowed origins are exact ("https://openhabx.com"), any subdomain ("https://*.openhabx.com")
+//! or any origin ("*", never with credentials). The compiled-in origins of a service can be replaced
+//! by the optional [`CORS_ORIGINS_FILE`] of the runtime configuration, a json array of origins.
+
+use crate::config::ConfigSource;
+use crate::CloudAuthError;
 use rocket::fairing::{Fairing, Info, Kind};
-use rocket::{http::Method, http::Status, Request, Response};
+use rocket::handler::Outcome;
+use rocket::http::{Header, Method, Status};
+use rocket::{Data, Request, Response, Rocket, Route, State};
+
+/// The name of the optional allowed origins configuration, see [`CorsPolicy::with_origins_from_config`]
+pub const CORS_ORIGINS_FILE: &str = "cors_origins.json";
+
+/// The origins of the OHX web pages
+pub const OHX_ORIGINS: &[&str] = &["https://openhabx.com", "https://*.openhabx.com"];
+
+/// The rank of the preflight route. Higher than all other routes, which never handle `OPTIONS` anyway.
+const PREFLIGHT_RANK: isize = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+enum AllowedOrigin {
+    Any,
+    Exact(String),
+    /// The scheme ("https://") and the domain ("openhabx.com") of "https://*.openhabx.com"
+    Subdomain(String, String),
+}
+
+impl AllowedOrigin {
+    fn parse(origin: &str) -> AllowedOrigin {
+        let origin = origin.trim().trim_end_matches('/').to_ascii_lowercase();
+        if origin == "*" {
+            return AllowedOrigin::Any;
+        }
+        match origin.find("://*.") {
+            Some(index) => AllowedOrigin::Subdomain(origin[..index + 3].to_owned(), origin[index + 5..].to_owned()),
+            None => AllowedOrigin::Exact(origin)
+        }
+    }
+
+    /// The given origin must be lower case
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigin::Any => true,
+            AllowedOrigin::Exact(allowed) => allowed == origin,
+            AllowedOrigin::Subdomain(scheme, domain) => {
+                if !origin.starts_with(scheme.as_str()) || !origin.ends_with(domain.as_str()) {
+                    return false;
+                }
+                let host = &origin[scheme.len()..];
+                if host.len() <= domain.len() + 1 {
+                    return false;
+                }
+                let subdomain = &host[..host.len() - domain.len()];
+                subdomain.ends_with('.') && !subdomain.starts_with('.') &&
+                    subdomain.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+            }
+        }
+    }
+}
+
+/// Allowed methods and request headers of the routes matching the path pattern
+#[derive(Debug, Clone)]
+struct RouteCors {
+    path: String,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+}
+
+/// Matches a request path against a rocket like path pattern:
+/// "<name>" matches any segment, "<name..>" all remaining segments.
+fn path_matches(pattern: &str, path: &str) -> bool {
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    for pattern_segment in pattern.split('/').filter(|s| !s.is_empty()) {
+        if pattern_segment.starts_with('<') && pattern_segment.ends_with("..>") {
+            return true;
+        }
+        match segments.next() {
+            Some(_) if pattern_segment.starts_with('<') && pattern_segment.ends_with('>') => {}
+            Some(segment) if segment == pattern_segment => {}
+            _ => return false
+        }
+    }
+    segments.next().is_none()
+}
+
+/// The CORS policy of a service. Created with the allowed origins and the default allowed methods
+/// (GET, POST) and request headers (authorization, content-type) for all routes.
+///
+/// ```ignore
+/// CorsPolicy::new(&["https://openhabx.com", "https://*.openhabx.com"])
+///     .with_route("/addon/<id>", &[Method::Delete], &["authorization"])
+///     .with_max_age(3600)
+/// ```
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    origins: Vec<AllowedOrigin>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    routes: Vec<RouteCors>,
+    max_age: Option<u32>,
+    allow_credentials: bool,
+}
+
+fn lower_case<T: AsRef<str>>(values: &[T]) -> Vec<String> {
+    values.iter().map(|value| value.as_ref().to_ascii_lowercase()).collect()
+}
+
+impl CorsPolicy {
+    pub fn new<T: AsRef<str>>(origins: &[T]) -> CorsPolicy {
+        CorsPolicy {
+            origins: origins.iter().map(|origin| AllowedOrigin::parse(origin.as_ref())).collect(),
+            methods: vec![Method::Get, Method::Post],
+            headers: lower_case(&["authorization", "content-type"]),
+            routes: Vec::new(),
+            max_age: None,
+            allow_credentials: false,
+        }
+    }
+
+    /// Replaces the allowed origins by the ones of [`CORS_ORIGINS_FILE`], if configured
+    pub fn with_origins_from_config(mut self, config_source: &ConfigSource) -> Result<CorsPolicy, CloudAuthError> {
+        if let Some(json) = config_source.optional(CORS_ORIGINS_FILE)? {
+            let origins: Vec<String> = serde_json::from_str(&json)?;
+            self.origins = origins.iter().map(|origin| AllowedOrigin::parse(origin)).collect();
+        }
+        Ok(self)
+    }
+
+    /// The allowed methods of routes without a route specific policy
+    pub fn with_methods(mut self, methods: &[Method]) -> CorsPolicy {
+        self.methods = methods.to_vec();
+        self
+    }
+
+    /// The allowed request headers of routes without a route specific policy
+    pub fn with_headers<T: AsRef<str>>(mut self, headers: &[T]) -> CorsPolicy {
+        self.headers = lower_case(headers);
+        self
+    }
+
+    /// Allowed methods and request headers for routes matching the given path pattern, like "/addon/<id>".
+    /// The first matching pattern applies.
+    pub fn with_route<T: AsRef<str>>(mut self, path: &str, methods: &[Method], headers: &[T]) -> CorsPolicy {
+        self.routes.push(RouteCors { path: path.to_owned(), methods: methods.to_vec(), headers: lower_case(headers) });
+        self
+    }
 
-pub struct CorsFairing;
+    /// Browsers may cache preflight results for this amount of seconds
+    pub fn with_max_age(mut self, seconds: u32) -> CorsPolicy {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    /// Allows requests with cookies or http authentication. Not applicable to the "*" origin.
+    pub fn with_credentials(mut self) -> CorsPolicy {
+        self.allow_credentials = true;
+        self
+    }
+
+    fn matching_origin(&self, origin: &str) -> Option<&AllowedOrigin> {
+        let origin = origin.to_ascii_lowercase();
+        self.origins.iter().find(|allowed| allowed.matches(&origin))
+    }
+
+    pub fn is_origin_allowed(&self, origin: &str) -> bool {
+        self.matching_origin(origin).is_some()
+    }
+
+    /// Allowed methods and request headers for the given request path
+    fn route(&self, path: &str) -> (&[Method], &[String]) {
+        match self.routes.iter().find(|route| path_matches(&route.path, path)) {
+            Some(route) => (&route.methods, &route.headers),
+            None => (&self.methods, &self.headers)
+        }
+    }
+
+    /// Returns true if a preflight request for the given path, origin, requested method
+    /// and requested headers (comma separated) is allowed
+    pub fn is_preflight_allowed(&self, path: &str, origin: &str, method: &str, headers: Option<&str>) -> bool {
+        if !self.is_origin_allowed(origin) {
+            return false;
+        }
+        let (methods, allowed_headers) = self.route(path);
+        let method_allowed = methods.iter().any(|allowed| allowed.as_str().eq_ignore_ascii_case(method.trim()));
+        let headers_allowed = headers.map_or(true, |headers| headers.split(',')
+            .map(|header| header.trim().to_ascii_lowercase())
+            .filter(|header| !header.is_empty())
+            .all(|header| allowed_headers.contains(&header)));
+        method_allowed && headers_allowed
+    }
+}
+
+fn is_preflight(request: &Request) -> bool {
+    request.method() == Method::Options && request.headers().get_one("Access-Control-Request-Method").is_some()
+}
+
+/// Answers preflight requests with 204 if allowed by the managed [`CorsPolicy`] and with 403 otherwise.
+/// Other `OPTIONS` requests are not found.
+fn preflight<'r>(request: &'r Request, _data: Data) -> Outcome<'r> {
+    let policy = match request.guard::<State<CorsPolicy>>().succeeded() {
+        Some(policy) => policy,
+        None => return Outcome::Failure(Status::InternalServerError)
+    };
+    let headers = request.headers();
+    match (headers.get_one("Origin"), headers.get_one("Access-Control-Request-Method")) {
+        (Some(origin), Some(method)) => {
+            if policy.is_preflight_allowed(request.uri().path(), origin, method, headers.get_one("Access-Control-Request-Headers")) {
+                Outcome::from(request, Status::NoContent)
+            } else {
+                Outcome::Failure(Status::Forbidden)
+            }
+        }
+        _ => Outcome::from(request, Status::NotFound)
+    }
+}
+
+/// Applies the given [`CorsPolicy`]. Manages the policy and mounts the preflight route on attach.
+pub struct CorsFairing {
+    policy: CorsPolicy,
+}
+
+impl CorsFairing {
+    pub fn new(policy: CorsPolicy) -> CorsFairing {
+        CorsFairing { policy }
+    }
+}
 
 impl Fairing for CorsFairing {
     fn info(&self) -> Info {
         Info {
             name: "CORS Fairing",
-            kind: Kind::Response,
+            kind: Kind::Attach | Kind::Response,
         }
     }
 
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        Ok(rocket
+            .manage(self.policy.clone())
+            .mount("/", vec![Route::ranked(PREFLIGHT_RANK, Method::Options, "/<path..>", preflight)]))
+    }
+
     fn on_response(&self, request: &Request, response: &mut Response) {
-        // Add CORS headers to allow all origins to all outgoing requests
-        response.set_header(rocket::http::Header::new(
-            "Access-Control-Allow-Origin",
-            request.headers().get_one("Origin").unwrap_or("*").to_owned(),
-        ));
-        response.set_header(rocket::http::Header::new(
-            "Access-Control-Allow-Methods",
-            "POST",
-        ));
-        response.set_header(rocket::http::Header::new(
-            "Access-Control-Allow-Headers",
-            "origin, authorization, content-type",
-        ));
-        response.set_header(rocket::http::Header::new(
-            "Access-Control-Allow-Credentials",
-            "true",
-        ));
-        response.set_header(rocket::http::Header::new(
-            "Vary",
-            "Origin",
-        ));
-
-        // Respond to all `OPTIONS` requests with a `204` (no content) status
-        if response.status() == Status::NotFound && request.method() == Method::Options {
-            response.set_status(Status::NoContent);
-        }
-    }
-}
\ No newline at end of file
+        // Responses differ per origin and must not be cached for another one
+        response.set_header(Header::new("Vary", "Origin"));
+        let origin = match request.headers().get_one("Origin") {
+            Some(origin) => origin,
+            None => return
+        };
+        let allowed = match self.policy.matching_origin(origin) {
+            Some(allowed) => allowed,
+            None => return
+        };
+        let preflight = is_preflight(request);
+        if preflight && response.status() != Status::NoContent {
+            return;
+        }
+
+        if *allowed == AllowedOrigin::Any {
+            response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+        } else {
+            response.set_header(Header::new("Access-Control-Allow-Origin", origin.to_owned()));
+            if self.policy.allow_credentials {
+                response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+            }
+        }
+
+        if preflight {
+            let (methods, headers) = self.policy.route(request.uri().path());
+            let methods: Vec<&str> = methods.iter().map(|method| method.as_str()).collect();
+            response.set_header(Header::new("Access-Control-Allow-Methods", methods.join(", ")));
+            response.set_header(Header::new("Access-Control-Allow-Headers", headers.join(", ")));
+            if let Some(max_age) = self.policy.max_age {
+                response.set_header(Header::new("Access-Control-Max-Age", max_age.to_string()));
+            }
+        }
+    }
+}
+
+#[test]
+fn cors_policy_test() {
+    let policy = CorsPolicy::new(&["https://openhabx.com", "https://*.openhabx.com"])
+        .with_route("/addon/<id>", &[Method::Delete], &["authorization"])
+        .with_route("/files/<path..>", &[Method::Get], &["authorization"]);
+
+    assert!(policy.is_origin_allowed("https://openhabx.com"));
+    assert!(policy.is_origin_allowed("https://OAuth.openhabx.com"));
+    assert!(policy.is_origin_allowed("https://a.b.openhabx.com"));
+    assert!(!policy.is_origin_allowed("http://oauth.openhabx.com"));
+    assert!(!policy.is_origin_allowed("https://evilopenhabx.com"));
+    assert!(!policy.is_origin_allowed("https://.openhabx.com"));
+    assert!(!policy.is_origin_allowed("https://user@x.openhabx.com"));
+    assert!(!policy.is_origin_allowed("https://openhabx.com.evil.com"));
+
+    assert!(policy.is_preflight_allowed("/token", "https://openhabx.com", "POST", Some("Content-Type")));
+    assert!(!policy.is_preflight_allowed("/token", "https://openhabx.com", "DELETE", None));
+    assert!(!policy.is_preflight_allowed("/token", "https://openhabx.com", "POST", Some("x-custom")));
+    assert!(!policy.is_preflight_allowed("/token", "https://evil.com", "POST", None));
+    assert!(policy.is_preflight_allowed("/addon/my-addon", "https://openhabx.com", "DELETE", Some("authorization")));
+    assert!(!policy.is_preflight_allowed("/addon/my-addon", "https://openhabx.com", "POST", None));
+    assert!(policy.is_preflight_allowed("/files/a/b", "https://openhabx.com", "GET", None));
+
+    let any = CorsPolicy::new(&["*"]);
+    assert!(any.is_origin_allowed("https://example.com"));
+}
@@ -0,0 +1,34 @@
+use super::guard_ip_addr;
+use rocket::{http::Status, request, Outcome, State};
+use crate::audit::{AuditEvent, AuditLog};
+use crate::CloudAuthError;
+
+/// Records audit events of the current request. The client ip is added to each event.
+/// The [`AuditLog`] must be managed by the rocket instance.
+pub struct Auditor<'r> {
+    log: State<'r, AuditLog>,
+    ip: Option<String>,
+}
+
+impl<'r> Auditor<'r> {
+    pub fn record(&self, event: AuditEvent) {
+        self.log.record(event.with_ip(self.ip.as_ref()));
+    }
+
+    pub fn log(&self) -> &AuditLog {
+        &self.log
+    }
+}
+
+impl<'a, 'r> request::FromRequest<'a, 'r> for Auditor<'r> {
+    type Error = CloudAuthError;
+
+    fn from_request(request: &'a request::Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let log = match request.guard::<State<AuditLog>>().succeeded() {
+            Some(log) => log,
+            None => return Outcome::Failure((Status::InternalServerError, CloudAuthError::Generic("AuditLog not managed")))
+        };
+        let ip = guard_ip_addr::get_request_client_ip(&request).map(|client_addr| client_addr.ip.to_string());
+        Outcome::Success(Auditor { log, ip })
+    }
+}
@@ -0,0 +1,53 @@
+//! # Client authentication with HTTP Basic
+//! Confidential clients may authenticate with `Authorization: Basic base64(client_id:client_secret)`
+//! instead of sending the credentials as form fields (RFC 6749, 2.3.1).
+//! The client id and secret are form url encoded before they are joined.
+
+use rocket::Outcome;
+use rocket::http::RawStr;
+use rocket::request::{self, Request, FromRequest};
+
+/// The client credentials of an `Authorization: Basic` header.
+/// Forwards if there is no or a malformed basic authorization header.
+pub struct ClientBasicAuth {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+fn parse_basic_auth(header: &str) -> Option<ClientBasicAuth> {
+    if !header.starts_with("Basic ") {
+        return None;
+    }
+    let credentials = base64::decode(header[6..].trim()).ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let mut parts = credentials.splitn(2, ':');
+    let client_id = RawStr::from_str(parts.next()?).url_decode().ok()?;
+    let client_secret = RawStr::from_str(parts.next()?).url_decode().ok()?;
+    if client_id.is_empty() {
+        return None;
+    }
+    Some(ClientBasicAuth { client_id, client_secret })
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientBasicAuth {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.headers().get_one("Authorization").and_then(parse_basic_auth) {
+            Some(client_auth) => Outcome::Success(client_auth),
+            None => Outcome::Forward(())
+        }
+    }
+}
+
+#[test]
+fn parse_basic_auth_test() {
+    let header = format!("Basic {}", base64::encode("my%20client:se%3Acret"));
+    let client_auth = parse_basic_auth(&header).unwrap();
+    assert_eq!(client_auth.client_id, "my client");
+    assert_eq!(client_auth.client_secret, "se:cret");
+
+    assert!(parse_basic_auth("Bearer abc").is_none());
+    assert!(parse_basic_auth("Basic !invalid!").is_none());
+    assert!(parse_basic_auth(&format!("Basic {}", base64::encode("no_secret"))).is_none());
+}
@@ -1,56 +1,218 @@
+//! # Client ip address
+//! The address of the connected peer is the client address, unless the peer is a trusted proxy.
+//! For requests from trusted proxies the forwarding headers are evaluated in this order:
+//! 1. `Forwarded` (RFC 7239), the "for" parameters
+//! 2. `X-Forwarded-For`
+//! 3. `X-Real-IP`
+//!
+//! Forwarded addresses are read from right to left, each hop was added by the proxy on its right.
+//! The first address that is not a trusted proxy is the client address. Any client can send
+//! forwarding headers, so addresses left of it are ignored.
+//!
+//! The trusted proxies ([`TrustedProxies`]) must be managed by the rocket instance.
+//! Otherwise no proxy is trusted and the peer address is used.
+
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-use rocket::Outcome;
+use rocket::{Outcome, State};
+use rocket::http::HeaderMap;
 use rocket::request::{self, Request, FromRequest};
+use crate::config::ConfigSource;
+use crate::CloudAuthError;
+
+/// The name of the optional trusted proxies configuration, see [`TrustedProxies::from_config`]
+pub const TRUSTED_PROXIES_FILE: &str = "trusted_proxies.json";
+
+/// Loopback only. Used if [`TRUSTED_PROXIES_FILE`] is not configured.
+/// Private networks are not trusted by default: Any host in them could spoof its address otherwise.
+const DEFAULT_TRUSTED_PROXIES: &[&str] = &["127.0.0.0/8", "::1/128"];
+
+/// The rule that produced a [`ClientRealAddr`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientAddrSource {
+    /// The connected peer, which is not a trusted proxy
+    Remote,
+    /// The `Forwarded` header
+    Forwarded,
+    /// The `X-Forwarded-For` header
+    XForwardedFor,
+    /// The `X-Real-IP` header
+    XRealIp,
+}
 
 /// The request guard used for getting an IP address from a client.
 #[derive(Debug, Clone)]
 pub struct ClientRealAddr {
     /// IP address from a client.
-    pub ip: IpAddr
+    pub ip: IpAddr,
+    /// How the address has been determined
+    pub source: ClientAddrSource,
 }
 
-pub fn get_request_client_ip(request: &Request) -> Option<ClientRealAddr> {
-    match request.client_ip() {
-        Some(ip) => Some(ClientRealAddr {
-            ip
-        }),
-        None => {
-            let forwarded_for_ip: Option<&str> = request.headers().get("x-forwarded-for").next(); // Only fetch the first one.
-
-            match forwarded_for_ip {
-                Some(forwarded_for_ip) => {
-                    let forwarded_for_ip = forwarded_for_ip.split(",").next(); // Only fetch the first one.
-
-                    match forwarded_for_ip {
-                        Some(forwarded_for_ip) => match forwarded_for_ip.trim().parse::<IpAddr>() {
-                            Ok(ip) => Some(ClientRealAddr {
-                                ip
-                            }),
-                            Err(_) => match request.remote() {
-                                Some(addr) => Some(ClientRealAddr {
-                                    ip: addr.ip()
-                                }),
-                                None => None
-                            }
-                        },
-                        None => match request.remote() {
-                            Some(addr) => Some(ClientRealAddr {
-                                ip: addr.ip()
-                            }),
-                            None => None
-                        }
+/// An ip network in CIDR notation ("10.0.0.0/8"). A single address is a network with the full prefix length.
+#[derive(Debug, Clone, PartialEq)]
+struct IpNetwork {
+    address: IpAddr,
+    prefix: u8,
+}
+
+/// IPv4 mapped IPv6 addresses are compared as IPv4 addresses
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ipv6) => match ipv6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => IpAddr::V4(ipv6.to_ipv4().unwrap()),
+            _ => IpAddr::V6(ipv6)
+        },
+        ip => ip
+    }
+}
+
+impl IpNetwork {
+    fn parse(cidr: &str) -> Result<IpNetwork, CloudAuthError> {
+        let invalid = || CloudAuthError::GenericOwned(format!("Invalid network {}", cidr));
+        let mut parts = cidr.trim().splitn(2, '/');
+        let address = canonical(parts.next().unwrap_or_default().parse::<IpAddr>().map_err(|_| invalid())?);
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        Ok(IpNetwork { address, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = if self.prefix == 0 { 0 } else { !0u32 << (32 - self.prefix as u32) };
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = if self.prefix == 0 { 0 } else { !0u128 << (128 - self.prefix as u32) };
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false
+        }
+    }
+}
+
+/// The networks of trusted reverse proxies and load balancers
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNetwork>,
+}
+
+impl TrustedProxies {
+    /// Trust the given networks in CIDR notation, like "10.0.0.0/8" or "::1/128"
+    pub fn new<T: AsRef<str>>(networks: &[T]) -> Result<TrustedProxies, CloudAuthError> {
+        Ok(TrustedProxies { networks: networks.iter().map(|n| IpNetwork::parse(n.as_ref())).collect::<Result<_, _>>()? })
+    }
+
+    /// Trust no proxy. The peer address is always the client address.
+    pub fn none() -> TrustedProxies {
+        TrustedProxies::default()
+    }
+
+    /// Reads [`TRUSTED_PROXIES_FILE`], a json array of networks, from the runtime configuration.
+    /// Only loopback addresses are trusted if it is not configured. Real proxies must be configured.
+    pub fn from_config(config_source: &ConfigSource) -> Result<TrustedProxies, CloudAuthError> {
+        match config_source.optional(TRUSTED_PROXIES_FILE)? {
+            Some(json) => TrustedProxies::new(&serde_json::from_str::<Vec<String>>(&json)?),
+            None => TrustedProxies::new(DEFAULT_TRUSTED_PROXIES)
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+}
+
+/// Parses a "for" value of the `Forwarded` header: An ip address, optionally quoted, with a port
+/// or in brackets ("[2001:db8::1]:4711"). Obfuscated identifiers and "unknown" are not addresses.
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if node.starts_with('[') {
+        return node[1..].split(']').next().and_then(|ip| ip.parse().ok());
+    }
+    match node.parse::<IpAddr>() {
+        Ok(ip) => Some(ip),
+        // IPv4 with port
+        Err(_) => node.rsplitn(2, ':').last().and_then(|ip| ip.parse::<Ipv4Addr>().ok()).map(IpAddr::V4)
+    }
+}
+
+/// The "for" values of all `Forwarded` headers, in order. None if there is no `Forwarded` header.
+fn forwarded_hops(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let mut hops = Vec::new();
+    let mut present = false;
+    for header in headers.get("Forwarded") {
+        present = true;
+        for element in header.split(',') {
+            let node = element.split(';')
+                .filter_map(|pair| {
+                    let mut pair = pair.splitn(2, '=');
+                    match (pair.next(), pair.next()) {
+                        (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case("for") => Some(value),
+                        _ => None
                     }
-                }
-                None => match request.remote() {
-                    Some(addr) => Some(ClientRealAddr {
-                        ip: addr.ip()
-                    }),
-                    None => None
-                }
+                })
+                .next();
+            // An element without "for" parameter is a hop with an unknown address
+            hops.push(node.and_then(parse_forwarded_node));
+        }
+    }
+    if present { Some(hops) } else { None }
+}
+
+/// The values of all `X-Forwarded-For` headers, in order. None if there is no such header.
+fn x_forwarded_for_hops(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let hops: Vec<Option<IpAddr>> = headers.get("X-Forwarded-For")
+        .flat_map(|header| header.split(','))
+        .map(|hop| hop.trim().parse::<IpAddr>().ok())
+        .collect();
+    if hops.is_empty() { None } else { Some(hops) }
+}
+
+/// Determines the client address from the peer address and the forwarding headers, see the module documentation.
+pub fn resolve_client_addr(remote: IpAddr, headers: &HeaderMap, trusted: &TrustedProxies) -> ClientRealAddr {
+    let mut client = ClientRealAddr { ip: remote, source: ClientAddrSource::Remote };
+    if !trusted.contains(remote) {
+        return client;
+    }
+
+    let (hops, source) = match (forwarded_hops(headers), x_forwarded_for_hops(headers)) {
+        (Some(hops), _) => (hops, ClientAddrSource::Forwarded),
+        (None, Some(hops)) => (hops, ClientAddrSource::XForwardedFor),
+        (None, None) => {
+            if let Some(ip) = headers.get_one("X-Real-IP").and_then(|ip| ip.trim().parse::<IpAddr>().ok()) {
+                client = ClientRealAddr { ip, source: ClientAddrSource::XRealIp };
             }
+            return client;
+        }
+    };
+
+    for hop in hops.into_iter().rev() {
+        // Addresses left of an unknown hop cannot be verified
+        let ip = match hop {
+            Some(ip) => ip,
+            None => break
+        };
+        client = ClientRealAddr { ip, source };
+        if !trusted.contains(ip) {
+            break;
         }
     }
+    client
+}
+
+pub fn get_request_client_ip(request: &Request) -> Option<ClientRealAddr> {
+    let remote = request.remote()?.ip();
+    match request.guard::<State<TrustedProxies>>() {
+        Outcome::Success(trusted) => Some(resolve_client_addr(remote, request.headers(), &trusted)),
+        _ => Some(ClientRealAddr { ip: remote, source: ClientAddrSource::Remote })
+    }
 }
 
 impl<'a, 'r> FromRequest<'a, 'r> for ClientRealAddr {
@@ -125,4 +287,56 @@ impl ClientRealAddr {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn resolve_client_addr_test() {
+    use rocket::http::Header;
+
+    let trusted = TrustedProxies::new(&["10.0.0.0/8", "2001:db8::/32"]).unwrap();
+    let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+    let resolve = |remote: IpAddr, headers: &[(&'static str, &'static str)]| {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.add(Header::new(*name, *value));
+        }
+        let addr = resolve_client_addr(remote, &map, &trusted);
+        (addr.ip.to_string(), addr.source)
+    };
+
+    // Untrusted peers cannot forward
+    assert_eq!(resolve("1.2.3.4".parse().unwrap(), &[("X-Forwarded-For", "5.6.7.8")]),
+               ("1.2.3.4".to_owned(), ClientAddrSource::Remote));
+    assert_eq!(resolve(proxy, &[]), ("10.0.0.1".to_owned(), ClientAddrSource::Remote));
+
+    // Right to left, stopping at the first untrusted hop. "6.6.6.6" is spoofed by the client.
+    assert_eq!(resolve(proxy, &[("X-Forwarded-For", "6.6.6.6, 1.2.3.4, 10.0.0.2")]),
+               ("1.2.3.4".to_owned(), ClientAddrSource::XForwardedFor));
+    assert_eq!(resolve(proxy, &[("X-Forwarded-For", "6.6.6.6"), ("X-Forwarded-For", "1.2.3.4")]),
+               ("1.2.3.4".to_owned(), ClientAddrSource::XForwardedFor));
+    assert_eq!(resolve(proxy, &[("X-Forwarded-For", "1.2.3.4, invalid, 10.0.0.2")]),
+               ("10.0.0.2".to_owned(), ClientAddrSource::XForwardedFor));
+
+    // Forwarded takes precedence
+    assert_eq!(resolve(proxy, &[("Forwarded", r#"for=6.6.6.6, for="[2001:db9::1]:4711";proto=https, for=10.0.0.2"#),
+        ("X-Forwarded-For", "1.2.3.4")]), ("2001:db9::1".to_owned(), ClientAddrSource::Forwarded));
+    assert_eq!(resolve(proxy, &[("Forwarded", "for=1.2.3.4:8080;by=10.0.0.1")]),
+               ("1.2.3.4".to_owned(), ClientAddrSource::Forwarded));
+    assert_eq!(resolve(proxy, &[("Forwarded", "for=_hidden")]), ("10.0.0.1".to_owned(), ClientAddrSource::Remote));
+
+    assert_eq!(resolve(proxy, &[("X-Real-IP", "1.2.3.4")]), ("1.2.3.4".to_owned(), ClientAddrSource::XRealIp));
+    assert_eq!(resolve("1.2.3.4".parse().unwrap(), &[("X-Real-IP", "5.6.7.8")]), ("1.2.3.4".to_owned(), ClientAddrSource::Remote));
+
+    assert!(TrustedProxies::new(&["::ffff:10.0.0.0/104"]).is_err());
+    assert!(TrustedProxies::new(&["10.0.0.0/8"]).unwrap().contains("::ffff:10.1.2.3".parse().unwrap()));
+    assert!(TrustedProxies::new(&["0.0.0.0/0"]).unwrap().contains("1.2.3.4".parse().unwrap()));
+    assert!(!TrustedProxies::none().contains(proxy));
+
+    // Only loopback is trusted by default
+    let default = TrustedProxies::new(DEFAULT_TRUSTED_PROXIES).unwrap();
+    assert!(default.contains("127.0.0.1".parse().unwrap()));
+    assert!(default.contains("::1".parse().unwrap()));
+    for private in &["10.0.0.1", "172.16.0.1", "192.168.0.1", "169.254.0.1", "fc00::1", "fe80::1"] {
+        assert!(!default.contains(private.parse().unwrap()));
+    }
+}
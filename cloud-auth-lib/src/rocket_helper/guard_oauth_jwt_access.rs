@@ -1,9 +1,13 @@
 use crate::credentials::Credentials;
+use crate::deny_list::TokenDenyList;
 use crate::jwt::verify_access_token;
-use rocket::{request, Outcome, State};
+use rocket::{http::Status, request, Outcome, State};
 use std::collections::{BTreeSet};
 use crate::CloudAuthError;
 
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
 pub struct OAuthIdentity {
     pub credentials_index: usize,
     pub credentials_email: String,
@@ -12,7 +16,9 @@ pub struct OAuthIdentity {
     pub scopes: BTreeSet<String>,
 }
 
-fn get_token(request: &request::Request) -> Option<OAuthIdentity> {
+/// Returns the identity of a valid, not revoked token or None.
+/// Fails if the deny-list is not managed: Revoked tokens would be accepted otherwise.
+fn get_token(request: &request::Request) -> Result<Option<OAuthIdentity>, Status> {
     let token = request
         .headers()
         .get_one("Authorization")
@@ -27,7 +33,7 @@ fn get_token(request: &request::Request) -> Option<OAuthIdentity> {
         None => request.get_query_value("auth").and_then(|r| r.ok())
     };
     if token.is_none() {
-        return None;
+        return Ok(None);
     }
     let token = token.unwrap();
     type CredentialsList = Vec<Credentials>;
@@ -35,7 +41,16 @@ fn get_token(request: &request::Request) -> Option<OAuthIdentity> {
     let credentials_list = match request
         .guard::<State<CredentialsList>>() {
         Outcome::Success(s) => s,
-        _ => return None
+        _ => return Ok(None)
+    };
+
+    // Revoked tokens and failed lookups are rejected (fail closed)
+    let deny_list = match request.guard::<State<TokenDenyList>>() {
+        Outcome::Success(s) => s,
+        _ => {
+            error!("The token deny-list is not managed");
+            return Err(Status::InternalServerError);
+        }
     };
 
     let mut counter: usize = 0;
@@ -43,32 +58,45 @@ fn get_token(request: &request::Request) -> Option<OAuthIdentity> {
         match verify_access_token(&credentials, &token) {
             Ok(token_validation_result) => {
                 if let Some(validation_result) = token_validation_result {
-                    return Some(OAuthIdentity {
+                    if let Some(jti) = validation_result.jti.as_ref() {
+                        match deny_list.is_denied(jti) {
+                            Ok(false) => {}
+                            Ok(true) => return Ok(None),
+                            Err(e) => {
+                                warn!("Token deny-list lookup failed: {:?}", e);
+                                return Ok(None);
+                            }
+                        }
+                    }
+                    return Ok(Some(OAuthIdentity {
                         credentials_index: counter,
                         credentials_email: credentials.client_email.clone(),
                         scopes: validation_result.claims.scope,
                         user_id: validation_result.claims.uid,
                         client_id: validation_result.claims.client_id,
-                    });
+                    }));
                 }
             }
             // An error means that the credentials matching but the token is invalid
             Err(_e) => {
-                return None;
+                return Ok(None);
             }
         }
         counter += 1;
     }
-    None
+    Ok(None)
 }
 
+const DENY_LIST_NOT_MANAGED: CloudAuthError = CloudAuthError::Generic("TokenDenyList not managed");
+
 impl<'a, 'r> request::FromRequest<'a, 'r> for OAuthIdentity {
     type Error = CloudAuthError;
 
     fn from_request(request: &'a request::Request<'r>) -> request::Outcome<Self, Self::Error> {
         match get_token(request) {
-            Some(data) => Outcome::Success(data),
-            None => Outcome::Forward(())
+            Ok(Some(data)) => Outcome::Success(data),
+            Ok(None) => Outcome::Forward(()),
+            Err(status) => Outcome::Failure((status, DENY_LIST_NOT_MANAGED))
         }
     }
 }
@@ -77,11 +105,12 @@ impl<'a, 'r> request::FromRequest<'a, 'r> for &'a OAuthIdentity {
     type Error = CloudAuthError;
 
     fn from_request(request: &'a request::Request<'r>) -> request::Outcome<Self, Self::Error> {
-        let cache: &Option<OAuthIdentity> = request.local_cache(|| get_token(request));
+        let cache: &Result<Option<OAuthIdentity>, Status> = request.local_cache(|| get_token(request));
 
-        match &cache {
-            Some(data) => Outcome::Success(data),
-            None => Outcome::Forward(())
+        match cache {
+            Ok(Some(data)) => Outcome::Success(data),
+            Ok(None) => Outcome::Forward(()),
+            Err(status) => Outcome::Failure((*status, DENY_LIST_NOT_MANAGED))
         }
     }
 }
\ No newline at end of file
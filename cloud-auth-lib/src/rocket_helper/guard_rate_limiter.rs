@@ -1,18 +1,140 @@
 use super::guard_ip_addr;
-use std::sync::Mutex;
-use rocket::{http::Status, request, Outcome, State};
-use ratelimit_meter::KeyedRateLimiter;
-use nonzero_ext::NonZero;
+use super::guard_oauth_jwt_access::OAuthIdentity;
+use std::sync::Arc;
+use rocket::{http::{Header, Status}, request, Outcome, Request, Response, State};
+use rocket::fairing::{Fairing, Info, Kind};
+use crate::rate_limit::{KeyKind, MemoryRateLimitBackend, Quota, RateLimitBackend, RateLimitDecision, RateLimits};
+use crate::oauth_clients::OAuthClientStore;
+use crate::storage::DocumentStore;
 use crate::CloudAuthError;
 
-pub struct RateLimiterMutex(Mutex<KeyedRateLimiter< std::net::IpAddr > >);
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
 
+/// The rate limit backend and the configured quotas. Must be managed by the rocket instance.
+pub struct RateLimiterState {
+    backend: Arc<dyn RateLimitBackend>,
+    limits: RateLimits,
+}
+
+/// Rate limits by client ip
 pub struct RateLimiter {}
 
-impl RateLimiterMutex {
-    pub fn new(rate: u32) -> RateLimiterMutex {
-        let rate = NonZero::new(rate).unwrap();
-        RateLimiterMutex(Mutex::new(KeyedRateLimiter::< std::net::IpAddr >::per_second(rate)))
+/// Rate limits by client ip and additionally by the user of a valid access token, or by its oauth client for tokens
+/// without a user. The quota can be configured per oauth client, see [`RateLimits::resolve_identity`].
+/// The ip quota always applies first, so that many tokens do not allow to exceed it.
+pub struct IdentityRateLimiter {}
+
+impl RateLimiterState {
+    /// Allows `rate` requests per second and client ip, counted in memory
+    pub fn new(rate: u32) -> RateLimiterState {
+        RateLimiterState::with_backend(Arc::new(MemoryRateLimitBackend::new()), RateLimits::per_second(rate))
+    }
+
+    pub fn with_backend(backend: Arc<dyn RateLimitBackend>, limits: RateLimits) -> RateLimiterState {
+        RateLimiterState { backend, limits }
+    }
+
+    /// Counts the request for the given key kind and id on the given route (by route function name).
+    /// Returns None if no quota is configured for the key kind.
+    pub fn check(&self, route: Option<&str>, kind: KeyKind, id: &str) -> Result<Option<RateLimitDecision>, CloudAuthError> {
+        match self.limits.resolve(route, kind, id) {
+            Some((quota, key)) => Ok(Some(self.backend.check(&key, &quota)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// Counts the request for the user or the client of an access token, see [`RateLimits::resolve_identity`].
+    pub fn check_identity(&self, route: Option<&str>, user_id: Option<&str>, client_id: Option<&str>, registered: Option<Quota>) -> Result<Option<RateLimitDecision>, CloudAuthError> {
+        match self.limits.resolve_identity(route, user_id, client_id, registered) {
+            Some((quota, key)) => Ok(Some(self.backend.check(&key, &quota)?)),
+            None => Ok(None)
+        }
+    }
+}
+
+/// The rate limit decision of the current request, if any. Used for the response headers.
+pub struct RateLimitStatus(pub Option<RateLimitDecision>);
+
+/// Milliseconds to full seconds, rounded up
+fn to_secs(millis: i64) -> i64 {
+    (millis + 999) / 1000
+}
+
+/// Adds the `RateLimit-Limit`, `RateLimit-Remaining`, `RateLimit-Reset` headers
+/// and `Retry-After` for rejected requests.
+pub fn set_rate_limit_headers(response: &mut Response, decision: &RateLimitDecision) {
+    response.set_header(Header::new("RateLimit-Limit", decision.limit.to_string()));
+    response.set_header(Header::new("RateLimit-Remaining", decision.remaining.to_string()));
+    response.set_header(Header::new("RateLimit-Reset", to_secs(decision.reset_after).to_string()));
+    if let Some(retry_after) = decision.retry_after {
+        response.set_header(Header::new("Retry-After", to_secs(retry_after).to_string()));
+    }
+}
+
+fn rate_limiter_state<'a, 'r>(request: &'a Request<'r>) -> Result<State<'r, RateLimiterState>, (Status, CloudAuthError)> {
+    request.guard::<State<RateLimiterState>>().succeeded()
+        .ok_or((Status::InternalServerError, CloudAuthError::Generic("RateLimiterState not managed")))
+}
+
+/// The decision with the fewest remaining requests, a rejection in any case
+fn most_restrictive(a: Option<RateLimitDecision>, b: Option<RateLimitDecision>) -> Option<RateLimitDecision> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if !b.allowed || (a.allowed && b.remaining < a.remaining) { b } else { a }),
+        (a, b) => a.or(b)
+    }
+}
+
+/// Remembers the decision for the response headers and fails if the request is rejected.
+/// Backend errors fail the request with 503 Service Unavailable instead of allowing it without a limit.
+fn apply_decision<'a, 'r>(request: &'a Request<'r>, result: Result<Option<RateLimitDecision>, CloudAuthError>) -> request::Outcome<(), CloudAuthError> {
+    match result {
+        Ok(Some(decision)) => {
+            let allowed = decision.allowed;
+            request.local_cache(|| RateLimitStatus(Some(decision)));
+            if !allowed {
+                return Outcome::Failure((Status::TooManyRequests, CloudAuthError::TooManyRequests));
+            }
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("Rate limit backend failed: {}", e);
+            return Outcome::Failure((Status::ServiceUnavailable, e));
+        }
+    }
+    Outcome::Success(())
+}
+
+/// Counts the request for the given key and remembers the decision for the response headers.
+/// Backend errors fail the request.
+pub(crate) fn check_request<'a, 'r>(request: &'a Request<'r>, kind: KeyKind, id: &str) -> request::Outcome<(), CloudAuthError> {
+    let state = match rate_limiter_state(request) {
+        Ok(state) => state,
+        Err(e) => return Outcome::Failure(e)
+    };
+    let route = request.route().and_then(|route| route.name);
+    apply_decision(request, state.check(route, kind, id))
+}
+
+/// The quota of the registered oauth client, if the client registry is managed by the rocket instance
+fn registered_client_quota<'a, 'r>(request: &'a Request<'r>, client_id: &str) -> Option<Quota> {
+    let oauth_clients = request.guard::<State<OAuthClientStore>>().succeeded()?;
+    let documents = request.guard::<State<Arc<dyn DocumentStore>>>().succeeded()?;
+    oauth_clients.get(&**documents, client_id).and_then(|client| client.rate_limit)
+}
+
+fn check_ip<'a, 'r>(request: &'a Request<'r>) -> request::Outcome<(), CloudAuthError> {
+    match guard_ip_addr::get_request_client_ip(&request) {
+        Some(client_addr) => check_request(request, KeyKind::Ip, &client_addr.ip.to_string()),
+        None => Outcome::Success(())
+    }
+}
+
+/// Counts the request for the client ip. Does not remember the decision.
+fn check_ip_quota<'a, 'r>(request: &'a Request<'r>, state: &RateLimiterState, route: Option<&str>) -> Result<Option<RateLimitDecision>, CloudAuthError> {
+    match guard_ip_addr::get_request_client_ip(&request) {
+        Some(client_addr) => state.check(route, KeyKind::Ip, &client_addr.ip.to_string()),
+        None => Ok(None)
     }
 }
 
@@ -20,26 +142,73 @@ impl<'a, 'r> request::FromRequest<'a, 'r> for RateLimiter {
     type Error = CloudAuthError;
 
     fn from_request(request: &'a request::Request<'r>) -> request::Outcome<Self, Self::Error> {
-        let rate_limiter_mutex = request
-            .guard::<State<RateLimiterMutex>>()
-            .success_or(CloudAuthError::TooManyRequests);
-        if let Err(err) = rate_limiter_mutex {
-            return Outcome::Failure((Status::InternalServerError, err));
-        }
-        let rate_limiter_mutex = &rate_limiter_mutex.as_ref().unwrap().0;
-        let client_addr = guard_ip_addr::get_request_client_ip(&request);
-
-        if let Some(client_addr) = client_addr {
-            // The rate limiter state is mutex locked. Unwrap and check if the limit has been hit.
-            if let Ok(mut rate_limiter) = rate_limiter_mutex.lock() {
-                if rate_limiter.check(client_addr.ip).is_err() {
-                    return Outcome::Failure((Status::TooManyRequests, CloudAuthError::TooManyRequests));
-                }
-            }
+        if let Outcome::Failure(f) = check_ip(request) {
+            return Outcome::Failure(f);
         }
 
         Outcome::Success(RateLimiter {})
     }
 }
 
-pub fn create() {}
\ No newline at end of file
+impl<'a, 'r> request::FromRequest<'a, 'r> for IdentityRateLimiter {
+    type Error = CloudAuthError;
+
+    fn from_request(request: &'a request::Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let state = match rate_limiter_state(request) {
+            Ok(state) => state,
+            Err(e) => return Outcome::Failure(e)
+        };
+        let route = request.route().and_then(|route| route.name);
+
+        // The ip quota first: A rejected or failed check does not count the identity
+        let ip_decision = match check_ip_quota(request, &state, route) {
+            Ok(Some(decision)) if !decision.allowed => return apply_decision(request, Ok(Some(decision))).map(|_| IdentityRateLimiter {}),
+            Ok(decision) => decision,
+            Err(e) => return apply_decision(request, Err(e)).map(|_| IdentityRateLimiter {})
+        };
+
+        let result = match request.guard::<&OAuthIdentity>().succeeded() {
+            Some(identity) => {
+                let registered = identity.client_id.as_ref().and_then(|client_id| registered_client_quota(request, client_id));
+                state.check_identity(route, identity.user_id.as_ref().map(|id| id.as_str()),
+                                     identity.client_id.as_ref().map(|id| id.as_str()), registered)
+            }
+            None => Ok(None)
+        };
+        // Only one decision is remembered for the response headers: The most restrictive one
+        let outcome = apply_decision(request, result.map(|identity_decision| most_restrictive(ip_decision, identity_decision)));
+        if let Outcome::Failure(f) = outcome {
+            return Outcome::Failure(f);
+        }
+
+        Outcome::Success(IdentityRateLimiter {})
+    }
+}
+
+/// Adds the rate limit headers to responses of rate limited routes
+pub struct RateLimitHeaders;
+
+impl Fairing for RateLimitHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit headers",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if let Some(ref decision) = request.local_cache(|| RateLimitStatus(None)).0 {
+            set_rate_limit_headers(response, decision);
+        }
+    }
+}
+
+#[test]
+fn most_restrictive_test() {
+    let decision = |allowed: bool, remaining: u32| RateLimitDecision { allowed, limit: 10, remaining, reset_after: 0, retry_after: None };
+    assert_eq!(most_restrictive(Some(decision(true, 5)), Some(decision(true, 2))), Some(decision(true, 2)));
+    assert_eq!(most_restrictive(Some(decision(true, 1)), Some(decision(true, 2))), Some(decision(true, 1)));
+    assert_eq!(most_restrictive(Some(decision(true, 5)), Some(decision(false, 0))), Some(decision(false, 0)));
+    assert_eq!(most_restrictive(None, Some(decision(true, 3))), Some(decision(true, 3)));
+    assert_eq!(most_restrictive(Some(decision(true, 3)), None), Some(decision(true, 3)));
+}
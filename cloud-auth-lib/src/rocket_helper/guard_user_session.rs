@@ -0,0 +1,45 @@
+use crate::identity::IdentityProvider;
+use crate::CloudAuthError;
+use rocket::{http::Status, request, Outcome, State};
+use std::sync::Arc;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// The user of a valid session token ("Authorization: Bearer {token}"), like the website sends it.
+/// The token is verified by the managed `Arc<dyn IdentityProvider>`.
+///
+/// Forwards if there is no or an invalid session token. Fails if no identity provider is managed.
+pub struct UserSessionGuard {
+    pub user_id: String,
+}
+
+const IDENTITY_PROVIDER_NOT_MANAGED: CloudAuthError = CloudAuthError::Generic("IdentityProvider not managed");
+
+impl<'a, 'r> request::FromRequest<'a, 'r> for UserSessionGuard {
+    type Error = CloudAuthError;
+
+    fn from_request(request: &'a request::Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let session_token = match request.headers().get_one("Authorization") {
+            Some(bearer) if bearer.starts_with("Bearer ") => &bearer[7..],
+            _ => return Outcome::Forward(())
+        };
+
+        let identities = match request.guard::<State<Arc<dyn IdentityProvider>>>() {
+            Outcome::Success(s) => s,
+            _ => {
+                error!("The identity provider is not managed");
+                return Outcome::Failure((Status::InternalServerError, IDENTITY_PROVIDER_NOT_MANAGED));
+            }
+        };
+
+        match identities.verify_session(session_token) {
+            Ok(Some(user_id)) => Outcome::Success(UserSessionGuard { user_id }),
+            Ok(None) => Outcome::Forward(()),
+            Err(e) => {
+                warn!("User session lookup failed: {:?}", e);
+                Outcome::Forward(())
+            }
+        }
+    }
+}
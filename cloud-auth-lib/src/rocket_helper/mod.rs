@@ -1,6 +1,9 @@
 pub mod catch_all;
 pub mod error_routes;
 pub mod fairing_cors;
+pub mod guard_audit;
+pub mod guard_client_basic_auth;
 pub mod guard_ip_addr;
 pub mod guard_oauth_jwt_access;
-pub mod guard_rate_limiter;
\ No newline at end of file
+pub mod guard_rate_limiter;
+pub mod guard_user_session;
\ No newline at end of file
@@ -0,0 +1,261 @@
+use super::{document_matches, merge_documents, DocumentStore, QueryOperator, TokenStore};
+use crate::CloudAuthError;
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A document store that persists each document as json file: `{directory}/{collection}/{id}.json`.
+///
+/// Meant for local development and tests. All operations are serialized by a lock.
+pub struct FileDocumentStore {
+    directory: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileDocumentStore {
+    /// Creates the store. The directory is created on the first write.
+    pub fn new<P: Into<PathBuf>>(directory: P) -> FileDocumentStore {
+        FileDocumentStore { directory: directory.into(), lock: Mutex::new(()) }
+    }
+
+    /// Collection names and ids must not be able to escape the store directory
+    fn checked_name(name: &str) -> Result<&str, CloudAuthError> {
+        if name.is_empty() || name.starts_with('.') || name.contains(|c| c == '/' || c == '\\') {
+            return Err(CloudAuthError::GenericOwned(format!("Invalid document path segment {}", name)));
+        }
+        Ok(name)
+    }
+
+    fn document_path(&self, collection: &str, id: &str) -> Result<PathBuf, CloudAuthError> {
+        Ok(self.directory.join(Self::checked_name(collection)?).join(format!("{}.json", Self::checked_name(id)?)))
+    }
+
+    fn read_file(path: &Path) -> Result<Option<Value>, CloudAuthError> {
+        match std::fs::read(path) {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+}
+
+const POISONED: CloudAuthError = CloudAuthError::Generic("File store lock poisoned");
+
+impl DocumentStore for FileDocumentStore {
+    fn read_value(&self, collection: &str, id: &str) -> Result<Option<Value>, CloudAuthError> {
+        let _lock = self.lock.lock().map_err(|_| POISONED)?;
+        Self::read_file(&self.document_path(collection, id)?)
+    }
+
+    fn write_value(&self, collection: &str, id: &str, document: Value, merge: bool) -> Result<(), CloudAuthError> {
+        let _lock = self.lock.lock().map_err(|_| POISONED)?;
+        let path = self.document_path(collection, id)?;
+        let document = match Self::read_file(&path)? {
+            Some(mut existing) if merge => {
+                merge_documents(&mut existing, document);
+                existing
+            }
+            _ => document
+        };
+        std::fs::create_dir_all(self.directory.join(collection))?;
+        // Write to a temporary file first, so that a document is never partially written
+        let temp_path = path.with_extension("json.tmp");
+        std::fs::write(&temp_path, serde_json::to_vec_pretty(&document)?)?;
+        std::fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+
+    fn delete(&self, collection: &str, id: &str) -> Result<(), CloudAuthError> {
+        let _lock = self.lock.lock().map_err(|_| POISONED)?;
+        match std::fs::remove_file(self.document_path(collection, id)?) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            r => Ok(r?)
+        }
+    }
+
+    fn query_values(&self, collection: &str, field: &str, operator: QueryOperator, value: Value) -> Result<Vec<(String, Value)>, CloudAuthError> {
+        Ok(self.list_values(collection)?.into_iter()
+            .filter(|(_, document)| document_matches(document, field, operator, &value))
+            .collect())
+    }
+
+    fn list_values(&self, collection: &str) -> Result<Vec<(String, Value)>, CloudAuthError> {
+        let _lock = self.lock.lock().map_err(|_| POISONED)?;
+        let entries = match std::fs::read_dir(self.directory.join(Self::checked_name(collection)?)) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into())
+        };
+        let mut documents = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().map_or(true, |extension| extension != "json") {
+                continue;
+            }
+            let id = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(id) => id.to_owned(),
+                None => continue
+            };
+            if let Some(document) = Self::read_file(&path)? {
+                documents.push((id, document));
+            }
+        }
+        documents.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(documents)
+    }
+}
+
+/// A token store that persists all values in one json file: `{"key": ["value", expiry]}`.
+/// The expiry is a unix timestamp. Expired values are removed on write.
+///
+/// Meant for local development and tests. All operations are serialized by a lock.
+pub struct FileTokenStore {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+/// Value and expiry (unix timestamp) per key
+type TokenEntries = HashMap<String, (String, i64)>;
+
+impl FileTokenStore {
+    /// Creates the store. The file and its directory are created on the first write.
+    pub fn new<P: Into<PathBuf>>(path: P) -> FileTokenStore {
+        FileTokenStore { path: path.into(), lock: Mutex::new(()) }
+    }
+
+    /// Reads all entries that are not yet expired
+    fn read_entries(&self, now: i64) -> Result<TokenEntries, CloudAuthError> {
+        let mut entries: TokenEntries = match std::fs::read(&self.path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(TokenEntries::new()),
+            Err(e) => return Err(e.into())
+        };
+        entries.retain(|_, (_, expiry)| *expiry > now);
+        Ok(entries)
+    }
+
+    fn write_entries(&self, entries: &TokenEntries) -> Result<(), CloudAuthError> {
+        if let Some(directory) = self.path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        // Write to a temporary file first, so that the file is never partially written
+        let temp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&temp_path, serde_json::to_vec(entries)?)?;
+        std::fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn get(&self, key: &str) -> Result<Option<String>, CloudAuthError> {
+        let _lock = self.lock.lock().map_err(|_| POISONED)?;
+        let mut entries = self.read_entries(chrono::Utc::now().timestamp())?;
+        Ok(entries.remove(key).map(|(value, _)| value))
+    }
+
+    fn set(&self, key: &str, value: &str, expires_in: usize) -> Result<(), CloudAuthError> {
+        let _lock = self.lock.lock().map_err(|_| POISONED)?;
+        let now = chrono::Utc::now().timestamp();
+        let mut entries = self.read_entries(now)?;
+        entries.insert(key.to_owned(), (value.to_owned(), now + expires_in as i64));
+        self.write_entries(&entries)
+    }
+
+    fn set_if_absent(&self, key: &str, value: &str, expires_in: usize) -> Result<bool, CloudAuthError> {
+        let _lock = self.lock.lock().map_err(|_| POISONED)?;
+        let now = chrono::Utc::now().timestamp();
+        let mut entries = self.read_entries(now)?;
+        if entries.contains_key(key) {
+            return Ok(false);
+        }
+        entries.insert(key.to_owned(), (value.to_owned(), now + expires_in as i64));
+        self.write_entries(&entries)?;
+        Ok(true)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), CloudAuthError> {
+        let _lock = self.lock.lock().map_err(|_| POISONED)?;
+        let mut entries = self.read_entries(chrono::Utc::now().timestamp())?;
+        if entries.remove(key).is_some() {
+            self.write_entries(&entries)?;
+        }
+        Ok(())
+    }
+
+    fn take(&self, key: &str) -> Result<Option<String>, CloudAuthError> {
+        let _lock = self.lock.lock().map_err(|_| POISONED)?;
+        let mut entries = self.read_entries(chrono::Utc::now().timestamp())?;
+        match entries.remove(key) {
+            Some((value, _)) => {
+                self.write_entries(&entries)?;
+                Ok(Some(value))
+            }
+            None => Ok(None)
+        }
+    }
+
+    fn ttl(&self, key: &str) -> Result<Option<i64>, CloudAuthError> {
+        let _lock = self.lock.lock().map_err(|_| POISONED)?;
+        let now = chrono::Utc::now().timestamp();
+        Ok(self.read_entries(now)?.get(key).map(|(_, expiry)| *expiry - now))
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>, CloudAuthError> {
+        let _lock = self.lock.lock().map_err(|_| POISONED)?;
+        Ok(self.read_entries(chrono::Utc::now().timestamp())?.into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| key.starts_with(prefix))
+            .collect())
+    }
+}
+
+#[test]
+fn file_token_store_test() {
+    let directory = std::env::temp_dir().join(format!("file_token_store_test_{}", uuid::Uuid::new_v4()));
+    let store = FileTokenStore::new(directory.join("tokens.json"));
+    assert!(store.get("code").unwrap().is_none());
+    assert!(store.set_if_absent("code", "value", 60).unwrap());
+    assert!(!store.set_if_absent("code", "other", 60).unwrap());
+    assert!(store.ttl("code").unwrap().unwrap() > 0);
+    assert_eq!(store.keys("co").unwrap(), vec!["code".to_owned()]);
+
+    // Values survive a restart
+    let store = FileTokenStore::new(directory.join("tokens.json"));
+    assert_eq!(store.get("code").unwrap().unwrap(), "value");
+    assert_eq!(store.take("code").unwrap().unwrap(), "value");
+    assert!(store.take("code").unwrap().is_none());
+
+    store.set("expired", "value", 0).unwrap();
+    assert!(store.get("expired").unwrap().is_none());
+    store.set("deleted", "value", 60).unwrap();
+    store.delete("deleted").unwrap();
+    assert!(store.ttl("deleted").unwrap().is_none());
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn file_document_store_test() {
+    let directory = std::env::temp_dir().join(format!("file_document_store_test_{}", uuid::Uuid::new_v4()));
+    let file_store = FileDocumentStore::new(&directory);
+    let store: &dyn DocumentStore = &file_store;
+
+    store.write("access_tokens", "a", &serde_json::json!({"uid": "user", "issued_at": 1})).unwrap();
+    store.merge("access_tokens", "a", &serde_json::json!({"issued_at": 2})).unwrap();
+    store.write("access_tokens", "b", &serde_json::json!({"uid": "other", "issued_at": 3})).unwrap();
+
+    let a: Value = store.read("access_tokens", "a").unwrap().unwrap();
+    assert_eq!(a, serde_json::json!({"uid": "user", "issued_at": 2}));
+    let result: Vec<(String, Value)> = store.query("access_tokens", "uid", QueryOperator::Equal, "user").unwrap();
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].0, "a");
+
+    store.delete("access_tokens", "a").unwrap();
+    store.delete("access_tokens", "a").unwrap();
+    assert!(store.read::<Value>("access_tokens", "a").unwrap().is_none());
+    assert!(store.read::<Value>("access_tokens", "../a").is_err());
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,79 @@
+use super::{DocumentStore, QueryOperator};
+use crate::CloudAuthError;
+
+use firestore_db_and_auth::{documents, dto, errors::FirebaseError, firebase_rest_to_rust, sessions::service_account::Session as SASession};
+use serde_json::Value;
+use std::sync::Mutex;
+
+/// A document store backed by Firestore, accessed via a service account session
+pub struct FirestoreDocumentStore {
+    session: Mutex<SASession>,
+}
+
+impl FirestoreDocumentStore {
+    pub fn new(session: SASession) -> FirestoreDocumentStore {
+        FirestoreDocumentStore { session: Mutex::new(session) }
+    }
+
+    fn session(&self) -> Result<std::sync::MutexGuard<SASession>, CloudAuthError> {
+        self.session.lock().map_err(|_| CloudAuthError::Generic("Firestore session poisoned"))
+    }
+}
+
+/// The document id is the last segment of the absolute document path
+fn document_id(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+impl DocumentStore for FirestoreDocumentStore {
+    fn read_value(&self, collection: &str, id: &str) -> Result<Option<Value>, CloudAuthError> {
+        let session = self.session()?;
+        match documents::read(&*session, collection, id) {
+            Ok(document) => Ok(Some(document)),
+            Err(FirebaseError::APIError(404, _, _)) => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    fn write_value(&self, collection: &str, id: &str, document: Value, merge: bool) -> Result<(), CloudAuthError> {
+        let session = self.session()?;
+        documents::write(&*session, collection, Some(id), &document, documents::WriteOptions { merge })?;
+        Ok(())
+    }
+
+    fn delete(&self, collection: &str, id: &str) -> Result<(), CloudAuthError> {
+        let session = self.session()?;
+        documents::delete(&*session, &format!("{}/{}", collection, id), false)?;
+        Ok(())
+    }
+
+    fn query_values(&self, collection: &str, field: &str, operator: QueryOperator, value: Value) -> Result<Vec<(String, Value)>, CloudAuthError> {
+        let operator = match operator {
+            QueryOperator::Equal => dto::FieldOperator::EQUAL,
+            QueryOperator::LessThan => dto::FieldOperator::LESS_THAN,
+            QueryOperator::LessThanOrEqual => dto::FieldOperator::LESS_THAN_OR_EQUAL,
+            QueryOperator::GreaterThan => dto::FieldOperator::GREATER_THAN,
+            QueryOperator::GreaterThanOrEqual => dto::FieldOperator::GREATER_THAN_OR_EQUAL,
+        };
+        let session = self.session()?;
+        // The query result contains the document fields already, no need to read each document
+        let result = documents::query(&*session, collection, value, operator, field)?;
+        let mut found = Vec::with_capacity(result.len());
+        for document in result {
+            let value: Value = firebase_rest_to_rust::document_to_pod(&document)?;
+            found.push((document_id(&document.name).to_owned(), value));
+        }
+        Ok(found)
+    }
+
+    fn list_values(&self, collection: &str) -> Result<Vec<(String, Value)>, CloudAuthError> {
+        let session = self.session()?;
+        let list: documents::List<Value, _> = documents::list(&*session, collection);
+        let mut found = Vec::new();
+        for document_and_metadata in list {
+            let (document, metadata) = document_and_metadata?;
+            found.push((document_id(&metadata.name).to_owned(), document));
+        }
+        Ok(found)
+    }
+}
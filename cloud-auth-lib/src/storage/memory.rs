@@ -0,0 +1,171 @@
+use super::{document_matches, merge_documents, DocumentStore, QueryOperator, TokenStore};
+use crate::CloudAuthError;
+
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+/// A document store that keeps all documents in memory. The content is lost on restart.
+#[derive(Default)]
+pub struct MemoryDocumentStore {
+    collections: RwLock<HashMap<String, BTreeMap<String, Value>>>,
+}
+
+impl MemoryDocumentStore {
+    pub fn new() -> MemoryDocumentStore {
+        MemoryDocumentStore::default()
+    }
+}
+
+const POISONED: CloudAuthError = CloudAuthError::Generic("Memory store poisoned");
+
+impl DocumentStore for MemoryDocumentStore {
+    fn read_value(&self, collection: &str, id: &str) -> Result<Option<Value>, CloudAuthError> {
+        let collections = self.collections.read().map_err(|_| POISONED)?;
+        Ok(collections.get(collection).and_then(|documents| documents.get(id)).cloned())
+    }
+
+    fn write_value(&self, collection: &str, id: &str, document: Value, merge: bool) -> Result<(), CloudAuthError> {
+        let mut collections = self.collections.write().map_err(|_| POISONED)?;
+        let documents = collections.entry(collection.to_owned()).or_default();
+        match documents.get_mut(id) {
+            Some(existing) if merge => merge_documents(existing, document),
+            _ => { documents.insert(id.to_owned(), document); }
+        }
+        Ok(())
+    }
+
+    fn delete(&self, collection: &str, id: &str) -> Result<(), CloudAuthError> {
+        let mut collections = self.collections.write().map_err(|_| POISONED)?;
+        if let Some(documents) = collections.get_mut(collection) {
+            documents.remove(id);
+        }
+        Ok(())
+    }
+
+    fn query_values(&self, collection: &str, field: &str, operator: QueryOperator, value: Value) -> Result<Vec<(String, Value)>, CloudAuthError> {
+        Ok(self.list_values(collection)?.into_iter()
+            .filter(|(_, document)| document_matches(document, field, operator, &value))
+            .collect())
+    }
+
+    fn list_values(&self, collection: &str) -> Result<Vec<(String, Value)>, CloudAuthError> {
+        let collections = self.collections.read().map_err(|_| POISONED)?;
+        Ok(collections.get(collection)
+            .map(|documents| documents.iter().map(|(id, document)| (id.clone(), document.clone())).collect())
+            .unwrap_or_default())
+    }
+}
+
+/// A token store that keeps all values in memory. Expired values are removed on write.
+#[derive(Default)]
+pub struct MemoryTokenStore {
+    /// Value and expiry (unix timestamp) per key
+    entries: RwLock<HashMap<String, (String, i64)>>,
+}
+
+impl MemoryTokenStore {
+    pub fn new() -> MemoryTokenStore {
+        MemoryTokenStore::default()
+    }
+
+    fn insert(entries: &mut HashMap<String, (String, i64)>, key: &str, value: &str, expires_in: usize) {
+        let now = chrono::Utc::now().timestamp();
+        entries.retain(|_, (_, expiry)| *expiry > now);
+        entries.insert(key.to_owned(), (value.to_owned(), now + expires_in as i64));
+    }
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn get(&self, key: &str) -> Result<Option<String>, CloudAuthError> {
+        let now = chrono::Utc::now().timestamp();
+        let entries = self.entries.read().map_err(|_| POISONED)?;
+        Ok(entries.get(key).filter(|(_, expiry)| *expiry > now).map(|(value, _)| value.clone()))
+    }
+
+    fn set(&self, key: &str, value: &str, expires_in: usize) -> Result<(), CloudAuthError> {
+        let mut entries = self.entries.write().map_err(|_| POISONED)?;
+        Self::insert(&mut entries, key, value, expires_in);
+        Ok(())
+    }
+
+    fn set_if_absent(&self, key: &str, value: &str, expires_in: usize) -> Result<bool, CloudAuthError> {
+        let now = chrono::Utc::now().timestamp();
+        let mut entries = self.entries.write().map_err(|_| POISONED)?;
+        if entries.get(key).map_or(false, |(_, expiry)| *expiry > now) {
+            return Ok(false);
+        }
+        Self::insert(&mut entries, key, value, expires_in);
+        Ok(true)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), CloudAuthError> {
+        let mut entries = self.entries.write().map_err(|_| POISONED)?;
+        entries.remove(key);
+        Ok(())
+    }
+
+    fn take(&self, key: &str) -> Result<Option<String>, CloudAuthError> {
+        let now = chrono::Utc::now().timestamp();
+        let mut entries = self.entries.write().map_err(|_| POISONED)?;
+        Ok(entries.remove(key).filter(|(_, expiry)| *expiry > now).map(|(value, _)| value))
+    }
+
+    fn ttl(&self, key: &str) -> Result<Option<i64>, CloudAuthError> {
+        let now = chrono::Utc::now().timestamp();
+        let entries = self.entries.read().map_err(|_| POISONED)?;
+        Ok(entries.get(key).map(|(_, expiry)| *expiry - now).filter(|remaining| *remaining > 0))
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>, CloudAuthError> {
+        let now = chrono::Utc::now().timestamp();
+        let entries = self.entries.read().map_err(|_| POISONED)?;
+        Ok(entries.iter()
+            .filter(|(key, (_, expiry))| key.starts_with(prefix) && *expiry > now)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+}
+
+#[test]
+fn memory_token_store_test() {
+    let store = MemoryTokenStore::new();
+    assert!(store.set_if_absent("code", "value", 60).unwrap());
+    assert!(!store.set_if_absent("code", "other", 60).unwrap());
+    assert_eq!(store.get("code").unwrap().unwrap(), "value");
+    assert!(store.ttl("code").unwrap().unwrap() > 0);
+    assert_eq!(store.keys("co").unwrap(), vec!["code".to_owned()]);
+    store.delete("code").unwrap();
+    assert!(store.get("code").unwrap().is_none());
+    assert!(store.ttl("code").unwrap().is_none());
+
+    // Expired values are not returned
+    store.set("expired", "value", 0).unwrap();
+    assert!(store.get("expired").unwrap().is_none());
+    assert!(store.take("expired").unwrap().is_none());
+
+    // A value can only be taken once
+    store.set("once", "value", 60).unwrap();
+    assert_eq!(store.take("once").unwrap().unwrap(), "value");
+    assert!(store.take("once").unwrap().is_none());
+    assert!(store.get("once").unwrap().is_none());
+}
+
+#[test]
+fn memory_document_store_test() {
+    let store: &dyn DocumentStore = &MemoryDocumentStore::new();
+    store.write("users", "a", &serde_json::json!({"name": "a", "age": 10})).unwrap();
+    store.merge("users", "a", &serde_json::json!({"age": 11})).unwrap();
+    store.write("users", "b", &serde_json::json!({"name": "b", "age": 20})).unwrap();
+
+    let a: Value = store.read("users", "a").unwrap().unwrap();
+    assert_eq!(a, serde_json::json!({"name": "a", "age": 11}));
+
+    let young: Vec<(String, Value)> = store.query("users", "age", QueryOperator::LessThan, 15).unwrap();
+    assert_eq!(young.len(), 1);
+    assert_eq!(young[0].0, "a");
+
+    store.delete("users", "a").unwrap();
+    assert!(store.read::<Value>("users", "a").unwrap().is_none());
+    assert_eq!(store.list::<Value>("users").unwrap().len(), 1);
+}
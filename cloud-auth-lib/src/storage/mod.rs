@@ -0,0 +1,174 @@
+//! # Storage backends
+//! Services persist documents (refresh tokens, oauth clients, user entries) in a [`DocumentStore`]
+//! and short living values (authorization codes, device codes, deny-list entries) in a [`TokenStore`].
+//!
+//! Firestore and Redis are used in production. The in-memory and file based stores ([`FileDocumentStore`],
+//! [`FileTokenStore`]) allow to run a service without Google credentials, for example in tests.
+//!
+//! Both traits are object safe. Services manage an `Arc<dyn DocumentStore>` and an `Arc<dyn TokenStore>`
+//! as rocket state. Typed access to documents is provided by the methods on `dyn DocumentStore`.
+
+mod file;
+mod firestore_store;
+mod memory;
+mod redis_store;
+
+pub use file::{FileDocumentStore, FileTokenStore};
+pub use firestore_store::FirestoreDocumentStore;
+pub use memory::{MemoryDocumentStore, MemoryTokenStore};
+pub use redis_store::{RedisTokenStore, REDIS_FILE};
+
+use crate::CloudAuthError;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+
+/// The comparison of a document field with a value for [`DocumentStore::query_values`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueryOperator {
+    Equal,
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+}
+
+pub trait DocumentStore: Send + Sync {
+    /// Returns the document with the given id or None if it does not exist
+    fn read_value(&self, collection: &str, id: &str) -> Result<Option<Value>, CloudAuthError>;
+    /// Writes a document. If `merge` is set, fields of an existing document that are not part of the
+    /// given document are kept.
+    fn write_value(&self, collection: &str, id: &str, document: Value, merge: bool) -> Result<(), CloudAuthError>;
+    /// Removes a document. Removing a document that does not exist is not an error.
+    fn delete(&self, collection: &str, id: &str) -> Result<(), CloudAuthError>;
+    /// Returns the ids and documents of the collection with a field that matches the value
+    fn query_values(&self, collection: &str, field: &str, operator: QueryOperator, value: Value) -> Result<Vec<(String, Value)>, CloudAuthError>;
+    /// Returns the ids and documents of the collection
+    fn list_values(&self, collection: &str) -> Result<Vec<(String, Value)>, CloudAuthError>;
+}
+
+/// Allows to keep a handle to a store that is owned by a rocket instance, for example in tests
+impl<S: DocumentStore + ?Sized> DocumentStore for std::sync::Arc<S> {
+    fn read_value(&self, collection: &str, id: &str) -> Result<Option<Value>, CloudAuthError> {
+        (**self).read_value(collection, id)
+    }
+
+    fn write_value(&self, collection: &str, id: &str, document: Value, merge: bool) -> Result<(), CloudAuthError> {
+        (**self).write_value(collection, id, document, merge)
+    }
+
+    fn delete(&self, collection: &str, id: &str) -> Result<(), CloudAuthError> {
+        (**self).delete(collection, id)
+    }
+
+    fn query_values(&self, collection: &str, field: &str, operator: QueryOperator, value: Value) -> Result<Vec<(String, Value)>, CloudAuthError> {
+        (**self).query_values(collection, field, operator, value)
+    }
+
+    fn list_values(&self, collection: &str) -> Result<Vec<(String, Value)>, CloudAuthError> {
+        (**self).list_values(collection)
+    }
+}
+
+impl<'a> dyn DocumentStore + 'a {
+    pub fn read<T: DeserializeOwned>(&self, collection: &str, id: &str) -> Result<Option<T>, CloudAuthError> {
+        match self.read_value(collection, id)? {
+            Some(document) => Ok(Some(serde_json::from_value(document)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// Adds or replaces a document
+    pub fn write<T: Serialize>(&self, collection: &str, id: &str, document: &T) -> Result<(), CloudAuthError> {
+        self.write_value(collection, id, serde_json::to_value(document)?, false)
+    }
+
+    /// Adds a document or updates the given fields of an existing one
+    pub fn merge<T: Serialize>(&self, collection: &str, id: &str, document: &T) -> Result<(), CloudAuthError> {
+        self.write_value(collection, id, serde_json::to_value(document)?, true)
+    }
+
+    pub fn query<T: DeserializeOwned, V: Into<Value>>(&self, collection: &str, field: &str, operator: QueryOperator, value: V) -> Result<Vec<(String, T)>, CloudAuthError> {
+        Self::deserialize_all(self.query_values(collection, field, operator, value.into())?)
+    }
+
+    pub fn list<T: DeserializeOwned>(&self, collection: &str) -> Result<Vec<(String, T)>, CloudAuthError> {
+        Self::deserialize_all(self.list_values(collection)?)
+    }
+
+    fn deserialize_all<T: DeserializeOwned>(documents: Vec<(String, Value)>) -> Result<Vec<(String, T)>, CloudAuthError> {
+        let mut result = Vec::with_capacity(documents.len());
+        for (id, document) in documents {
+            result.push((id, serde_json::from_value(document)?));
+        }
+        Ok(result)
+    }
+}
+
+pub trait TokenStore: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<String>, CloudAuthError>;
+    /// Stores a value that expires after the given amount of seconds
+    fn set(&self, key: &str, value: &str, expires_in: usize) -> Result<(), CloudAuthError>;
+    /// Stores a value only if the key does not exist yet. Returns true if the value has been stored.
+    fn set_if_absent(&self, key: &str, value: &str, expires_in: usize) -> Result<bool, CloudAuthError>;
+    /// Removes a value. Removing a missing key is not an error.
+    fn delete(&self, key: &str) -> Result<(), CloudAuthError>;
+    /// Returns and removes a value in one atomic operation. Of concurrent callers only one receives the value.
+    fn take(&self, key: &str) -> Result<Option<String>, CloudAuthError>;
+    /// The remaining lifetime in seconds or None if the key does not exist
+    fn ttl(&self, key: &str) -> Result<Option<i64>, CloudAuthError>;
+    /// All keys that start with the given prefix
+    fn keys(&self, prefix: &str) -> Result<Vec<String>, CloudAuthError>;
+}
+
+/// Compares two json values. Numbers are compared by value, strings lexicographically.
+/// Values of different types are not comparable.
+fn compare_values(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        (a, b) if a == b => Some(Ordering::Equal),
+        _ => None
+    }
+}
+
+/// Query evaluation for stores without a query engine
+pub(crate) fn document_matches(document: &Value, field: &str, operator: QueryOperator, value: &Value) -> bool {
+    let ordering = match document.get(field).and_then(|field_value| compare_values(field_value, value)) {
+        Some(ordering) => ordering,
+        None => return false
+    };
+    match operator {
+        QueryOperator::Equal => ordering == Ordering::Equal,
+        QueryOperator::LessThan => ordering == Ordering::Less,
+        QueryOperator::LessThanOrEqual => ordering != Ordering::Greater,
+        QueryOperator::GreaterThan => ordering == Ordering::Greater,
+        QueryOperator::GreaterThanOrEqual => ordering != Ordering::Less,
+    }
+}
+
+/// Merges the top level fields of `update` into `document`. Replaces the document if one of both is not an object.
+pub(crate) fn merge_documents(document: &mut Value, update: Value) {
+    match (document, update) {
+        (Value::Object(document), Value::Object(update)) => {
+            for (key, value) in update {
+                document.insert(key, value);
+            }
+        }
+        (document, update) => *document = update
+    }
+}
+
+#[test]
+fn document_matches_test() {
+    let document = serde_json::json!({"uid": "user", "issued_at": 100});
+    assert!(document_matches(&document, "uid", QueryOperator::Equal, &"user".into()));
+    assert!(!document_matches(&document, "uid", QueryOperator::Equal, &"other".into()));
+    assert!(document_matches(&document, "issued_at", QueryOperator::LessThanOrEqual, &100.into()));
+    assert!(document_matches(&document, "issued_at", QueryOperator::GreaterThan, &99.5.into()));
+    assert!(!document_matches(&document, "issued_at", QueryOperator::LessThan, &100.into()));
+    // Missing fields and different types never match
+    assert!(!document_matches(&document, "family_id", QueryOperator::Equal, &"user".into()));
+    assert!(!document_matches(&document, "uid", QueryOperator::LessThan, &100.into()));
+}
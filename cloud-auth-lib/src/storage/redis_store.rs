@@ -0,0 +1,101 @@
+use super::TokenStore;
+use crate::config::ConfigSource;
+use crate::CloudAuthError;
+
+use redis::Commands;
+
+/// The runtime configuration value with the Redis connection url
+pub const REDIS_FILE: &str = "redis.txt";
+
+/// GETDEL for Redis versions before 6.2
+const TAKE_SCRIPT: &str = r"
+local value = redis.call('GET', KEYS[1])
+if value then redis.call('DEL', KEYS[1]) end
+return value
+";
+
+/// Escapes the glob characters of a key prefix for a MATCH pattern
+fn match_pattern(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        match c {
+            '*' | '?' | '[' | ']' | '\\' => pattern.push('\\'),
+            _ => {}
+        }
+        pattern.push(c);
+    }
+    pattern.push('*');
+    pattern
+}
+
+/// A token store backed by Redis. A connection is established per operation.
+pub struct RedisTokenStore {
+    client: redis::Client,
+}
+
+impl RedisTokenStore {
+    pub fn new(client: redis::Client) -> RedisTokenStore {
+        RedisTokenStore { client }
+    }
+
+    /// Connects to the Redis instance of [`REDIS_FILE`]
+    pub fn from_config(config_source: &ConfigSource) -> Result<RedisTokenStore, CloudAuthError> {
+        Ok(RedisTokenStore::new(redis::Client::open(config_source.get(REDIS_FILE)?.trim())?))
+    }
+}
+
+impl TokenStore for RedisTokenStore {
+    fn get(&self, key: &str) -> Result<Option<String>, CloudAuthError> {
+        let mut connection = self.client.get_connection()?;
+        Ok(connection.get(key)?)
+    }
+
+    fn set(&self, key: &str, value: &str, expires_in: usize) -> Result<(), CloudAuthError> {
+        let mut connection = self.client.get_connection()?;
+        connection.set_ex(key, value, expires_in)?;
+        Ok(())
+    }
+
+    fn set_if_absent(&self, key: &str, value: &str, expires_in: usize) -> Result<bool, CloudAuthError> {
+        let mut connection = self.client.get_connection()?;
+        let stored: Option<String> = redis::cmd("SET").arg(key).arg(value)
+            .arg("EX").arg(expires_in).arg("NX")
+            .query(&mut connection)?;
+        Ok(stored.is_some())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), CloudAuthError> {
+        let mut connection = self.client.get_connection()?;
+        connection.del(key)?;
+        Ok(())
+    }
+
+    fn take(&self, key: &str) -> Result<Option<String>, CloudAuthError> {
+        let mut connection = self.client.get_connection()?;
+        Ok(redis::Script::new(TAKE_SCRIPT).key(key).invoke(&mut connection)?)
+    }
+
+    fn ttl(&self, key: &str) -> Result<Option<i64>, CloudAuthError> {
+        let mut connection = self.client.get_connection()?;
+        let ttl: i64 = connection.ttl(key)?;
+        // -2: The key does not exist. -1: The key has no expiry.
+        Ok(match ttl {
+            -2 => None,
+            -1 => Some(0),
+            ttl => Some(ttl)
+        })
+    }
+
+    /// Iterates with SCAN instead of KEYS, which would block the server for large databases
+    fn keys(&self, prefix: &str) -> Result<Vec<String>, CloudAuthError> {
+        let mut connection = self.client.get_connection()?;
+        let keys: Vec<String> = connection.scan_match(match_pattern(prefix))?.collect();
+        Ok(keys)
+    }
+}
+
+#[test]
+fn match_pattern_test() {
+    assert_eq!(match_pattern("authcode."), "authcode.*");
+    assert_eq!(match_pattern("a*b?[c]\\"), "a\\*b\\?\\[c\\]\\\\*");
+}
@@ -1,19 +1,13 @@
 use ring::aead::CHACHA20_POLY1305;
-use ring::aead::{Nonce, OpeningKey, SealingKey, UnboundKey};
-use ring::error::Unspecified;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
 
 use crate::{jwt, CloudAuthError};
 use biscuit::jws::Secret;
 
-struct NullNonceSequence();
-
-impl ring::aead::NonceSequence for NullNonceSequence {
-    fn advance(&mut self) -> Result<Nonce, Unspecified> {
-        Ok(Nonce::assume_unique_for_key([
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        ]))
-    }
-}
+/// The version byte of the unsigned jwt envelope: VERSION | NONCE (12 bytes) | CIPHERTEXT | TAG.
+/// Legacy envelopes have no version and nonce and have been encrypted with an all-zero nonce.
+const UNSIGNED_ENVELOPE_V1: u8 = 1;
 
 pub fn hash_of_token(token: &[u8]) -> String {
     use sha2::{Digest, Sha256};
@@ -24,96 +18,130 @@ pub fn hash_of_token(token: &[u8]) -> String {
     base64::encode_config(result.as_slice(), config)
 }
 
-/// Decrypt an unsigned jwt. The secret is expected to be a 32 byte long key.
-pub fn decrypt_unsigned_jwt_token(secret: &[u8], token_base64: &[u8]) -> Result<jwt::AuthClaimsJWT, CloudAuthError> {
+/// Checks a PKCE (RFC 7636) code verifier against a "S256" code challenge.
+///
+/// The challenge is BASE64URL(SHA256(code_verifier)) which is exactly what [`hash_of_token`] computes.
+/// A verifier must be between 43 and 128 characters long.
+pub fn verify_code_challenge(code_verifier: &str, code_challenge: &str) -> bool {
+    if code_verifier.len() < 43 || code_verifier.len() > 128 {
+        return false;
+    }
+    let computed = hash_of_token(code_verifier.as_bytes());
+    ring::constant_time::verify_slices_are_equal(computed.as_bytes(), code_challenge.as_bytes()).is_ok()
+}
+
+/// Consonants only, to avoid ambiguous characters and accidental words (RFC 8628, 6.1)
+const USER_CODE_CHARSET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+/// Generates a human typable device flow user code like "WDJB-MJHT" (RFC 8628).
+pub fn generate_user_code() -> Result<String, CloudAuthError> {
+    let rand = SystemRandom::new();
+    let mut user_code = String::with_capacity(9);
+    let mut buffer = [0u8; 16];
+    while user_code.len() < 9 {
+        rand.fill(&mut buffer)?;
+        // Bytes above the largest multiple of the charset length are skipped to avoid a bias
+        let limit = (256 / USER_CODE_CHARSET.len() * USER_CODE_CHARSET.len()) as u8;
+        for byte in buffer.iter().filter(|b| **b < limit) {
+            if user_code.len() == 4 {
+                user_code.push('-');
+            }
+            if user_code.len() == 9 {
+                break;
+            }
+            user_code.push(USER_CODE_CHARSET[*byte as usize % USER_CODE_CHARSET.len()] as char);
+        }
+    }
+    Ok(user_code)
+}
+
+/// Normalizes a user entered device flow user code: Upper case, without dashes and whitespace.
+pub fn normalize_user_code(user_code: &str) -> String {
+    user_code.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_uppercase()).collect()
+}
+
+/// The additional authenticated data of an envelope: The version and the client id
+fn envelope_aad(version: u8, client_id: &str) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(1 + client_id.len());
+    aad.push(version);
+    aad.extend_from_slice(client_id.as_bytes());
+    aad
+}
+
+/// Opens a versioned envelope. Returns None if it is not a valid envelope for the given client.
+fn open_envelope(key: &LessSafeKey, envelope: &mut [u8], client_id: &str) -> Option<Vec<u8>> {
+    if envelope.len() < 1 + NONCE_LEN || envelope[0] != UNSIGNED_ENVELOPE_V1 {
+        return None;
+    }
+    let (header, ciphertext) = envelope.split_at_mut(1 + NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(&header[1..]).ok()?;
+    key.open_in_place(nonce, Aad::from(envelope_aad(UNSIGNED_ENVELOPE_V1, client_id)), ciphertext)
+        .ok()
+        .map(|plaintext| plaintext.to_vec())
+}
+
+/// Decrypt an unsigned jwt that has been issued for the given client. The secret is expected to be a 32 byte long key.
+///
+/// Legacy envelopes (all-zero nonce, no client binding) are only accepted if `accept_legacy` is set.
+/// They can be forged by anyone who has seen two of them, so this is meant for a short transition window.
+pub fn decrypt_unsigned_jwt_token(secret: &[u8], token_base64: &[u8], client_id: &str, accept_legacy: bool) -> Result<jwt::AuthClaimsJWT, CloudAuthError> {
     use miniz_oxide::inflate::decompress_to_vec;
-    use ring::aead::BoundKey;
     use biscuit::jwa::SignatureAlgorithm;
 
-    let nonce_seq = NullNonceSequence();
-
-    // "token_base64" is := BASE64(AES_ENCRYPTED(COMPRESSED(BINARY_SERIALIZED(jwt))))
+    // "token_base64" is := BASE64(ENVELOPE(COMPRESSED(SERIALIZED(jwt))))
     // Therefore it 1.) need to be base64 decoded into bytes
     let config = base64::Config::new(base64::CharacterSet::UrlSafe, false);
     let mut buffer = base64::decode_config(&token_base64, config)?;
     // 2. decrypted
-    let key = UnboundKey::new(&CHACHA20_POLY1305, secret)?;
-    let mut key = OpeningKey::new(key, nonce_seq);
-    let buffer = key.open_within(ring::aead::Aad::empty(), &mut buffer, 0..)?;
+    let key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, secret)?);
+    // The content of a buffer that failed to open is unspecified. The legacy attempt needs the original.
+    let buffer = match open_envelope(&key, &mut buffer.clone(), client_id) {
+        Some(plaintext) => plaintext,
+        None if accept_legacy => key.open_in_place(Nonce::assume_unique_for_key([0; NONCE_LEN]), Aad::empty(), &mut buffer)?.to_vec(),
+        None => return Err(CloudAuthError::Generic("Invalid unsigned token"))
+    };
     // 3. decompressed
-    let buffer = &decompress_to_vec(buffer)?[..];
+    let buffer = &decompress_to_vec(&buffer)?[..];
     // 4. deserialize
-//    Ok(err_wrap(bincode::deserialize(buffer))?)
-
     let decoded: jwt::AuthClaimsJWT = serde_json::from_slice(buffer)?;
-    let decoded = decoded.into_decoded(&Secret::None, SignatureAlgorithm::None).unwrap();
+    let decoded = decoded.into_decoded(&Secret::None, SignatureAlgorithm::None)?;
+    // Legacy envelopes are not bound to the client
+    if decoded.payload()?.private.client_id.as_ref().map(|id| id.as_str()) != Some(client_id) {
+        return Err(CloudAuthError::Generic("Invalid unsigned token"));
+    }
     Ok(decoded)
 }
 
-/// Serialize, compress, encrypt and base64 a jwt
+/// Serialize, compress, encrypt and base64 a jwt. The envelope is bound to the given client id.
 ///
-/// The secret is expected to be a 32 byte long key.
-pub fn encrypt_unsigned_jwt_token(secret: &[u8], mut jwt: jwt::AuthClaimsJWT) -> Result<String, CloudAuthError> {
+/// The secret is expected to be a 32 byte long key. A random nonce is used for each token.
+pub fn encrypt_unsigned_jwt_token(secret: &[u8], client_id: &str, mut jwt: jwt::AuthClaimsJWT) -> Result<String, CloudAuthError> {
     use miniz_oxide::deflate::compress_to_vec;
-    use ring::aead::BoundKey;
     use biscuit::jwa::SignatureAlgorithm;
 
-    let nonce_seq = NullNonceSequence();
-
     // The jwt must be encoded first and should not carry a signature (Secret::None)
     jwt.header_mut().unwrap().registered.algorithm = SignatureAlgorithm::None;
     let jwt = jwt.encode(&Secret::None)?;
 
-    // We need to convert jwt so that result := BASE64(AES_ENCRYPTED(COMPRESSED(BINARY_SERIALIZED(jwt))))
-    // 1. Binary serialize
-    //let mut buffer: Vec<u8> = err_wrap(bincode::serialize(&jwt))?;
+    // We need to convert jwt so that result := BASE64(ENVELOPE(COMPRESSED(SERIALIZED(jwt))))
+    // 1. Serialize
     let buffer: Vec<u8> = serde_json::to_vec(&jwt)?;
     // 2. Compress
     let mut buffer = compress_to_vec(&buffer, 9);
     // 3. encrypt
-    let key = UnboundKey::new(&CHACHA20_POLY1305, secret)?;
-    let mut key = SealingKey::new(key, nonce_seq);
-    key.seal_in_place_append_tag(ring::aead::Aad::empty(), &mut buffer)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce)?;
+    let key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, secret)?);
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(envelope_aad(UNSIGNED_ENVELOPE_V1, client_id)), &mut buffer)?;
+    let mut envelope = Vec::with_capacity(1 + NONCE_LEN + buffer.len());
+    envelope.push(UNSIGNED_ENVELOPE_V1);
+    envelope.extend_from_slice(&nonce);
+    envelope.extend_from_slice(&buffer);
 
     // 4. to base64 string
     let config = base64::Config::new(base64::CharacterSet::UrlSafe, false);
-    Ok(base64::encode_config(&buffer, config))
+    Ok(base64::encode_config(&envelope, config))
 }
-//
-//#[test]
-//fn test_enc_dec() {
-//    use cloud_vault::credentials::Credentials;
-//    use ring::aead::BoundKey;
-//
-//    let credentials: Credentials = serde_json::from_value(serde_json::json!({
-//        "project_id": "project_id",
-//        "private_key_id": "key_id",
-//        "private_key": "",
-//        "client_email": "some@email.com",
-//        "client_id": "client_id"
-//    })).unwrap();
-//
-//    let encoded: Vec<u8> = err_wrap(bincode::serialize(&credentials)).unwrap();
-//    let mut buffer = encoded.clone();
-//    let nonce_seq = NullNonceSequence();
-//    let key = UnboundKey::new(&CHACHA20_POLY1305, &SECRET[0..32]).unwrap();
-//    let mut key = SealingKey::new(key, nonce_seq);
-//    key.seal_in_place_append_tag(ring::aead::Aad::empty(), &mut buffer).unwrap();
-//    let nonce_seq = NullNonceSequence();
-//    let key = UnboundKey::new(&CHACHA20_POLY1305, &SECRET[0..32]).unwrap();
-//    let mut key = OpeningKey::new(key, nonce_seq);
-//    let enc_slice = key.open_within(ring::aead::Aad::empty(), &mut buffer, 0..).unwrap();
-//    assert_eq!(enc_slice.len(), encoded.len());
-//    assert_eq!(enc_slice, &encoded[..]);
-//    let c: Credentials = bincode::deserialize(&enc_slice[..]).unwrap();
-//    assert_eq!(c.project_id, "project_id");
-//}
-
-// Test bincode
-//    let test_bincode_jwt = jwt.encode(&Secret::None).unwrap();
-//    let test_bincode: Vec<u8> = bincode::serialize(&test_bincode_jwt).unwrap();
-//    let _c: jwt::AuthClaimsJWT = bincode::deserialize(&test_bincode[..]).unwrap();
-//assert_eq!(c.project_id, "project_id");
 
 #[test]
 fn encrypt_decrypt_test() {
@@ -137,17 +165,58 @@ fn encrypt_decrypt_test() {
     ).unwrap();
 
     let mut secret : [u8;32] = [0;32];
-    use ring::rand::{SystemRandom,SecureRandom};
     let rand = SystemRandom::new();
     rand.fill(&mut secret).unwrap();
 
-    let encoded = encrypt_unsigned_jwt_token(&secret, jwt).unwrap();
+    let encoded = encrypt_unsigned_jwt_token(&secret, "client_id", jwt.clone()).unwrap();
+    // Random nonces: The same token never results in the same envelope
+    assert_ne!(encoded, encrypt_unsigned_jwt_token(&secret, "client_id", jwt.clone()).unwrap());
 
-    let decoded = decrypt_unsigned_jwt_token(&secret, &encoded.as_bytes()).unwrap();
+    let decoded = decrypt_unsigned_jwt_token(&secret, &encoded.as_bytes(), "client_id", false).unwrap();
     let payload = decoded.payload().unwrap();
 
     assert_eq!(payload.registered.subject.as_ref().unwrap().to_string(), "some@email.com");
     assert_eq!(payload.private.scope.iter().next().unwrap(), "demo ");
     assert_eq!(payload.private.client_id.as_ref().unwrap().to_string(), "client_id");
     assert_eq!(payload.private.uid.as_ref().unwrap().to_string(), "user_id");
-}
\ No newline at end of file
+
+    // Bound to the client
+    assert!(decrypt_unsigned_jwt_token(&secret, &encoded.as_bytes(), "other_client", true).is_err());
+
+    // A legacy envelope: All-zero nonce, no version and client binding
+    use biscuit::jwa::SignatureAlgorithm;
+    let mut legacy_jwt = jwt;
+    legacy_jwt.header_mut().unwrap().registered.algorithm = SignatureAlgorithm::None;
+    let legacy_jwt = legacy_jwt.encode(&Secret::None).unwrap();
+    let mut buffer = miniz_oxide::deflate::compress_to_vec(&serde_json::to_vec(&legacy_jwt).unwrap(), 9);
+    let key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &secret).unwrap());
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key([0; NONCE_LEN]), Aad::empty(), &mut buffer).unwrap();
+    let legacy = base64::encode_config(&buffer, base64::Config::new(base64::CharacterSet::UrlSafe, false));
+
+    assert!(decrypt_unsigned_jwt_token(&secret, legacy.as_bytes(), "client_id", false).is_err());
+    let decoded = decrypt_unsigned_jwt_token(&secret, legacy.as_bytes(), "client_id", true).unwrap();
+    assert_eq!(decoded.payload().unwrap().private.uid.as_ref().unwrap().to_string(), "user_id");
+    assert!(decrypt_unsigned_jwt_token(&secret, legacy.as_bytes(), "other_client", true).is_err());
+}
+
+#[test]
+fn code_challenge_s256_test() {
+    // Example from RFC 7636, Appendix B
+    let code_verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    let code_challenge = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    assert!(verify_code_challenge(code_verifier, code_challenge));
+    assert!(!verify_code_challenge(code_verifier, "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cN"));
+    assert!(!verify_code_challenge("too_short", &hash_of_token(b"too_short")));
+}
+
+#[test]
+fn user_code_test() {
+    let user_code = generate_user_code().unwrap();
+    assert_eq!(user_code.len(), 9);
+    assert_eq!(&user_code[4..5], "-");
+    assert!(user_code.bytes().filter(|b| *b != b'-').all(|b| USER_CODE_CHARSET.contains(&b)));
+
+    assert_eq!(normalize_user_code(" wdjb-MJHT "), "WDJBMJHT");
+    assert_eq!(normalize_user_code(&user_code), user_code.replace("-", ""));
+}
@@ -6,47 +6,113 @@ pub(crate) mod routes;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use cloud_auth_lib::{oauth_clients, guard_rate_limiter, fairing_cors, catch_all, Credentials, error_routes};
-use std::sync::Mutex;
+use cloud_auth_lib::{oauth_clients, guard_rate_limiter, fairing_cors, catch_all, deny_list::TokenDenyList, authorization_codes::AuthorizationCodeStore, error_routes};
+use cloud_auth_lib::audit::AuditLog;
+use cloud_auth_lib::config::ConfigSource;
+use cloud_auth_lib::fairing_cors::{CorsPolicy, OHX_ORIGINS};
+use cloud_auth_lib::guard_ip_addr::TrustedProxies;
+use cloud_auth_lib::identity::{IdentityProvider, FirebaseIdentityProvider};
+use cloud_auth_lib::rate_limit::{RateLimits, RedisRateLimitBackend};
+use cloud_auth_lib::storage::{DocumentStore, TokenStore, FirestoreDocumentStore, RedisTokenStore};
+use std::sync::Arc;
 use rocket::{Config,catchers,routes};
+use rocket::http::Method;
 use rocket::config::Environment;
 use std::env;
 use firestore_db_and_auth::{credentials::Credentials as DBCredentials, sessions::service_account::Session as SASession};
 
 use routes::*;
 
-// Embed the allowed oauth clients
+// Embed the initial oauth clients. Used to seed an empty database if "oauth_clients.json" is not configured.
 const OAUTH_CLIENTS: &'static str = include_str!("../../data/oauth_clients.json");
 
-// Include all kind of keys from the secret directory
-const KEY_GOOGLE_TRAVIS: &'static str = include_str!("../../secrets/travisci-deployer@openhabx.iam.gserviceaccount.com.key");
-const GOOGLE_SERVICE_ACCOUNT_ST: &'static str = include_str!("../../secrets/securetoken@system.gserviceaccount.com.json");
-const GOOGLE_SERVICE_ACCOUNT_TRAVIS: &'static str = include_str!("../../secrets/travisci-deployer@openhabx.iam.gserviceaccount.com.json");
-const GOOGLE_SERVICE_ACCOUNT_OHX: &'static str = include_str!("../../secrets/openhabx-device@openhabx.iam.gserviceaccount.com.json");
-const OHX_ADMIN_ACCOUNT: &'static str = include_str!("../../secrets/ohx_oauth_key.key");
-const OHX_AUTH_JWKS: &'static str = include_str!("../../secrets/ohx_oauth_key.json");
-const FIREBASE_CREDENTIALS: &'static str = include_str!("../../secrets/openhabx-device@openhabx.iam.gserviceaccount.com.key");
-const REDIS_CREDENTIALS: &'static str = include_str!("../../secrets/redis.txt");
+// The names of the keys in the runtime configuration (secrets directory or environment)
+pub const GOOGLE_SERVICE_ACCOUNT_ST: &'static str = "securetoken@system.gserviceaccount.com.json";
+pub const GOOGLE_SERVICE_ACCOUNT_OHX: &'static str = "openhabx-device@openhabx.iam.gserviceaccount.com.json";
+pub const OHX_ADMIN_ACCOUNT: &'static str = "ohx_oauth_key.key";
+pub const OHX_AUTH_JWKS: &'static str = "ohx_oauth_key.json";
+pub const FIREBASE_CREDENTIALS: &'static str = "openhabx-device@openhabx.iam.gserviceaccount.com.key";
+pub const REDIS_CREDENTIALS: &'static str = "redis.txt";
+pub const UNSIGNED_TOKEN_KEY: &'static str = "random_seed.bin";
+/// Optional unix timestamp until unsigned jwts of the previous envelope format are accepted
+pub const UNSIGNED_LEGACY_UNTIL: &'static str = "unsigned_legacy_until.txt";
+pub const OAUTH_CLIENTS_FILE: &'static str = "oauth_clients.json";
+
+fn firebase_credentials(config_source: &ConfigSource) -> Result<DBCredentials, failure::Error> {
+    Ok(DBCredentials::new(&config_source.get(FIREBASE_CREDENTIALS)?,
+                          &[&config_source.get(GOOGLE_SERVICE_ACCOUNT_OHX)?, &config_source.get(GOOGLE_SERVICE_ACCOUNT_ST)?])?)
+}
 
-pub fn create_rocket(rate_limit: u32) -> Result<rocket::Rocket, failure::Error> {
-    let oauth_clients = oauth_clients::new(OAUTH_CLIENTS)?;
+/// The production stores: Firestore for documents and Redis for short living tokens
+pub fn google_stores(config_source: &ConfigSource) -> Result<(Arc<dyn DocumentStore>, Arc<dyn TokenStore>), failure::Error> {
+    let documents = FirestoreDocumentStore::new(SASession::new(firebase_credentials(config_source)?)?);
+    let tokens = RedisTokenStore::new(redis::Client::open(config_source.get(REDIS_CREDENTIALS)?.trim())?);
+    Ok((Arc::new(documents), Arc::new(tokens)))
+}
 
-    // Rate limit
-    let lim = guard_rate_limiter::RateLimiterMutex::new(rate_limit);
+/// The production identity provider: Firebase Authentication
+pub fn google_identity_provider(config_source: &ConfigSource) -> Result<Arc<dyn IdentityProvider>, failure::Error> {
+    Ok(Arc::new(FirebaseIdentityProvider::new(firebase_credentials(config_source)?)))
+}
 
-    let (google_credentials, _g_access_token, _g_scopes) =
-        Credentials::load_and_check(KEY_GOOGLE_TRAVIS, &[GOOGLE_SERVICE_ACCOUNT_ST, GOOGLE_SERVICE_ACCOUNT_TRAVIS], None::<&[&str]>)?;
+/// Rate limits are shared across instances via Redis. Allows `rate` requests per second and client ip,
+/// if the quotas are not configured ([`cloud_auth_lib::rate_limit::RATE_LIMITS_FILE`]).
+pub fn google_rate_limiter(config_source: &ConfigSource, rate: u32) -> Result<guard_rate_limiter::RateLimiterState, failure::Error> {
+    let backend = RedisRateLimitBackend::new(redis::Client::open(config_source.get(REDIS_CREDENTIALS)?.trim())?);
+    Ok(guard_rate_limiter::RateLimiterState::with_backend(Arc::new(backend), RateLimits::from_config(config_source, rate)?))
+}
 
+/// Creates the rocket instance. Documents (refresh tokens, oauth clients) are persisted in the given
+/// document store, authorization codes, device codes and revoked token ids in the given token store.
+/// User sessions and profiles are resolved by the given identity provider.
+///
+/// Credentials and keys are read from the given runtime configuration and validated.
+pub fn create_rocket(rate_limiter: guard_rate_limiter::RateLimiterState, config_source: &ConfigSource, documents: Arc<dyn DocumentStore>,
+                     tokens: Arc<dyn TokenStore>, identities: Arc<dyn IdentityProvider>) -> Result<rocket::Rocket, failure::Error> {
+    let initial_clients = config_source.optional(OAUTH_CLIENTS_FILE)?;
+    let oauth_clients = oauth_clients::OAuthClientStore::new(oauth_clients::new(initial_clients.as_ref().map_or(OAUTH_CLIENTS, |f| f.as_str()))?);
+
+    // The login and account pages call the api from another origin
+    let cors_policy = CorsPolicy::new(OHX_ORIGINS)
+        .with_route("/sessions", &[Method::Get, Method::Delete], &["authorization"])
+        .with_route("/sessions/<session_id>", &[Method::Delete], &["authorization"])
+        .with_route("/consents", &[Method::Get], &["authorization"])
+        .with_route("/consents/<client_id>", &[Method::Delete], &["authorization"])
+        .with_route("/audit_events", &[Method::Get], &["authorization"])
+        .with_route("/authorize", &[Method::Post], &["authorization", "content-type"])
+        .with_route("/clients/<client_id>", &[Method::Put, Method::Delete], &["authorization", "content-type"])
+        .with_max_age(3600)
+        .with_origins_from_config(config_source)?;
+    let trusted_proxies = TrustedProxies::from_config(config_source)?;
+
+    // Only tokens of this service are accepted. Service accounts and cron jobs use the client credentials grant.
     let (openhabx_credentials, _ohx_access_token, _ohx_scopes) =
-        Credentials::load_and_check(OHX_ADMIN_ACCOUNT, &[OHX_AUTH_JWKS], None::<&[&str]>)?;
-
-    let credentials_list = vec![google_credentials, openhabx_credentials];
-
-    let redis = redis::Client::open(REDIS_CREDENTIALS)?;
-
-    let firebase_credentials = DBCredentials::new(FIREBASE_CREDENTIALS, &[GOOGLE_SERVICE_ACCOUNT_OHX, GOOGLE_SERVICE_ACCOUNT_ST, ])?;
+        config_source.credentials(OHX_ADMIN_ACCOUNT, &[OHX_AUTH_JWKS], None::<&[&str]>)?;
+
+    let credentials_list = vec![openhabx_credentials];
+
+    let deny_list = TokenDenyList::new(tokens.clone());
+    let authorization_codes = AuthorizationCodeStore::new(tokens.clone());
+    let audit_log = AuditLog::from_config(config_source, "cloud-auth", Some(documents.clone()))?;
+    let refresh_token_policy = RefreshTokenPolicy::from_config(config_source)?;
+
+    let mut unsigned_token_key = UnsignedTokenKey::new(config_source.bytes(UNSIGNED_TOKEN_KEY)?);
+    if unsigned_token_key.key.len() != 32 {
+        return Err(failure::format_err!("{} must be a 32 byte key", UNSIGNED_TOKEN_KEY));
+    }
+    if let Some(legacy_until) = config_source.optional(UNSIGNED_LEGACY_UNTIL)? {
+        let legacy_until = legacy_until.trim().parse::<i64>()
+            .map_err(|_| failure::format_err!("{} must be a unix timestamp", UNSIGNED_LEGACY_UNTIL))?;
+        if legacy_until > chrono::Utc::now().timestamp() {
+            unsigned_token_key = unsigned_token_key.with_legacy_accepted_until(legacy_until);
+        } else {
+            warn!("{} has passed. Remove it from the runtime configuration", UNSIGNED_LEGACY_UNTIL);
+        }
+    }
 
-    let firebase = Mutex::new(SASession::new(firebase_credentials.clone())?);
+    if let Err(e) = oauth_clients.seed(&*documents) {
+        warn!("Could not read the registered oauth clients. Using the compiled-in clients: {:?}", e);
+    }
 
     let config = Config::build(Environment::Development)
         .port(
@@ -61,11 +127,6 @@ pub fn create_rocket(rate_limit: u32) -> Result<rocket::Rocket, failure::Error>
     #[cfg(debug_assertions)]
         {
             info!("Listening on http://localhost:{}", config.port);
-            info!(
-                "Google 1h access code for scopes: {:?}\n\t{}",
-                _g_scopes.claims.scope,
-                &_g_access_token
-            );
             info!(
                 "OHX 1h access code for scopes: {:?}\n\t{}",
                 _ohx_scopes.claims.scope,
@@ -75,12 +136,19 @@ pub fn create_rocket(rate_limit: u32) -> Result<rocket::Rocket, failure::Error>
 
     Ok(rocket::custom(config)
         .manage(credentials_list)
-        .manage(lim)
-        .manage(firebase)
-        .manage(firebase_credentials)
-        .manage(redis)
+        .manage(rate_limiter)
+        .manage(documents)
+        .manage(tokens)
+        .manage(identities)
+        .manage(deny_list)
+        .manage(authorization_codes)
+        .manage(audit_log)
+        .manage(refresh_token_policy)
         .manage(oauth_clients)
-        .attach(fairing_cors::CorsFairing)
+        .manage(unsigned_token_key)
+        .manage(trusted_proxies)
+        .attach(fairing_cors::CorsFairing::new(cors_policy))
+        .attach(guard_rate_limiter::RateLimitHeaders)
         .register(catchers![
             error_routes::not_found,
             error_routes::access_denied,
@@ -94,11 +162,29 @@ pub fn create_rocket(rate_limit: u32) -> Result<rocket::Rocket, failure::Error>
                 check_for_users,
                 check_for_users_unauthorized,
                 authorize,
+                device_authorization,
+                device_user_code,
                 list_intermediate_tokens,
                 user_info,
                 grant_scopes,
                 grant_scopes_unauthorized,
                 token,
+                revoke,
+                list_sessions,
+                list_sessions_unauthorized,
+                revoke_session,
+                revoke_all_sessions,
+                list_consents,
+                list_consents_unauthorized,
+                revoke_consent,
+                list_audit_events,
+                list_audit_events_unauthorized,
+                introspect,
+                register_client,
+                register_client_unauthorized,
+                list_clients,
+                update_client,
+                delete_client,
                 revoke_by_oauth,
                 pubkey_jwk,
                 openid_configuration
@@ -20,6 +20,10 @@ pub fn main() -> Result<(), failure::Error> {
             std::process::exit(1);
         }
     });
-    cloud_auth::create_rocket(5u32)?.launch();
+    let config_source = cloud_auth_lib::config::ConfigSource::from_env();
+    let (documents, tokens) = cloud_auth::google_stores(&config_source)?;
+    let rate_limiter = cloud_auth::google_rate_limiter(&config_source, 5u32)?;
+    let identities = cloud_auth::google_identity_provider(&config_source)?;
+    cloud_auth::create_rocket(rate_limiter, &config_source, documents, tokens, identities)?.launch();
     Ok(())
 }
@@ -1,46 +1,81 @@
-// External, controlled libraries
-
-use firestore_db_and_auth::{
-    rocket::FirestoreAuthSessionGuard, sessions::service_account::Session as SASession, documents,
-};
-
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
 // External libraries
-use redis::Commands;
 use rocket::http::RawStr;
-use rocket::response::content;
-use rocket::{get, post};
+use rocket::response::{content, status};
+use rocket::{get, post, put, delete};
 use rocket_contrib::json::Json;
 use biscuit::{TemporalOptions, Validation};
-use biscuit::jwa::SignatureAlgorithm;
+use serde::Deserialize;
 
 // std
-use std::collections::{HashMap, HashSet};
-use std::ops::Deref;
-use std::sync::Mutex;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
 use chrono::Duration;
 
 use crate::responder_type::MyResponder;
 use cloud_auth_lib::{
-    guard_rate_limiter::RateLimiter,
+    audit::AuditEvent,
+    guard_audit::Auditor,
+    guard_client_basic_auth::ClientBasicAuth,
+    guard_rate_limiter::{IdentityRateLimiter, RateLimiter},
+    guard_ip_addr::ClientRealAddr,
     guard_oauth_jwt_access,
+    guard_user_session::UserSessionGuard,
+    identity::IdentityProvider,
+    deny_list::TokenDenyList,
+    authorization_codes::{AuthorizationCodeStore, AuthorizationGrant},
+    storage::{DocumentStore, QueryOperator, TokenStore},
     jwt,
+    config::ConfigSource,
     Credentials,
-    oauth_clients::OAuthClients,
-    token::{decrypt_unsigned_jwt_token, encrypt_unsigned_jwt_token, hash_of_token},
+    CloudAuthError,
+    oauth_clients::{OAuthClient, OAuthClientStore},
+    token::{decrypt_unsigned_jwt_token, encrypt_unsigned_jwt_token, generate_user_code, hash_of_token, normalize_user_code, verify_code_challenge},
     dto::{
-        oauth::{GrantRequest, SCOPE_OFFLINE_ACCESS, TokenRequest, OAuthTokenResponse, GenerateTokenRequest, RedirectOrResponseAuthorize, AuthPageRedirectUri, DeviceFlowResponse},
+        oauth::{GrantRequest, SCOPE_ADMIN, SCOPE_OFFLINE_ACCESS, SCOPE_OPENID, ClientRegistrationDTO, TokenRequest, RevokeRequest, IntrospectRequest, IntrospectionResponse, OAuthTokenResponse, TokenDTO, GenerateCodeDTO, GenerateTokenRequest, DeviceAuthorizationRequest, RedirectOrResponseAuthorize, AuthPageRedirectUri, DeviceFlowResponse, SessionDTO, ConsentDTO},
         db
     },
 };
 
-const CREDENTIALS_GOOGLE_SERVICE_ACCOUNT_INDEX: usize = 0;
-const CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX: usize = 1;
+const CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX: usize = 0;
+/// The maximum amount of audit events returned to a user
+const AUDIT_EVENTS_LIMIT: usize = 100;
+
+/// The 32 byte key that encrypts the unsigned jwt of an authorization request ("unsigned" of /authorize)
+pub struct UnsignedTokenKey {
+    pub key: Vec<u8>,
+    /// Fixed unix timestamp until legacy envelopes are accepted. Independent of restarts.
+    /// No legacy envelope is accepted once it has passed, or if it is 0.
+    pub legacy_accepted_until: i64,
+}
+
+impl UnsignedTokenKey {
+    /// A key that does not accept legacy envelopes
+    pub fn new(key: Vec<u8>) -> UnsignedTokenKey {
+        UnsignedTokenKey { key, legacy_accepted_until: 0 }
+    }
+
+    /// Accepts legacy envelopes until the given unix timestamp. Set it to the time of the update plus the validity
+    /// of an unsigned jwt, so that authorizations in flight during the update succeed.
+    pub fn with_legacy_accepted_until(mut self, timestamp: i64) -> UnsignedTokenKey {
+        self.legacy_accepted_until = timestamp;
+        self
+    }
+
+    fn accepts_legacy(&self) -> bool {
+        chrono::Utc::now().timestamp() < self.legacy_accepted_until
+    }
+}
+
+/// The credentials that sign the tokens of this service
+fn ohx_credentials(credentials_list: &[Credentials]) -> Result<&Credentials, MyResponder> {
+    credentials_list
+        .get(CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX)
+        .ok_or(MyResponder::internal_error("Signing credentials not available"))
+}
 
-const SECRET: &[u8] = include_bytes!("../../secrets/random_seed.bin");
-const OHX_AUTH_JWKS: &'static str = include_str!("../../secrets/ohx_oauth_key.json");
 const OPENID_CONFIG: &'static str = include_str!("../../data/openid-configuration.json");
 
 /// Empty default route
@@ -49,14 +84,22 @@ pub fn index() -> &'static str {
     ""
 }
 
+/// Publishes the active and all not yet retired previous signing keys
 #[get("/.well-known/jwks.json")]
-pub fn pubkey_jwk() -> &'static str {
-    OHX_AUTH_JWKS
+pub fn pubkey_jwk(credentials_list: rocket::State<Vec<Credentials>>) -> Result<content::Json<String>, MyResponder> {
+    let credentials = ohx_credentials(&credentials_list)?;
+    Ok(content::Json(credentials.public_jwks()?.to_string()))
 }
 
+/// The discovery document. The issuer is the one of the issued tokens,
+/// ID tokens are signed with the algorithm of the active signing key.
 #[get("/.well-known/openid-configuration")]
-pub fn openid_configuration() -> &'static str {
-    OPENID_CONFIG
+pub fn openid_configuration(credentials_list: rocket::State<Vec<Credentials>>) -> Result<content::Json<String>, MyResponder> {
+    let credentials = ohx_credentials(&credentials_list)?;
+    let mut configuration: serde_json::Map<String, serde_json::Value> = serde_json::from_str(OPENID_CONFIG)?;
+    configuration.insert("issuer".to_owned(), jwt::ISSUER.into());
+    configuration.insert("id_token_signing_alg_values_supported".to_owned(), serde_json::json!([credentials.signing_algorithm().name()]));
+    Ok(content::Json(serde_json::to_string(&configuration)?))
 }
 
 /// Refresh access token environment variable on travis-ci
@@ -66,40 +109,30 @@ pub fn openid_configuration() -> &'static str {
 #[get("/check_for_users")]
 pub fn check_for_users(
     oauth_user: guard_oauth_jwt_access::OAuthIdentity,
-    firebase: rocket::State<Mutex<SASession>>,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    identities: rocket::State<Arc<dyn IdentityProvider>>,
+    auditor: Auditor,
 ) -> Result<String, MyResponder> {
-    // Only the google account is allowed to call this endpoint
-    if oauth_user.credentials_index != CREDENTIALS_GOOGLE_SERVICE_ACCOUNT_INDEX {
+    // Only service accounts (client credentials grant) with the admin scope are allowed to call this endpoint
+    if !oauth_user.scopes.contains(SCOPE_ADMIN) {
         return Err(MyResponder::AccessScopeInsufficient(
-            "Only the google CI account is allowed to call this endpoint".to_owned(),
+            "Requires the admin scope".to_owned(),
         ));
     }
 
-    use firestore_db_and_auth::dto;
     let timestamp: i64 = chrono::Utc::now().timestamp_millis() - 1000 * 60 * 60;
 
-    let session_mutex = firebase.lock()?;
-    let session: &SASession = session_mutex.deref();
-
-    let result = documents::query(session, "users", timestamp.into(), dto::FieldOperator::LESS_THAN_OR_EQUAL, "queued_remove")?;
-    for metadata in result {
-        let name = documents::abs_to_rel(&metadata.name);
-        let user_id = &name[name.rfind("/").unwrap() + 1..];
-        info!("FOUND DOCUMENT {} - {}", name, user_id);
-        let user_session = firestore_db_and_auth::UserSession::by_user_id(&session.credentials, user_id, false);
-        match user_session {
-            Ok(user_session) => {
-                match firestore_db_and_auth::users::user_remove(&user_session) {
-                    Ok(_) => {
-                        documents::delete(session, name, false)?;
-                    }
-                    Err(e) => {
-                        error!("Could not delete user {}. {:?}", user_id, e);
-                    }
-                }
+    let result: Vec<(String, serde_json::Value)> = store.query("users", "queued_remove", QueryOperator::LessThanOrEqual, timestamp)?;
+    for (user_id, _) in result {
+        info!("FOUND DOCUMENT users/{}", user_id);
+        match identities.remove_user(&user_id) {
+            Ok(_) => {
+                store.delete("users", &user_id)?;
+                auditor.record(AuditEvent::success("user.delete").with_client(oauth_user.client_id.as_ref()).with_target(user_id));
             }
             Err(e) => {
                 error!("Could not delete user {}. {:?}", user_id, e);
+                auditor.record(AuditEvent::failure("user.delete").with_client(oauth_user.client_id.as_ref()).with_target(user_id));
             }
         };
     }
@@ -111,73 +144,215 @@ pub fn check_for_users_unauthorized() -> MyResponder {
     MyResponder::AccessScopeInsufficient("Requires authorization".to_owned())
 }
 
+/// The token store key that marks the authorization request with the given code as granted
+fn granted_key(code: &str) -> String {
+    format!("granted.{}", code)
+}
+
 #[post("/grant_scopes", format = "application/json", data = "<request>")]
 pub fn grant_scopes(
     request: Json<GrantRequest>,
-    redis: rocket::State<redis::Client>,
-    firestore_auth: FirestoreAuthSessionGuard,
+    tokens: rocket::State<Arc<dyn TokenStore>>,
+    authorization_codes: rocket::State<AuthorizationCodeStore>,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    identities: rocket::State<Arc<dyn IdentityProvider>>,
+    user: UserSessionGuard,
     credentials_list: rocket::State<Vec<Credentials>>,
+    unsigned_token_key: rocket::State<UnsignedTokenKey>,
+    auditor: Auditor,
     _rate_limiter: RateLimiter,
 ) -> Result<String, MyResponder> {
-    let mut redis_connection = redis.get_connection()?;
-
-    //// Get stored access token from Redis. Might be "access_denied" or not set ////
-    let jwt_token: Option<String> = redis_connection.get(&request.code)?;
-    if jwt_token.is_some() {
-        return Err(MyResponder::bad_request("already_used"));
+    // The code is derived from the authorization request, it is not chosen by the caller
+    let code = hash_of_token(request.unsigned.as_bytes());
+    if code != request.code {
+        return Err(MyResponder::bad_request("invalid_request"));
     }
 
-    let credentials = credentials_list
-        .get(CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX)
-        .unwrap();
-    let secret = credentials.encode_secret().ok_or(MyResponder::InternalError("No private key found!".into()))?;
-
-    let mut jwt = decrypt_unsigned_jwt_token(&SECRET, &request.unsigned.as_bytes())?;
-
-    // Fix signature
-    jwt.header_mut()?.registered.algorithm = SignatureAlgorithm::RS256;
+    let credentials = ohx_credentials(&credentials_list)?;
+    let mut jwt = decrypt_unsigned_jwt_token(&unsigned_token_key.key, &request.unsigned.as_bytes(),
+                                             &request.client_id, unsigned_token_key.accepts_legacy())?;
 
-    let mut payload = jwt.payload_mut()?;
+    let payload = jwt.payload_mut()?;
 
     // Validate that this temporary, unsigned token from /authorize is still valid.
     // This is usually limited to 5 minutes.
     payload.registered.validate_exp(Validation::Validate(TemporalOptions::default()))
         .map_err(|_| MyResponder::bad_request("expired"))?;
 
-    // Fix user_id
-    payload.private.uid = Some(firestore_auth.0.user_id);
+    // An authorization request can only be granted once, also after its code has been redeemed.
+    // The marker lives as long as the authorization request is valid.
+    let now = chrono::Utc::now().timestamp();
+    let expires_in = payload.registered.expiry.as_ref().map_or(1, |f| f.timestamp() - now).max(1);
+    let granted_key = granted_key(&code);
+    if !tokens.set_if_absent(&granted_key, "1", expires_in as usize)? {
+        return Err(MyResponder::bad_request("already_used"));
+    }
 
     // Fix scopes
     payload.private.scope = request.scopes.intersection(&payload.private.scope).cloned().collect();
+    let granted_scopes = payload.private.scope.clone();
+
+    if let Err(e) = issue_authorization_code(&credentials, &authorization_codes, &store, &identities, &auditor, &user.user_id, jwt, &code) {
+        // Nothing has been granted. Release the marker, so that the user can retry.
+        if let Err(e) = tokens.delete(&granted_key) {
+            warn!("Failed to release the grant marker {}: {:?}", &granted_key, e);
+        }
+        return Err(e);
+    }
+    record_consent(&store, &user.user_id, &request.client_id, granted_scopes)?;
+    Ok(code)
+}
+
+/// Signs the tokens of an authorization request for the given user and stores them under the given code.
+/// The scopes of the unsigned token must already be reduced to the granted ones.
+fn issue_authorization_code(credentials: &Credentials, authorization_codes: &AuthorizationCodeStore, store: &dyn DocumentStore,
+                            identities: &dyn IdentityProvider, auditor: &Auditor, user_id: &str, mut jwt: jwt::AuthClaimsJWT,
+                            code: &str) -> Result<(), MyResponder> {
+    let payload = jwt.payload_mut()?;
+
+    // Fix user_id
+    payload.private.uid = Some(user_id.to_owned());
+
+    // Incremental authorization: Extend the current grant of the client instead of creating a parallel one
+    let mut family_id = None;
+    if payload.private.include_granted_scopes.take() == Some(true) {
+        let client_id = payload.private.client_id.as_ref().ok_or(MyResponder::bad_request("invalid_client"))?;
+        if let Some((current_family_id, granted_scopes)) = current_token_family(store, user_id, client_id)? {
+            payload.private.scope.extend(granted_scopes);
+            family_id = Some(current_family_id);
+        }
+    }
+
+    // The PKCE code challenge and the redirect uri must not end up in the signed tokens. They are stored next to them instead.
+    let code_challenge = payload.private.code_challenge.take();
+    let redirect_uri = payload.private.redirect_uri.take();
+    let client_id = payload.private.client_id.clone().ok_or(MyResponder::bad_request("invalid_client"))?;
+
+    // OpenID Connect: An ID token is issued if the "openid" scope has been granted
+    let nonce = payload.private.nonce.take();
+    let id_token = match payload.private.scope.contains(SCOPE_OPENID) {
+        true => {
+            let client_id = payload.private.client_id.as_ref().ok_or(MyResponder::bad_request("invalid_client"))?;
+            Some(create_id_token(credentials, identities, user_id, client_id, &payload.private.scope, nonce)?)
+        }
+        false => None
+    };
+
+    let event = AuditEvent::success("authorization.grant")
+        .with_user(Some(user_id))
+        .with_client(payload.private.client_id.as_ref())
+        .with_target(payload.private.scope.iter().cloned().collect::<Vec<_>>().join(" "));
 
     use std::ops::Add;
 
-    // If there is a refresh_token, it will be appended as second argument after a whitespace
-    let two_jwts = match request.scopes.contains(SCOPE_OFFLINE_ACCESS) {
+    let grant = match payload.private.scope.contains(SCOPE_OFFLINE_ACCESS) {
         true => {
             payload.registered.expiry = Some(biscuit::Timestamp::from(chrono::Utc::now().add(chrono::Duration::weeks(52 * 10))));
             let scopes = payload.private.scope.iter().filter(|f| f.as_str() != SCOPE_OFFLINE_ACCESS);
             // Create access token (same as refresh token but without the SCOPE_OFFLINE_ACCESS scope
             // and with only 1h expiry time
             let access_token = jwt::create_jwt_encoded_for_user(
-                &credentials,
+                credentials,
                 Some(scopes),
                 Duration::seconds(3600),
                 payload.private.client_id.as_ref().and_then(|f| Some(f.clone())),
                 payload.private.uid.as_ref().unwrap().clone(),
                 payload.registered.subject.as_ref().unwrap().to_string())?;
             // Sign
-            format!("{} {}", access_token, jwt.encode(&secret.deref())?.encoded()?.encode())
+            AuthorizationGrant {
+                client_id,
+                redirect_uri,
+                access_token,
+                refresh_token: Some(jwt::sign_jwt(credentials, jwt)?),
+                code_challenge,
+                id_token,
+                family_id,
+            }
         }
         false => {
             payload.registered.expiry = Some(biscuit::Timestamp::from(chrono::Utc::now().add(chrono::Duration::hours(1))));
             // Sign
-            jwt.encode(&secret.deref())?.encoded()?.encode()
+            AuthorizationGrant {
+                client_id,
+                redirect_uri,
+                access_token: jwt::sign_jwt(credentials, jwt)?,
+                refresh_token: None,
+                code_challenge,
+                id_token,
+                family_id: None,
+            }
         }
     };
 
-    redis_connection.set_ex(&request.code, &two_jwts, 360)?;
-    Ok(request.code.clone())
+    // Concurrent requests with the same code: Only the first one is stored
+    if !authorization_codes.insert(code, &grant, 360)? {
+        return Err(MyResponder::bad_request("already_used"));
+    }
+    auditor.record(event);
+    Ok(())
+}
+
+/// Remembers the granted scopes of a client for the user. Previously granted scopes are kept.
+fn record_consent(store: &dyn DocumentStore, uid: &str, client_id: &str, scopes: BTreeSet<String>) -> Result<(), MyResponder> {
+    let id = db::ConsentInDB::id(uid, client_id);
+    let mut consent = store.read::<db::ConsentInDB>("consents", &id)?.unwrap_or_else(|| db::ConsentInDB {
+        uid: uid.to_owned(),
+        client_id: client_id.to_owned(),
+        scopes: BTreeSet::new(),
+        granted_at: 0,
+    });
+    consent.scopes.extend(scopes);
+    consent.granted_at = chrono::Utc::now().timestamp();
+    store.write("consents", &id, &consent)?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct OpenIdConfiguration {
+    claims_supported: BTreeSet<String>,
+    scopes_supported: BTreeSet<String>,
+}
+
+/// Create a signed ID token for the given user.
+///
+/// Profile claims are only added for the "profile" scope, email claims only for the "email" scope.
+/// Claims that are not listed in "claims_supported" of the discovery document are never added.
+fn create_id_token(credentials: &Credentials, identities: &dyn IdentityProvider, user_id: &str, client_id: &str,
+                   scopes: &BTreeSet<String>, nonce: Option<String>) -> Result<String, MyResponder> {
+    let claims_supported = serde_json::from_str::<OpenIdConfiguration>(OPENID_CONFIG)?.claims_supported;
+
+    let user = identities.user_info(user_id)?.ok_or(MyResponder::NotFound("User not found!".into()))?;
+    let auth_time = auth_time(user.last_login_at);
+
+    let mut claims = jwt::IdTokenPrivateClaims {
+        nonce,
+        auth_time: Some(auth_time),
+        ..Default::default()
+    };
+    if scopes.contains("email") {
+        claims.email = user.email;
+        claims.email_verified = user.email_verified;
+    }
+    if scopes.contains("profile") {
+        claims.name = user.display_name;
+        claims.picture = user.photo_url;
+    }
+    claims.retain_supported(&claims_supported);
+
+    Ok(jwt::create_id_token_encoded(credentials, client_id, user_id, Duration::hours(1), claims)?)
+}
+
+/// The last login of a user (unix timestamp). The "last_login_at" field is in milliseconds.
+fn auth_time(last_login_at: Option<i64>) -> i64 {
+    last_login_at
+        .map(|f| f / 1000)
+        .unwrap_or_else(|| chrono::Utc::now().timestamp())
+}
+
+/// The last login of the given user (unix timestamp)
+fn user_auth_time(identities: &dyn IdentityProvider, user_id: &str) -> Result<i64, MyResponder> {
+    let user = identities.user_info(user_id)?.ok_or(MyResponder::NotFound("User not found!".into()))?;
+    Ok(auth_time(user.last_login_at))
 }
 
 #[post("/grant_scopes", rank = 2)]
@@ -185,18 +360,178 @@ pub fn grant_scopes_unauthorized() -> MyResponder {
     MyResponder::AccessScopeInsufficient("Not authorized!".into())
 }
 
+/// The optional amount of days after which an unused refresh token family expires (runtime configuration)
+pub const REFRESH_TOKEN_MAX_INACTIVE_DAYS: &str = "refresh_token_max_inactive_days.txt";
+
+/// Expiry policy for refresh tokens
+pub struct RefreshTokenPolicy {
+    /// Refresh tokens that have not been used for this amount of days are expired. Never expire if not set.
+    pub max_inactive_days: Option<u32>,
+}
+
+impl RefreshTokenPolicy {
+    /// Reads the policy from [`REFRESH_TOKEN_MAX_INACTIVE_DAYS`] of the runtime configuration
+    pub fn from_config(config_source: &ConfigSource) -> Result<RefreshTokenPolicy, CloudAuthError> {
+        let max_inactive_days = match config_source.optional(REFRESH_TOKEN_MAX_INACTIVE_DAYS)? {
+            Some(days) => Some(days.trim().parse::<u32>()
+                .map_err(|_| CloudAuthError::GenericOwned(format!("{} must be a number of days", REFRESH_TOKEN_MAX_INACTIVE_DAYS)))?),
+            None => None
+        };
+        Ok(RefreshTokenPolicy { max_inactive_days })
+    }
+
+    /// The expiry of the given refresh token: The expiry of the token itself, or the end of the allowed
+    /// period of inactivity if that is earlier
+    pub fn expires_at(&self, db_entry: &db::AccessTokenInDB) -> Option<i64> {
+        let inactive_at = self.max_inactive_days.map(|days| db_entry.last_used() + Duration::days(days as i64).num_seconds());
+        match (jwt::unverified_expiry(&db_entry.token), inactive_at) {
+            (Some(expiry), Some(inactive_at)) => Some(expiry.min(inactive_at)),
+            (expiry, inactive_at) => expiry.or(inactive_at)
+        }
+    }
+
+    /// Returns true if the given refresh token has not been used within the allowed period
+    pub fn is_inactive(&self, db_entry: &db::AccessTokenInDB, now: i64) -> bool {
+        match self.max_inactive_days {
+            Some(days) => now - db_entry.last_used() > Duration::days(days as i64).num_seconds(),
+            None => false
+        }
+    }
+}
+
+/// A refresh token is claimed for this amount of seconds while it is rotated.
+/// The presented token is superseded afterwards, so a later presentation is detected as reuse anyway.
+const REFRESH_CLAIM_SECS: usize = 300;
+
+/// A superseded refresh token that is presented again within this amount of seconds after its rotation is
+/// a retry of the client (for example after a lost response) and not a replay. The client receives the
+/// successor again, as long as that one has not been rotated itself.
+const REFRESH_GRACE_SECS: i64 = 60;
+
+/// The token store key that claims the refresh token with the given hash for a rotation
+fn refresh_claim_key(token_hash: &str) -> String {
+    format!("refresh_claim.{}", token_hash)
+}
+
+/// RFC 6749, 6: The access token can be limited to a subset of the granted scopes.
+/// The refresh token keeps all granted scopes.
+fn refresh_scopes(token_request: &TokenDTO, db_entry: &db::AccessTokenInDB) -> Result<BTreeSet<String>, MyResponder> {
+    let scopes: BTreeSet<String> = match &token_request.scope {
+        Some(scope) => scope.split(" ").filter(|f| !f.is_empty()).map(|f| f.to_owned()).collect(),
+        None => db_entry.scopes.clone()
+    };
+    if !scopes.is_subset(&db_entry.scopes) {
+        return Err(MyResponder::bad_request("invalid_scope"));
+    }
+    Ok(scopes)
+}
+
+/// Returns the successor of a superseded refresh token if the token has been rotated within the grace period
+/// and the successor is still the current token of the family.
+fn current_successor(store: &dyn DocumentStore, db_entry: &db::AccessTokenInDB, now: i64) -> Result<Option<db::AccessTokenInDB>, MyResponder> {
+    let successor_hash = match (&db_entry.successor, db_entry.superseded_at) {
+        (Some(successor_hash), Some(superseded_at)) if now - superseded_at <= REFRESH_GRACE_SECS => successor_hash,
+        _ => return Ok(None)
+    };
+    let successor: Option<db::AccessTokenInDB> = store.read("access_tokens", successor_hash)?;
+    Ok(successor.filter(|successor| successor.superseded_at.is_none() && successor.family_id == db_entry.family_id))
+}
+
+/// Issues an access token and a new refresh token of the same family for the refresh token with the given hash.
+/// The presented refresh token is marked as superseded. Returns the access and the new refresh token.
+fn rotate_refresh_token(credentials: &Credentials, store: &dyn DocumentStore, token_hash: &str, db_entry: &mut db::AccessTokenInDB,
+                        scopes: &BTreeSet<String>, now: i64, last_ip: Option<String>) -> Result<(String, String), MyResponder> {
+    db_entry.last_used_at = Some(now);
+    db_entry.last_ip = last_ip;
+    db_entry.use_count += 1;
+
+    // Filter out offline scope and create access token
+    let access_token = jwt::create_jwt_encoded_for_user(credentials, Some(scopes.iter().filter(|f| f.as_str() != SCOPE_OFFLINE_ACCESS)),
+                                                        Duration::hours(1),
+                                                        Some(db_entry.client_id.clone()), db_entry.uid.clone(), credentials.client_email.clone())?;
+    let (access_token_id, access_token_expiry) = access_token_id_and_expiry(credentials, &access_token);
+
+    // Rotate: Issue a new refresh token of the same family and mark the presented one as superseded
+    let new_refresh_token = jwt::create_jwt_encoded_for_user(credentials, Some(db_entry.scopes.iter()),
+                                                             Duration::weeks(52 * 10),
+                                                             Some(db_entry.client_id.clone()), db_entry.uid.clone(), credentials.client_email.clone())?;
+    let new_db_entry = db::AccessTokenInDB {
+        uid: db_entry.uid.clone(),
+        token: new_refresh_token.clone(),
+        client_id: db_entry.client_id.clone(),
+        scopes: db_entry.scopes.clone(),
+        issued_at: now,
+        family_id: db_entry.family_id.clone(),
+        superseded_at: None,
+        successor: None,
+        access_token_id,
+        access_token_expiry,
+        last_used_at: db_entry.last_used_at,
+        last_ip: db_entry.last_ip.clone(),
+        use_count: db_entry.use_count,
+    };
+    let new_token_hash = hash_of_token(new_refresh_token.as_bytes());
+    store.write("access_tokens", &new_token_hash, &new_db_entry)?;
+    db_entry.superseded_at = Some(now);
+    db_entry.successor = Some(new_token_hash.clone());
+    // The document store has no transactions: Remove the new token again if the presented one cannot be superseded.
+    // Otherwise two current tokens would exist in the family.
+    if let Err(e) = store.write("access_tokens", token_hash, &*db_entry) {
+        if let Err(e) = store.delete("access_tokens", &new_token_hash) {
+            error!("Failed to remove the new refresh token of family {}: {:?}", &db_entry.family_id, e);
+        }
+        return Err(e.into());
+    }
+    Ok((access_token, new_refresh_token))
+}
+
+/// Issues another access token for the successor of a refresh token that is presented again within the grace period.
+/// The access token that has been issued before for the presented token is added to the deny-list and replaced,
+/// so that a revocation of the family still covers all issued access tokens.
+fn reissue_for_successor(credentials: &Credentials, store: &dyn DocumentStore, deny_list: &TokenDenyList, token_hash: &str,
+                         db_entry: &mut db::AccessTokenInDB, scopes: &BTreeSet<String>) -> Result<String, MyResponder> {
+    let access_token = jwt::create_jwt_encoded_for_user(credentials, Some(scopes.iter().filter(|f| f.as_str() != SCOPE_OFFLINE_ACCESS)),
+                                                        Duration::hours(1),
+                                                        Some(db_entry.client_id.clone()), db_entry.uid.clone(), credentials.client_email.clone())?;
+    if let (Some(jti), Some(expiry)) = (&db_entry.access_token_id, db_entry.access_token_expiry) {
+        deny_list.deny(jti, expiry)?;
+    }
+    let (access_token_id, access_token_expiry) = access_token_id_and_expiry(credentials, &access_token);
+    db_entry.access_token_id = access_token_id;
+    db_entry.access_token_expiry = access_token_expiry;
+    store.write("access_tokens", token_hash, &*db_entry)?;
+    Ok(access_token)
+}
+
 /// Exchange
 #[post("/token", data = "<token_request>")]
 pub fn token(
     token_request: TokenRequest,
-    redis: rocket::State<redis::Client>,
-    firebase: rocket::State<Mutex<SASession>>,
+    tokens: rocket::State<Arc<dyn TokenStore>>,
+    authorization_codes: rocket::State<AuthorizationCodeStore>,
+    store: rocket::State<Arc<dyn DocumentStore>>,
     credentials_list: rocket::State<Vec<Credentials>>,
+    oauth_clients: rocket::State<OAuthClientStore>,
+    deny_list: rocket::State<TokenDenyList>,
+    refresh_token_policy: rocket::State<RefreshTokenPolicy>,
+    client_addr: Option<ClientRealAddr>,
+    auditor: Auditor,
     _rate_limiter: RateLimiter,
 ) -> Result<content::Json<String>, MyResponder> {
-    let credentials = credentials_list
-        .get(CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX)
-        .unwrap();
+    let credentials = ohx_credentials(&credentials_list)?;
+
+    if &token_request.grant_type == "client_credentials" {
+        return client_credentials_grant(&token_request, credentials, &store, &oauth_clients, &auditor);
+    }
+
+    // Confidential clients must authenticate with their secret (RFC 6749, 3.2.1)
+    let client = match oauth_clients.get(&store, &token_request.client_id) {
+        Some(client) if client.authenticate(token_request.client_secret.as_ref().map(|f| f.as_str())) => client,
+        _ => {
+            auditor.record(AuditEvent::failure("token.client_authentication").with_client(Some(&token_request.client_id)));
+            return Err(MyResponder::AccessScopeInsufficient("invalid_client".to_owned()));
+        }
+    };
 
     if &token_request.grant_type == "refresh_token" {
         let refresh_token = match &token_request.refresh_token {
@@ -205,17 +540,69 @@ pub fn token(
         };
 
         let code = hash_of_token(refresh_token.as_bytes());
-        let db_entry: db::AccessTokenInDB = {
-            let session_mutex = firebase.lock()?;
-            let session: &SASession = session_mutex.deref();
-            firestore_db_and_auth::documents::read(session, "access_tokens", code).map_err(|_e| MyResponder::bad_request("Access Token not valid. It may have been revoked!"))?
+        let mut db_entry: db::AccessTokenInDB = store.read("access_tokens", &code)?
+            .ok_or(MyResponder::bad_request("invalid_grant"))?;
+        if db_entry.client_id != token_request.client_id {
+            return Err(MyResponder::bad_request("invalid_grant"));
+        }
+        if db_entry.family_id.is_empty() {
+            db_entry.family_id = code.clone();
+        }
+
+        let now = chrono::Utc::now().timestamp();
+
+        // A superseded refresh token is presented again. Within the grace period this is a retry of the client,
+        // which receives the current successor. Later on either the client or an attacker holds a leaked token.
+        // Revoke the whole token family, the user must authorize again.
+        if db_entry.superseded_at.is_some() {
+            if let Some(successor) = current_successor(&store, &db_entry, now)? {
+                let scopes = refresh_scopes(&token_request, &db_entry)?;
+                let access_token = reissue_for_successor(&credentials, &store, &deny_list, &code, &mut db_entry, &scopes)?;
+                auditor.record(AuditEvent::success("token.refresh_retry")
+                    .with_user(Some(&db_entry.uid)).with_client(Some(&db_entry.client_id)).with_target(db_entry.family_id.clone()));
+                let token_response = OAuthTokenResponse::new(access_token, Some(successor.token), scopes.into_iter().collect());
+                return Ok(content::Json(serde_json::to_string(&token_response)?));
+            }
+            warn!("Refresh token reuse detected for client {}. Revoking token family {}", &db_entry.client_id, &db_entry.family_id);
+            revoke_token_family(&store, &deny_list, &db_entry.family_id)?;
+            auditor.record(AuditEvent::failure("token.refresh_reuse")
+                .with_user(Some(&db_entry.uid)).with_client(Some(&db_entry.client_id)).with_target(db_entry.family_id));
+            return Err(MyResponder::bad_request("invalid_grant"));
+        }
+
+        if refresh_token_policy.is_inactive(&db_entry, now) {
+            info!("Refresh token family {} expired due to inactivity", &db_entry.family_id);
+            revoke_token_family(&store, &deny_list, &db_entry.family_id)?;
+            auditor.record(AuditEvent::failure("token.refresh")
+                .with_user(Some(&db_entry.uid)).with_client(Some(&db_entry.client_id)).with_target(db_entry.family_id));
+            return Err(MyResponder::bad_request("invalid_grant"));
+        }
+        let scopes = refresh_scopes(&token_request, &db_entry)?;
+
+        // Concurrent refreshes with the same token: Only the first one claims the token and rotates it.
+        // The others fail without revoking the family. A retry after the rotation receives the successor.
+        let claim_key = refresh_claim_key(&code);
+        if !tokens.set_if_absent(&claim_key, "1", REFRESH_CLAIM_SECS)? {
+            info!("Concurrent refresh of a token of family {} for client {}", &db_entry.family_id, &db_entry.client_id);
+            return Err(MyResponder::bad_request("invalid_grant"));
+        }
+
+        let last_ip = client_addr.map(|addr| addr.ip.to_string());
+        let (access_token, new_refresh_token) = match rotate_refresh_token(&credentials, &store, &code, &mut db_entry, &scopes, now, last_ip) {
+            Ok(rotated) => rotated,
+            Err(e) => {
+                // The token has not been rotated. Release the claim, so that the client can retry.
+                if let Err(e) = tokens.delete(&claim_key) {
+                    warn!("Failed to release the refresh token claim {}: {:?}", &claim_key, e);
+                }
+                return Err(e);
+            }
         };
-        // Filter out offline scope and create access token
-        let access_token = jwt::create_jwt_encoded_for_user(&credentials, Some(db_entry.scopes.iter().filter(|f| f.as_str() != SCOPE_OFFLINE_ACCESS)),
-                                                            Duration::hours(1),
-                                                            Some(db_entry.client_id.clone()), db_entry.uid.clone(), credentials.client_email.clone())?;
 
-        let token_response = OAuthTokenResponse::new(access_token, Some(db_entry.token), db_entry.scopes.clone().into_iter().collect());
+        auditor.record(AuditEvent::success("token.refresh")
+            .with_user(Some(&db_entry.uid)).with_client(Some(&db_entry.client_id)).with_target(db_entry.family_id.clone()));
+
+        let token_response = OAuthTokenResponse::new(access_token, Some(new_refresh_token), scopes.into_iter().collect());
         return Ok(content::Json(serde_json::to_string(&token_response)?));
     }
 
@@ -225,34 +612,57 @@ pub fn token(
     let is_device_code = match &token_request.grant_type[..] {
         "urn:ietf:params:oauth:grant-type:device_code" | "device_code" => true,
         "authorization_code" => false,
-        _ => return Err(MyResponder::bad_request("grant_type must be authorization_code, refresh_token or urn:ietf:params:oauth:grant-type:device_code"))
+        _ => return Err(MyResponder::bad_request("unsupported_grant_type"))
     };
 
-    let mut redis_connection = redis.get_connection()?;
-
-    //// Get stored access token from Redis. Might be "access_denied" or not set ////
-    let two_jwts: Option<String> = redis_connection.get(code)?;
-    if two_jwts.is_none() {
-        if is_device_code {
+    //// Redeem the code. It can only be used once, also if the request fails from here on ////
+    let grant = match authorization_codes.take(code)? {
+        Some(grant) => grant,
+        None if is_device_code => {
+            // RFC 8628, 3.5: The authorization is pending only until the device code expires
+            if tokens.get(&device_pending_key(code))?.is_none() {
+                return Err(MyResponder::bad_request("expired_token"));
+            }
+            // A client that polls more often than the interval must slow down
+            let first_poll_in_interval = tokens.set_if_absent(&device_poll_key(code), "1", DEVICE_FLOW_INTERVAL as usize)?;
+            if !first_poll_in_interval {
+                return Err(MyResponder::bad_request("slow_down"));
+            }
             return Err(MyResponder::bad_request("authorization_pending"));
-        } else {
-            return Err(MyResponder::bad_request("expired_token"));
+        }
+        None => return Err(MyResponder::bad_request("expired_token"))
+    };
+    if is_device_code {
+        if let Err(e) = tokens.delete(&device_pending_key(code)) {
+            warn!("Failed to remove the pending device authorization: {:?}", e);
         }
     }
-    let two_jwts = two_jwts.unwrap();
-    if &two_jwts == "access_denied" {
-        return Err(MyResponder::bad_request("access_denied"));
+
+    // The code is bound to the client and the redirect uri of the authorization request (RFC 6749, 4.1.3)
+    let redirect_uri_matches = grant.redirect_uri.is_none() || grant.redirect_uri == token_request.redirect_uri;
+    if grant.client_id != token_request.client_id || !redirect_uri_matches {
+        auditor.record(AuditEvent::failure("token.code_exchange").with_client(Some(&token_request.client_id)));
+        return Err(MyResponder::bad_request("invalid_grant"));
     }
 
-    let mut two_jwts = two_jwts.split(" ");
-    let access_token = two_jwts.next().unwrap_or_default();
-    let refresh_token = two_jwts.next().unwrap_or(&access_token);
+    // PKCE: If the authorization request contained a code challenge, the code verifier must match.
+    // A code of a public client without a code challenge is not accepted.
+    let verified = match (&grant.code_challenge, &token_request.code_verifier) {
+        (Some(code_challenge), Some(code_verifier)) => verify_code_challenge(code_verifier, code_challenge),
+        (Some(_), None) => false,
+        (None, _) => is_device_code || client.is_confidential()
+    };
+    if !verified {
+        auditor.record(AuditEvent::failure("token.code_exchange").with_client(Some(&token_request.client_id)));
+        return Err(MyResponder::bad_request("invalid_grant"));
+    }
 
+    let access_token = grant.access_token.as_str();
+    let refresh_token = grant.refresh_token.as_ref().unwrap_or(&grant.access_token);
 
     //// verify token
     let token_result = jwt::verify_access_token(&credentials, &refresh_token)?;
     if token_result.is_none() {
-        redis_connection.del(code)?;
         return Err(MyResponder::bad_request("expired_token"));
     }
     let token_result = token_result.unwrap();
@@ -263,29 +673,37 @@ pub fn token(
     };
 
     let scopes = token_result.claims.scope;
-    let token_response = if scopes.contains(SCOPE_OFFLINE_ACCESS) {
+
+    let mut token_response = if scopes.contains(SCOPE_OFFLINE_ACCESS) {
+        let (access_token_id, access_token_expiry) = access_token_id_and_expiry(&credentials, access_token);
+        let now = chrono::Utc::now().timestamp();
+        // Incremental authorization: The refresh token replaces the current one of the extended grant
+        let family_id = match &grant.family_id {
+            Some(family_id) => {
+                supersede_token_family(&store, family_id, now)?;
+                family_id.clone()
+            }
+            None => uuid::Uuid::new_v4().to_string()
+        };
         let access_token_in_db = db::AccessTokenInDB {
             uid: uid.to_owned(),
-            client_id: token_request.client_id.clone(),
+            client_id: grant.client_id.clone(),
             token: refresh_token.to_owned(),
             scopes: scopes.clone(),
-            issued_at: chrono::Utc::now().timestamp(),
+            issued_at: now,
+            family_id,
+            superseded_at: None,
+            successor: None,
+            access_token_id,
+            access_token_expiry,
+            last_used_at: None,
+            last_ip: None,
+            use_count: 0,
         };
 
         // Write refresh token to database. Can be revoked by the user (== deleted) and is used
         // by the token endpoint to create new access_tokens.
-        {
-            let session_mutex = firebase.lock()?;
-            let session: &SASession = session_mutex.deref();
-
-            documents::write(
-                session,
-                "access_tokens",
-                Some(&hash_of_token(refresh_token.as_bytes())),
-                &access_token_in_db,
-                documents::WriteOptions::default(),
-            )?;
-        }
+        store.write("access_tokens", &hash_of_token(refresh_token.as_bytes()), &access_token_in_db)?;
 
         OAuthTokenResponse::new(
             access_token.to_owned(),
@@ -296,10 +714,60 @@ pub fn token(
         OAuthTokenResponse::new(access_token.to_owned(), None, scopes)
     };
 
-    redis_connection.del(code)?;
+    token_response.id_token = grant.id_token;
+
+    auditor.record(AuditEvent::success("token.code_exchange").with_user(Some(uid)).with_client(Some(&token_request.client_id)));
+
     return Ok(content::Json(serde_json::to_string(&token_response)?));
 }
 
+/// The redirect uri with the given error code and state (RFC 6749, 4.1.2.1)
+fn authorize_error_uri(redirect_uri: &str, error: &str, state: Option<&String>) -> String {
+    let mut uri = match url::Url::parse(redirect_uri) {
+        Ok(uri) => uri,
+        Err(_) => return redirect_uri.to_owned()
+    };
+    uri.query_pairs_mut().append_pair("error", error);
+    if let Some(state) = state {
+        uri.query_pairs_mut().append_pair("state", state);
+    }
+    uri.into_string()
+}
+
+/// Client credentials grant (RFC 6749, 4.4) for service-to-service tokens.
+///
+/// Only confidential clients are allowed. The access token is limited to the requested or otherwise all
+/// scopes of the client, has no user id and is valid for one hour. No refresh token is issued.
+fn client_credentials_grant(
+    token_request: &TokenDTO,
+    credentials: &Credentials,
+    store: &dyn DocumentStore,
+    oauth_clients: &OAuthClientStore,
+    auditor: &Auditor,
+) -> Result<content::Json<String>, MyResponder> {
+    let client = oauth_clients.get(store, &token_request.client_id);
+    let client = match client {
+        Some(client) if client.is_confidential() && client.authenticate(token_request.client_secret.as_ref().map(|f| f.as_str())) => client,
+        _ => {
+            auditor.record(AuditEvent::failure("token.client_credentials").with_client(Some(&token_request.client_id)));
+            return Err(MyResponder::AccessScopeInsufficient("invalid_client".to_owned()));
+        }
+    };
+
+    let scopes: BTreeSet<String> = match &token_request.scope {
+        Some(scope) => scope.split(" ").filter(|f| !f.is_empty()).map(|f| f.to_owned()).collect(),
+        None => client.scopes.iter().filter(|f| f.as_str() != SCOPE_OFFLINE_ACCESS).cloned().collect()
+    };
+    if scopes.contains(SCOPE_OFFLINE_ACCESS) || !scopes.iter().all(|scope| client.scopes.contains(scope)) {
+        return Err(MyResponder::bad_request("invalid_scope"));
+    }
+
+    let access_token = jwt::create_jwt_encoded(credentials, Some(scopes.iter()), Duration::hours(1), Some(client.id.clone()))?;
+    auditor.record(AuditEvent::success("token.client_credentials").with_client(Some(&client.id)));
+    let token_response = OAuthTokenResponse::new(access_token, None, scopes);
+    Ok(content::Json(serde_json::to_string(&token_response)?))
+}
+
 /// Code grant Flow: Redirect the user to the openhabx.com/oauth?client_id&code&response_type&unsigned page.
 /// Device Flow: Returns a json with the same arguments
 ///
@@ -307,116 +775,860 @@ pub fn token(
 /// * unsigned: a jwt, but unsigned, compressed and encrypted. Cannot be modified by the UI
 ///   and must be passed to the /grant_scopes endpoint unchanged.
 /// * code: The hash of unsigned but otherwise opaque to the consumer.
-///   Will be used by /grant_scopes as key to store generated tokens in redis and will be used
+///   Will be used by /grant_scopes as key to store generated tokens in the token store and will be used
 ///   by /token to retrieve those generated tokens.
+///
+/// If the request is made with the session of a user (like the website does) and the user already
+/// consented to the requested scopes, the code is issued directly and the client is redirected to its
+/// redirect uri. The OpenID Connect "prompt" and "max_age" parameters are supported:
+/// * prompt=none: Never show the UI. Fails with "login_required" or "consent_required" instead.
+/// * prompt=login, or a last login longer ago than max_age: The UI asks the user to login again.
+/// * prompt=consent: The UI shows the consent screen, even if the user already consented.
 #[post("/authorize", data = "<request>")]
 pub fn authorize(
     request: GenerateTokenRequest,
+    user: Option<UserSessionGuard>,
+    identities: rocket::State<Arc<dyn IdentityProvider>>,
+    tokens: rocket::State<Arc<dyn TokenStore>>,
+    authorization_codes: rocket::State<AuthorizationCodeStore>,
     credentials_list: rocket::State<Vec<Credentials>>,
-    client_data: rocket::State<OAuthClients>,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    oauth_clients: rocket::State<OAuthClientStore>,
+    unsigned_token_key: rocket::State<UnsignedTokenKey>,
+    auditor: Auditor,
     _rate_limiter: RateLimiter,
+) -> Result<RedirectOrResponseAuthorize, MyResponder> {
+    let user_id = user.as_ref().map(|f| f.user_id.as_str());
+    create_authorization(&request, user_id, &identities, &tokens, &authorization_codes, &credentials_list, &store, &oauth_clients, &unsigned_token_key, &auditor)
+}
+
+/// The OpenID Connect "prompt" parameter: A space separated list of "login" and "consent", or "none" alone
+#[derive(Default)]
+struct Prompt {
+    none: bool,
+    login: bool,
+    consent: bool,
+}
+
+impl Prompt {
+    fn parse(prompt: Option<&String>) -> Option<Prompt> {
+        let mut result = Prompt::default();
+        for value in prompt.map(|f| f.as_str()).unwrap_or_default().split(" ").filter(|f| !f.is_empty()) {
+            match value {
+                "none" => result.none = true,
+                "login" => result.login = true,
+                "consent" => result.consent = true,
+                // "select_account" is not supported
+                _ => return None
+            }
+        }
+        match result.none && (result.login || result.consent) {
+            true => None,
+            false => Some(result)
+        }
+    }
+
+    /// The prompt for the UI: "login" takes precedence, the UI shows the consent screen after a login anyway
+    fn for_ui(&self, login_required: bool) -> Option<String> {
+        if self.login || login_required {
+            Some("login".to_owned())
+        } else if self.consent {
+            Some("consent".to_owned())
+        } else {
+            None
+        }
+    }
+}
+
+/// The redirect uri of the client with the given code and state (RFC 6749, 4.1.2)
+fn authorize_code_uri(redirect_uri: &str, code: &str, state: Option<&String>) -> String {
+    let mut uri = match url::Url::parse(redirect_uri) {
+        Ok(uri) => uri,
+        Err(_) => return redirect_uri.to_owned()
+    };
+    uri.query_pairs_mut().append_pair("code", code);
+    if let Some(state) = state {
+        uri.query_pairs_mut().append_pair("state", state);
+    }
+    uri.into_string()
+}
+
+fn create_authorization(
+    request: &GenerateCodeDTO,
+    user_id: Option<&str>,
+    identities: &dyn IdentityProvider,
+    tokens: &dyn TokenStore,
+    authorization_codes: &AuthorizationCodeStore,
+    credentials_list: &[Credentials],
+    store: &dyn DocumentStore,
+    oauth_clients: &OAuthClientStore,
+    unsigned_token_key: &UnsignedTokenKey,
+    auditor: &Auditor,
 ) -> Result<RedirectOrResponseAuthorize, MyResponder> {
     use rocket::http::uri::{Query, UriDisplay};
     use rocket::response::Redirect;
 
-    // Check if client_id is valid
-    let client_data = if let Some(client_data) = client_data.get(&request.client_id) {
-        if let Some(secret) = client_data.secret.as_ref() {
-            match &request.client_secret {
-                None => return Err(MyResponder::bad_request("Client secret expected!")),
-                Some(client_secret) if secret != client_secret => return Err(MyResponder::bad_request("Client secret does not match")),
-                _ => {}
-            }
+    // Check if client_id is valid. Errors before the redirect uri has been validated are not redirected.
+    let client_data = oauth_clients.get(store, &request.client_id);
+    let client_data = client_data.ok_or(MyResponder::bad_request("invalid_client"))?;
+
+    // The code grant flow redirects to a registered uri only
+    let redirect_uri = match &request.response_type[..] {
+        "code" => Some(client_data.resolve_redirect_uri(request.redirect_uri.as_ref().map(|f| f.as_str()))
+            .ok_or(MyResponder::bad_request("invalid_request"))?),
+        "device" => None,
+        _ => return Err(MyResponder::bad_request("unsupported_response_type"))
+    };
+
+    // RFC 6749, 4.1.2.1: Redirect to the client with an error code, if possible.
+    let error_response = |error: &str| -> Result<RedirectOrResponseAuthorize, MyResponder> {
+        match &redirect_uri {
+            Some(redirect_uri) => Ok(RedirectOrResponseAuthorize::ToClient(
+                Redirect::to(authorize_error_uri(redirect_uri, error, request.state.as_ref())))),
+            None => Err(MyResponder::bad_request(error))
         }
-        client_data.clone()
-    } else {
-        return Err(MyResponder::bad_request("client_id unknown"));
     };
 
+    if !client_data.authenticate(request.client_secret.as_ref().map(|f| f.as_str())) {
+        return error_response("unauthorized_client");
+    }
 
+    if client_data.requires_state && request.state.as_ref().map_or(true, |f| f.is_empty()) {
+        return error_response("invalid_request");
+    }
 
-    let credentials = credentials_list
-        .get(CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX)
-        .unwrap();
+    let credentials = ohx_credentials(&credentials_list)?;
 
-    let scopes: HashSet<String> = match request.0.scope {
+    let scopes: HashSet<String> = match request.scope {
         Some(ref v) => v.split(" ").filter(|f| !f.is_empty()).map(|f| f.to_owned()).collect(),
         None => HashSet::new(),
     };
 
-    // Check scopes: Only those defined in oauth_clients.json are allowed
-    if !scopes.is_subset(&client_data.scopes) {
-        return Err(MyResponder::bad_request("Requested scopes are invalid"));
+    // Check scopes: Only those registered for the client are allowed.
+    // The admin scope is never granted by a user, only by the client credentials grant.
+    if !scopes.is_subset(&client_data.scopes) || scopes.contains(SCOPE_ADMIN) {
+        return error_response("invalid_scope");
+    }
+
+    // PKCE: Only the S256 method is accepted. "plain" would not protect against a leaked code.
+    // Public clients cannot authenticate at the token endpoint, a code challenge is mandatory for them (RFC 8252, 8.1).
+    let pkce_required = redirect_uri.is_some() && !client_data.is_confidential();
+    match (&request.code_challenge, request.code_challenge_method.as_ref().map(|f| f.as_str())) {
+        (Some(_), Some("S256")) => {}
+        (None, None) if !pkce_required => {}
+        _ => return error_response("invalid_request")
+    }
+
+    // OpenID Connect: The device flow has no user at this point and always shows the UI
+    let prompt = match Prompt::parse(request.prompt.as_ref()) {
+        Some(prompt) if !(prompt.none && redirect_uri.is_none()) => prompt,
+        _ => return error_response("invalid_request")
+    };
+
+    // The user must login again if the last login is older than max_age. Only known for a user session.
+    let login_required = match (user_id, request.max_age) {
+        (None, _) => true,
+        (Some(user_id), Some(max_age)) => chrono::Utc::now().timestamp() - user_auth_time(identities, user_id)? > max_age,
+        (Some(_), None) => false
+    };
+
+    // A consent of the user for the client that covers the requested scopes skips the consent screen
+    let consented = match user_id {
+        Some(user_id) if redirect_uri.is_some() && !login_required && !prompt.login && !prompt.consent => {
+            let consent: Option<db::ConsentInDB> = store.read("consents", &db::ConsentInDB::id(user_id, &request.client_id))?;
+            consent.map_or(false, |consent| consent.covers(&scopes.iter().cloned().collect()))
+        }
+        _ => false
+    };
+    if prompt.none && !consented {
+        return error_response(if login_required { "login_required" } else { "consent_required" });
     }
 
     // Create a token without signature
-    let jwt = jwt::create_jwt(
+    let mut jwt = jwt::create_jwt(
         &credentials,
         Some(scopes),
         chrono::Duration::minutes(5),
         Some(request.client_id.clone()),
         None, &credentials.client_email,
     )?;
-
-    let unsigned = encrypt_unsigned_jwt_token(&SECRET, jwt)?;
+    jwt.payload_mut()?.private.code_challenge = request.code_challenge.clone();
+    jwt.payload_mut()?.private.nonce = request.nonce.clone();
+    jwt.payload_mut()?.private.include_granted_scopes = request.include_granted_scopes.filter(|f| *f);
+    jwt.payload_mut()?.private.redirect_uri = request.redirect_uri.clone().filter(|_| redirect_uri.is_some());
+
+    let unsigned = encrypt_unsigned_jwt_token(&unsigned_token_key.key, &request.client_id, jwt.clone())?;
+
+    if let (true, Some(user_id), Some(redirect_uri)) = (consented, user_id, &redirect_uri) {
+        let code = hash_of_token(&unsigned.as_bytes());
+        issue_authorization_code(&credentials, authorization_codes, store, identities, auditor, user_id, jwt, &code)?;
+        return Ok(RedirectOrResponseAuthorize::ToClient(
+            Redirect::to(authorize_code_uri(redirect_uri, &code, request.state.as_ref()))));
+    }
 
     let message = AuthPageRedirectUri {
         client_id: request.client_id.clone(),
         client_secret: request.client_secret.clone(),
         client_name: request.client_name.clone(),
-        redirect_uri: request.redirect_uri.clone(),
+        redirect_uri: redirect_uri.clone(),
         response_type: request.response_type.clone(),
         scope: request.scope.as_ref().and_then(|f| Some(f.trim().to_owned())),
         state: request.state.clone(),
+        prompt: prompt.for_ui(login_required && user_id.is_some()),
         code: hash_of_token(&unsigned.as_bytes()),
         unsigned,
     };
-    let uri = format!(
-        "https://openhabx.com/oauth?{}",
-        &message as &dyn UriDisplay<Query>
-    );
-
     match &request.response_type[..] {
         "code" => {
+            let uri = format!(
+                "https://openhabx.com/oauth?{}",
+                &message as &dyn UriDisplay<Query>
+            );
             return Ok(RedirectOrResponseAuthorize::ToOhxLoginPage(Redirect::to(uri)));
         }
         "device" => {
-            Ok(RedirectOrResponseAuthorize::Json(content::Json(
-                serde_json::to_string(&DeviceFlowResponse {
-                    user_code: String::new(),
-                    device_code: message.code,
-                    verification_uri: uri,
-                    interval: 2,
-                    expires_in: 360,
-                })?,
-            )))
+            Ok(RedirectOrResponseAuthorize::Json(create_device_authorization(tokens, message)?))
         }
         _ => Err(MyResponder::bad_request("invalid response_type")),
     }
 }
 
+/// The device flow verification page. The user enters the user code there.
+const DEVICE_VERIFICATION_URI: &str = "https://openhabx.com/device";
+/// Minimum amount of seconds between two device flow token requests
+const DEVICE_FLOW_INTERVAL: u32 = 5;
+/// Device and user codes expire after this amount of seconds
+const DEVICE_FLOW_EXPIRES_IN: u32 = 360;
+
+/// The token store key of a device flow user code. The value is the authorization request in json.
+fn user_code_key(user_code: &str) -> String {
+    format!("user_code.{}", normalize_user_code(user_code))
+}
+
+/// The token store key of a pending device authorization. It exists until the device code expires
+/// or has been redeemed.
+fn device_pending_key(device_code: &str) -> String {
+    format!("device_pending.{}", device_code)
+}
+
+/// The token store key that exists for the interval time after each device flow token request
+fn device_poll_key(device_code: &str) -> String {
+    format!("device_poll.{}", device_code)
+}
+
+/// Device flow (RFC 8628): Stores the authorization request under a newly generated, short user code
+/// and returns the device authorization response.
+fn create_device_authorization(tokens: &dyn TokenStore, message: AuthPageRedirectUri) -> Result<content::Json<String>, MyResponder> {
+    let value = serde_json::to_string(&message)?;
+
+    // A user code is short and might collide with an active one
+    let mut user_code = None;
+    for _ in 0..5 {
+        let candidate = generate_user_code()?;
+        if tokens.set_if_absent(&user_code_key(&candidate), &value, DEVICE_FLOW_EXPIRES_IN as usize)? {
+            user_code = Some(candidate);
+            break;
+        }
+    }
+    let user_code = user_code.ok_or(MyResponder::internal_error("Could not generate a user code"))?;
+    tokens.set(&device_pending_key(&message.code), "1", DEVICE_FLOW_EXPIRES_IN as usize)?;
+
+    Ok(content::Json(serde_json::to_string(&DeviceFlowResponse {
+        verification_uri_complete: format!("{}?user_code={}", DEVICE_VERIFICATION_URI, &user_code),
+        user_code,
+        device_code: message.code,
+        verification_uri: DEVICE_VERIFICATION_URI.to_owned(),
+        interval: DEVICE_FLOW_INTERVAL,
+        expires_in: DEVICE_FLOW_EXPIRES_IN,
+    })?))
+}
+
+/// Device authorization endpoint (RFC 8628, 3.1). Same as /authorize with response_type=device.
+///
+/// Returns a device_code for the token endpoint and a short user_code that the user enters on the verification page.
+#[post("/device_authorization", data = "<request>")]
+pub fn device_authorization(
+    request: DeviceAuthorizationRequest,
+    identities: rocket::State<Arc<dyn IdentityProvider>>,
+    tokens: rocket::State<Arc<dyn TokenStore>>,
+    authorization_codes: rocket::State<AuthorizationCodeStore>,
+    credentials_list: rocket::State<Vec<Credentials>>,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    oauth_clients: rocket::State<OAuthClientStore>,
+    unsigned_token_key: rocket::State<UnsignedTokenKey>,
+    auditor: Auditor,
+    _rate_limiter: RateLimiter,
+) -> Result<RedirectOrResponseAuthorize, MyResponder> {
+    let request = request.into_inner();
+    let request = GenerateCodeDTO {
+        client_id: request.client_id,
+        client_secret: request.client_secret,
+        client_name: request.client_name,
+        redirect_uri: None,
+        response_type: "device".to_owned(),
+        scope: request.scope,
+        state: None,
+        code_challenge: None,
+        code_challenge_method: None,
+        nonce: None,
+        prompt: None,
+        max_age: None,
+        include_granted_scopes: None,
+    };
+    create_authorization(&request, None, &identities, &tokens, &authorization_codes, &credentials_list, &store, &oauth_clients, &unsigned_token_key, &auditor)
+}
+
+/// Resolves a device flow user code to the authorization request (client_id, scope, unsigned, code).
+/// Called by the verification page, which then calls /grant_scopes.
+#[get("/device?<user_code>")]
+pub fn device_user_code(
+    user_code: String,
+    tokens: rocket::State<Arc<dyn TokenStore>>,
+    _user: UserSessionGuard,
+    _rate_limiter: RateLimiter,
+) -> Result<content::Json<String>, MyResponder> {
+    let message = tokens.get(&user_code_key(&user_code))?;
+    match message {
+        Some(message) => Ok(content::Json(message)),
+        None => Err(MyResponder::bad_request("invalid_user_code"))
+    }
+}
+
+/// Token revocation (RFC 7009). The client must authenticate itself and can only revoke
+/// tokens that were issued to it.
+///
+/// A refresh token is removed from the database. A still valid access token is added to the
+/// deny-list until it expires. The "token_type_hint" is not required, both token types are checked.
+/// An unknown or invalid token is not an error and a 200 is returned.
+/// Store failures are logged and audited, a 200 is returned nevertheless.
+#[post("/revoke", data = "<request>")]
+pub fn revoke(
+    request: RevokeRequest,
+    deny_list: rocket::State<TokenDenyList>,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    credentials_list: rocket::State<Vec<Credentials>>,
+    oauth_clients: rocket::State<OAuthClientStore>,
+    auditor: Auditor,
+    _rate_limiter: RateLimiter,
+) -> Result<(), MyResponder> {
+    // Refresh token
+    let code = hash_of_token(request.token.as_bytes());
+    let client = oauth_clients.get(&store, &request.client_id);
+    let client = match client {
+        Some(client) if client.authenticate(request.client_secret.as_ref().map(|f| f.as_str())) => client,
+        _ => {
+            auditor.record(AuditEvent::failure("token.revoke").with_client(Some(&request.client_id)));
+            return Err(MyResponder::AccessScopeInsufficient("invalid_client".to_owned()));
+        }
+    };
+    // Store failures are logged and audited, but do not change the response (RFC 7009, 2.2)
+    let db_entry: Option<db::AccessTokenInDB> = match store.read("access_tokens", &code) {
+        Ok(db_entry) => db_entry,
+        Err(e) => {
+            error!("Failed to read refresh token for revocation: {:?}", e);
+            auditor.record(AuditEvent::failure("token.revoke").with_client(Some(&client.id)));
+            None
+        }
+    };
+    if let Some(db_entry) = db_entry {
+        if db_entry.client_id == client.id {
+            let result = match db_entry.family_id.is_empty() {
+                true => store.delete("access_tokens", &code).map_err(MyResponder::from),
+                false => revoke_token_family(&store, &deny_list, &db_entry.family_id)
+            };
+            let event = match result {
+                Ok(()) => AuditEvent::success("token.revoke"),
+                Err(e) => {
+                    error!("Failed to revoke refresh token of client {}: {:?}", &client.id, e);
+                    AuditEvent::failure("token.revoke")
+                }
+            };
+            auditor.record(event.with_user(Some(&db_entry.uid)).with_client(Some(&client.id)));
+        }
+    }
+
+    // Access token
+    let credentials = ohx_credentials(&credentials_list)?;
+    if let Ok(Some(token_result)) = jwt::verify_access_token(&credentials, &request.token) {
+        if token_result.claims.client_id.as_ref() == Some(&request.client_id) {
+            if let (Some(jti), Some(expiry)) = (token_result.jti, token_result.expiry) {
+                if let Err(e) = deny_list.deny(&jti, expiry) {
+                    error!("Failed to deny-list access token of client {}: {:?}", &client.id, e);
+                    auditor.record(AuditEvent::failure("token.revoke")
+                        .with_user(token_result.claims.uid.as_ref()).with_client(Some(&client.id)).with_target(jti));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes all refresh tokens of the given family and adds the access tokens that were issued
+/// together with them to the deny-list.
+/// Tokens issued before the rotation was introduced use their hash as family id.
+fn revoke_token_family(store: &dyn DocumentStore, deny_list: &TokenDenyList, family_id: &str) -> Result<(), MyResponder> {
+    let mut family: Vec<(String, db::AccessTokenInDB)> = store.query("access_tokens", "family_id", QueryOperator::Equal, family_id)?;
+    if !family.iter().any(|(code, _)| code == family_id) {
+        if let Some(db_entry) = store.read("access_tokens", family_id)? {
+            family.push((family_id.to_owned(), db_entry));
+        }
+    }
+    for (code, db_entry) in family {
+        if let (Some(jti), Some(expiry)) = (db_entry.access_token_id, db_entry.access_token_expiry) {
+            deny_list.deny(&jti, expiry)?;
+        }
+        store.delete("access_tokens", &code)?;
+    }
+    Ok(())
+}
+
+/// Marks all refresh tokens of the given family as superseded. A later use of one of them revokes the family.
+fn supersede_token_family(store: &dyn DocumentStore, family_id: &str, now: i64) -> Result<(), MyResponder> {
+    let mut family: Vec<(String, db::AccessTokenInDB)> = store.query("access_tokens", "family_id", QueryOperator::Equal, family_id)?;
+    if !family.iter().any(|(code, _)| code == family_id) {
+        if let Some(db_entry) = store.read("access_tokens", family_id)? {
+            family.push((family_id.to_owned(), db_entry));
+        }
+    }
+    for (code, mut db_entry) in family {
+        if db_entry.superseded_at.is_some() {
+            continue;
+        }
+        // Tokens issued before rotation was introduced are their own family
+        db_entry.family_id = family_id.to_owned();
+        db_entry.superseded_at = Some(now);
+        store.write("access_tokens", &code, &db_entry)?;
+    }
+    Ok(())
+}
+
+/// The current refresh token family of the given user and client. The most recent one if there are several.
+fn current_token_family(store: &dyn DocumentStore, uid: &str, client_id: &str) -> Result<Option<(String, BTreeSet<String>)>, MyResponder> {
+    let families = read_user_token_families(store, uid)?;
+    Ok(families.into_iter()
+        .filter_map(|(family_id, tokens)| tokens.into_iter()
+            .find(|db_entry| db_entry.superseded_at.is_none() && db_entry.client_id == client_id)
+            .map(|current| (family_id, current)))
+        .max_by_key(|(_, current)| current.issued_at)
+        .map(|(family_id, current)| (family_id, current.scopes)))
+}
+
+/// Reads all refresh tokens of the given user, grouped by token family id
+fn read_user_token_families(store: &dyn DocumentStore, uid: &str) -> Result<BTreeMap<String, Vec<db::AccessTokenInDB>>, MyResponder> {
+    let result: Vec<(String, db::AccessTokenInDB)> = store.query("access_tokens", "uid", QueryOperator::Equal, uid)?;
+    let mut families: BTreeMap<String, Vec<db::AccessTokenInDB>> = BTreeMap::new();
+    for (code, db_entry) in result {
+        let family_id = match db_entry.family_id.is_empty() {
+            true => code,
+            false => db_entry.family_id.clone()
+        };
+        families.entry(family_id).or_default().push(db_entry);
+    }
+    Ok(families)
+}
+
+/// The unique id (jti) and expiry of an issued access token
+fn access_token_id_and_expiry(credentials: &Credentials, access_token: &str) -> (Option<String>, Option<i64>) {
+    match jwt::verify_access_token(credentials, access_token) {
+        Ok(Some(token_result)) => (token_result.jti, token_result.expiry),
+        _ => (None, None)
+    }
+}
+
+/// Lists the sessions (refresh token families) of the authenticated user
+#[get("/sessions")]
+pub fn list_sessions(
+    user: UserSessionGuard,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    oauth_clients: rocket::State<OAuthClientStore>,
+    _rate_limiter: RateLimiter,
+) -> Result<content::Json<String>, MyResponder> {
+    let families = read_user_token_families(&store, &user.user_id)?;
+    let mut sessions = Vec::with_capacity(families.len());
+    for (family_id, tokens) in families {
+        // The current refresh token of a family is the one that is not superseded
+        let current = match tokens.iter().find(|db_entry| db_entry.superseded_at.is_none()) {
+            Some(current) => current,
+            None => continue
+        };
+        let client = oauth_clients.get(&store, &current.client_id);
+        sessions.push(SessionDTO {
+            id: family_id,
+            client_id: current.client_id.clone(),
+            client_name: client.as_ref().map(|c| c.title.clone()).unwrap_or_else(|| current.client_id.clone()),
+            logo_uri: client.map(|c| c.logo_url).unwrap_or_default(),
+            scope: current.scopes.clone(),
+            created_at: tokens.iter().map(|db_entry| db_entry.issued_at).min().unwrap_or(current.issued_at),
+            last_used_at: current.last_used(),
+            last_ip: current.last_ip.clone(),
+            use_count: current.use_count,
+        });
+    }
+    sessions.sort_by(|a, b| b.last_used_at.cmp(&a.last_used_at));
+    Ok(content::Json(serde_json::to_string(&sessions)?))
+}
+
+#[get("/sessions", rank = 2)]
+pub fn list_sessions_unauthorized() -> MyResponder {
+    MyResponder::AccessScopeInsufficient("Requires authorization".to_owned())
+}
+
+/// Revokes a session of the authenticated user. The refresh tokens are removed and the
+/// last issued access token is deny-listed.
+#[delete("/sessions/<session_id>")]
+pub fn revoke_session(
+    session_id: String,
+    user: UserSessionGuard,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    deny_list: rocket::State<TokenDenyList>,
+    auditor: Auditor,
+    _rate_limiter: RateLimiter,
+) -> Result<(), MyResponder> {
+    let families = read_user_token_families(&store, &user.user_id)?;
+    if !families.contains_key(&session_id) {
+        return Err(MyResponder::NotFound(String::new()));
+    }
+    revoke_token_family(&store, &deny_list, &session_id)?;
+    auditor.record(AuditEvent::success("session.revoke").with_user(Some(&user.user_id)).with_target(session_id));
+    Ok(())
+}
+
+/// Revokes all sessions of the authenticated user
+#[delete("/sessions")]
+pub fn revoke_all_sessions(
+    user: UserSessionGuard,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    deny_list: rocket::State<TokenDenyList>,
+    auditor: Auditor,
+    _rate_limiter: RateLimiter,
+) -> Result<(), MyResponder> {
+    let families = read_user_token_families(&store, &user.user_id)?;
+    for family_id in families.keys() {
+        revoke_token_family(&store, &deny_list, family_id)?;
+        auditor.record(AuditEvent::success("session.revoke").with_user(Some(&user.user_id)).with_target(family_id.as_str()));
+    }
+    Ok(())
+}
+
+/// Lists the consents of the authenticated user
+#[get("/consents")]
+pub fn list_consents(
+    user: UserSessionGuard,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    oauth_clients: rocket::State<OAuthClientStore>,
+    _rate_limiter: RateLimiter,
+) -> Result<content::Json<String>, MyResponder> {
+    let result: Vec<(String, db::ConsentInDB)> = store.query("consents", "uid", QueryOperator::Equal, user.user_id.as_str())?;
+    let mut consents: Vec<ConsentDTO> = result.into_iter().map(|(_, consent)| {
+        let client = oauth_clients.get(&store, &consent.client_id);
+        ConsentDTO {
+            client_name: client.as_ref().map(|c| c.title.clone()).unwrap_or_else(|| consent.client_id.clone()),
+            logo_uri: client.map(|c| c.logo_url).unwrap_or_default(),
+            client_id: consent.client_id,
+            scope: consent.scopes,
+            granted_at: consent.granted_at,
+        }
+    }).collect();
+    consents.sort_by(|a, b| b.granted_at.cmp(&a.granted_at));
+    Ok(content::Json(serde_json::to_string(&consents)?))
+}
+
+#[get("/consents", rank = 2)]
+pub fn list_consents_unauthorized() -> MyResponder {
+    MyResponder::AccessScopeInsufficient("Requires authorization".to_owned())
+}
+
+/// Lists the most recent audit events of the authenticated user, newest first
+#[get("/audit_events")]
+pub fn list_audit_events(
+    user: UserSessionGuard,
+    auditor: Auditor,
+    _rate_limiter: RateLimiter,
+) -> Result<content::Json<String>, MyResponder> {
+    let events = auditor.log().query_user(&user.user_id, AUDIT_EVENTS_LIMIT)?;
+    Ok(content::Json(serde_json::to_string(&events)?))
+}
+
+#[get("/audit_events", rank = 2)]
+pub fn list_audit_events_unauthorized() -> MyResponder {
+    MyResponder::AccessScopeInsufficient("Requires authorization".to_owned())
+}
+
+/// Withdraws the consent of the authenticated user for a client. The next authorization request of
+/// that client shows the consent screen again. Existing sessions are not revoked.
+#[delete("/consents/<client_id>")]
+pub fn revoke_consent(
+    client_id: String,
+    user: UserSessionGuard,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    auditor: Auditor,
+    _rate_limiter: RateLimiter,
+) -> Result<(), MyResponder> {
+    let id = db::ConsentInDB::id(&user.user_id, &client_id);
+    if store.read::<db::ConsentInDB>("consents", &id)?.is_none() {
+        return Err(MyResponder::NotFound(String::new()));
+    }
+    store.delete("consents", &id)?;
+    auditor.record(AuditEvent::success("consent.revoke").with_user(Some(&user.user_id)).with_target(client_id));
+    Ok(())
+}
+
+/// Token introspection (RFC 7662) for resource servers. Only registered confidential clients are allowed.
+///
+/// A refresh token is active if it is stored in the database (not revoked), not superseded and not expired
+/// due to inactivity. Like the refresh_token grant, this does not depend on the signature, the signing key
+/// might already be retired. An access token is active if its signature and expiry are valid and it is
+/// not on the deny-list.
+#[post("/introspect", data = "<request>")]
+pub fn introspect(
+    request: IntrospectRequest,
+    deny_list: rocket::State<TokenDenyList>,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    credentials_list: rocket::State<Vec<Credentials>>,
+    oauth_clients: rocket::State<OAuthClientStore>,
+    refresh_token_policy: rocket::State<RefreshTokenPolicy>,
+    client_auth: Option<ClientBasicAuth>,
+    _rate_limiter: RateLimiter,
+) -> Result<content::Json<String>, MyResponder> {
+    // The client authenticates with HTTP Basic or with the form fields
+    let (client_id, client_secret) = match (&client_auth, &request.client_id) {
+        (Some(client_auth), _) => (&client_auth.client_id, Some(client_auth.client_secret.as_str())),
+        (None, Some(client_id)) => (client_id, request.client_secret.as_ref().map(|f| f.as_str())),
+        (None, None) => return Err(MyResponder::AccessScopeInsufficient("invalid_client".to_owned()))
+    };
+    match oauth_clients.get(&store, client_id) {
+        Some(ref client) if client.is_confidential() && client.authenticate(client_secret) => {}
+        _ => return Err(MyResponder::AccessScopeInsufficient("invalid_client".to_owned()))
+    };
+
+    let credentials = ohx_credentials(&credentials_list)?;
+
+    let inactive = || -> Result<content::Json<String>, MyResponder> {
+        Ok(content::Json(serde_json::to_string(&IntrospectionResponse::default())?))
+    };
+
+    // Refresh tokens are looked up by their hash
+    let code = hash_of_token(request.token.as_bytes());
+    match store.read::<db::AccessTokenInDB>("access_tokens", &code) {
+        Ok(Some(db_entry)) => {
+            if db_entry.superseded_at.is_some() || refresh_token_policy.is_inactive(&db_entry, chrono::Utc::now().timestamp()) {
+                return inactive();
+            }
+            let response = IntrospectionResponse {
+                active: true,
+                exp: refresh_token_policy.expires_at(&db_entry),
+                scope: db_entry.scopes,
+                client_id: Some(db_entry.client_id),
+                uid: Some(db_entry.uid),
+                jti: None,
+            };
+            return Ok(content::Json(serde_json::to_string(&response)?));
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read refresh token for introspection: {:?}", e)
+    }
+
+    let token_result = match jwt::verify_access_token(&credentials, &request.token) {
+        Ok(Some(token_result)) => token_result,
+        _ => return inactive()
+    };
+
+    // Fail closed: A token is reported as inactive if the deny-list cannot be consulted
+    if let Some(jti) = token_result.jti.as_ref() {
+        match deny_list.is_denied(jti) {
+            Ok(false) => {}
+            _ => return inactive()
+        }
+    }
+
+    // A refresh token that is not (or could not be read from) the database is revoked
+    if token_result.claims.scope.contains(SCOPE_OFFLINE_ACCESS) {
+        return inactive();
+    }
+
+    let response = IntrospectionResponse {
+        active: true,
+        scope: token_result.claims.scope,
+        client_id: token_result.claims.client_id,
+        uid: token_result.claims.uid,
+        exp: token_result.expiry,
+        jti: token_result.jti,
+    };
+    Ok(content::Json(serde_json::to_string(&response)?))
+}
+
+/// Management endpoints like the client registration require the "admin" scope
+fn is_admin(oauth_user: &guard_oauth_jwt_access::OAuthIdentity) -> bool {
+    oauth_user.scopes.contains(SCOPE_ADMIN)
+}
+
+/// The scopes that can be registered for a client: The "scopes_supported" of the discovery document and "offline_access".
+/// The "admin" scope is never registered. Clients with the admin scope are configured ([`crate::OAUTH_CLIENTS_FILE`]).
+fn registrable_scopes() -> Result<BTreeSet<String>, MyResponder> {
+    let mut scopes = serde_json::from_str::<OpenIdConfiguration>(OPENID_CONFIG)?.scopes_supported;
+    scopes.insert(SCOPE_OFFLINE_ACCESS.to_owned());
+    scopes.remove(SCOPE_ADMIN);
+    Ok(scopes)
+}
+
+/// Checks the registration metadata and applies it to the given client
+fn apply_client_registration(client: &mut OAuthClient, registration: ClientRegistrationDTO) -> Result<(), MyResponder> {
+    if registration.client_name.is_empty() {
+        return Err(MyResponder::bad_request("invalid_client_metadata"));
+    }
+    if !registration.scope.is_subset(&registrable_scopes()?) {
+        return Err(MyResponder::bad_request("invalid_client_metadata"));
+    }
+    for redirect_uri in registration.redirect_uris.iter() {
+        match url::Url::parse(redirect_uri) {
+            Ok(ref uri) if uri.scheme() == "https" || uri.host_str() == Some("localhost") => {}
+            _ => return Err(MyResponder::bad_request("invalid_redirect_uri"))
+        }
+    }
+    match registration.token_endpoint_auth_method.as_ref().map(|f| f.as_str()) {
+        Some("none") => {
+            client.secret = None;
+            client.secret_hash = None;
+        }
+        None | Some("client_secret_post") => {
+            if !client.is_confidential() {
+                client.set_secret(hash_of_token(uuid::Uuid::new_v4().as_bytes()));
+            }
+        }
+        _ => return Err(MyResponder::bad_request("invalid_client_metadata"))
+    }
+    client.apply_registration(registration);
+    Ok(())
+}
+
+/// Dynamic client registration (RFC 7591). The client is persisted and immediately available.
+/// The response contains the generated client secret for confidential clients.
+#[post("/register", format = "application/json", data = "<registration>")]
+pub fn register_client(
+    registration: Json<ClientRegistrationDTO>,
+    oauth_user: guard_oauth_jwt_access::OAuthIdentity,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    oauth_clients: rocket::State<OAuthClientStore>,
+    auditor: Auditor,
+) -> Result<status::Created<content::Json<String>>, MyResponder> {
+    if !is_admin(&oauth_user) {
+        return Err(MyResponder::AccessScopeInsufficient("Requires the admin scope".to_owned()));
+    }
+    let registration = registration.into_inner();
+
+    let client_id = registration.client_id.clone().unwrap_or_else(|| uuid::Uuid::new_v4().to_simple().to_string());
+    if oauth_clients.get(&store, &client_id).is_some() {
+        return Err(MyResponder::bad_request("invalid_client_metadata"));
+    }
+
+    let mut client = OAuthClient {
+        id: client_id,
+        issued_at: Some(chrono::Utc::now().timestamp()),
+        ..Default::default()
+    };
+    apply_client_registration(&mut client, registration)?;
+    oauth_clients.store(&store, client.clone())?;
+    auditor.record(AuditEvent::success("client.register")
+        .with_user(oauth_user.user_id.as_ref()).with_client(oauth_user.client_id.as_ref()).with_target(client.id.as_str()));
+
+    let response = serde_json::to_string(&client.registration_response(true))?;
+    Ok(status::Created(format!("/clients/{}", &client.id), Some(content::Json(response))))
+}
+
+#[post("/register", rank = 2)]
+pub fn register_client_unauthorized() -> MyResponder {
+    MyResponder::AccessScopeInsufficient("Requires authorization".to_owned())
+}
+
+/// Lists all registered clients. Secrets are not included.
+#[get("/clients")]
+pub fn list_clients(
+    oauth_user: guard_oauth_jwt_access::OAuthIdentity,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    oauth_clients: rocket::State<OAuthClientStore>,
+) -> Result<content::Json<String>, MyResponder> {
+    if !is_admin(&oauth_user) {
+        return Err(MyResponder::AccessScopeInsufficient("Requires the admin scope".to_owned()));
+    }
+    let clients = oauth_clients.list(&store)?;
+    let clients: Vec<_> = clients.iter().map(|client| client.registration_response(false)).collect();
+    Ok(content::Json(serde_json::to_string(&clients)?))
+}
+
+/// Replaces the metadata of a registered client. The client secret is kept,
+/// except if the client becomes a public client ("token_endpoint_auth_method": "none").
+#[put("/clients/<client_id>", format = "application/json", data = "<registration>")]
+pub fn update_client(
+    client_id: String,
+    registration: Json<ClientRegistrationDTO>,
+    oauth_user: guard_oauth_jwt_access::OAuthIdentity,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    oauth_clients: rocket::State<OAuthClientStore>,
+    auditor: Auditor,
+) -> Result<content::Json<String>, MyResponder> {
+    if !is_admin(&oauth_user) {
+        return Err(MyResponder::AccessScopeInsufficient("Requires the admin scope".to_owned()));
+    }
+    let mut client = oauth_clients.get(&store, &client_id).ok_or(MyResponder::NotFound(String::new()))?;
+    let had_secret = client.is_confidential();
+    apply_client_registration(&mut client, registration.into_inner())?;
+    oauth_clients.store(&store, client.clone())?;
+    auditor.record(AuditEvent::success("client.update")
+        .with_user(oauth_user.user_id.as_ref()).with_client(oauth_user.client_id.as_ref()).with_target(client_id));
+
+    // A newly generated secret must be returned
+    let with_secret = !had_secret && client.is_confidential();
+    Ok(content::Json(serde_json::to_string(&client.registration_response(with_secret))?))
+}
+
+/// Removes a registered client. Already issued tokens stay valid until they expire or are revoked.
+#[delete("/clients/<client_id>")]
+pub fn delete_client(
+    client_id: String,
+    oauth_user: guard_oauth_jwt_access::OAuthIdentity,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    oauth_clients: rocket::State<OAuthClientStore>,
+    auditor: Auditor,
+) -> Result<(), MyResponder> {
+    if !is_admin(&oauth_user) {
+        return Err(MyResponder::AccessScopeInsufficient("Requires the admin scope".to_owned()));
+    }
+    if oauth_clients.get(&store, &client_id).is_none() {
+        return Err(MyResponder::NotFound(String::new()));
+    }
+    oauth_clients.remove(&store, &client_id)?;
+    auditor.record(AuditEvent::success("client.delete")
+        .with_user(oauth_user.user_id.as_ref()).with_client(oauth_user.client_id.as_ref()).with_target(client_id));
+    Ok(())
+}
+
 /// This is a rate limited endpoint to revoke an auth token
 #[get("/revoke?<token>", rank = 2)]
 pub fn revoke_by_oauth(
     token: &RawStr,
-    firebase: rocket::State<Mutex<SASession>>,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    deny_list: rocket::State<TokenDenyList>,
     oauth_user: guard_oauth_jwt_access::OAuthIdentity,
-    _rate_limiter: RateLimiter,
+    auditor: Auditor,
+    _rate_limiter: IdentityRateLimiter,
 ) -> Result<(), MyResponder> {
-    // Only the google account is allowed to call this endpoint
-    if oauth_user.credentials_index != CREDENTIALS_GOOGLE_SERVICE_ACCOUNT_INDEX {
+    // Only service accounts (client credentials grant) with the admin scope are allowed to call this endpoint
+    if !oauth_user.scopes.contains(SCOPE_ADMIN) {
         return Err(MyResponder::AccessScopeInsufficient(
-            "Only the google CI account is allowed to call this endpoint".to_owned(),
+            "Requires the admin scope".to_owned(),
         ));
     }
 
-    let session_mutex = firebase.lock()?;
     let code = hash_of_token(token.as_str().as_bytes());
-    firestore_db_and_auth::documents::delete(
-        session_mutex.deref(),
-        &format!("access_tokens/{}", code),
-        false,
-    )?;
+    let db_entry: Option<db::AccessTokenInDB> = store.read("access_tokens", &code)?;
+    match db_entry {
+        Some(ref db_entry) if !db_entry.family_id.is_empty() => revoke_token_family(&store, &deny_list, &db_entry.family_id)?,
+        _ => revoke_token_family(&store, &deny_list, &code)?
+    }
+    auditor.record(AuditEvent::success("token.revoke")
+        .with_user(db_entry.as_ref().map(|db_entry| &db_entry.uid))
+        .with_client(oauth_user.client_id.as_ref()));
 
     Ok(())
 }
@@ -427,8 +1639,8 @@ pub fn revoke_by_oauth(
 pub fn user_info(
     user_id: Option<String>,
     oauth_user: guard_oauth_jwt_access::OAuthIdentity,
-    firebase: rocket::State<Mutex<SASession>>,
-    _rate_limiter: RateLimiter,
+    identities: rocket::State<Arc<dyn IdentityProvider>>,
+    _rate_limiter: IdentityRateLimiter,
 ) -> Result<content::Json<String>, MyResponder> {
     if !oauth_user.scopes.contains("profile") {
         return Err(MyResponder::AccessScopeInsufficient(
@@ -444,49 +1656,26 @@ pub fn user_info(
 
     let user_id = user_id.unwrap();
 
-    let session_mutex = firebase.lock()?;
-    let session: &SASession = session_mutex.deref();
-
-    let user_session = firestore_db_and_auth::UserSession::by_user_id(
-        &session.credentials,
-        &user_id,
-        false,
-    )?;
-
-    let info = firestore_db_and_auth::users::user_info(&user_session)?;
-    if !info.users.len() == 1 {
-        return Err(MyResponder::NotFound("User info not found".to_owned()));
+    match identities.user_info(&user_id)? {
+        Some(user_info) => Ok(content::Json(serde_json::to_string(&user_info)?)),
+        None => Err(MyResponder::NotFound("User not found!".into()))
     }
-    if let Some(user_info) = info.users.iter().next() {
-        return Ok(content::Json(serde_json::to_string(&user_info)?));
-    }
-    Err(MyResponder::NotFound("User not found!".into()))
 }
 
 /// This is a rate limited endpoint to poll for an auth token
 #[get("/list_intermediate_tokens")]
 pub fn list_intermediate_tokens(
-    redis: rocket::State<redis::Client>,
+    authorization_codes: rocket::State<AuthorizationCodeStore>,
     oauth_user: guard_oauth_jwt_access::OAuthIdentity,
-    _rate_limiter: RateLimiter,
+    _rate_limiter: IdentityRateLimiter,
 ) -> Result<content::Json<String>, MyResponder> {
-    // Only the google account is allowed to call this endpoint
-    if oauth_user.credentials_index != CREDENTIALS_GOOGLE_SERVICE_ACCOUNT_INDEX {
+    // Only service accounts (client credentials grant) with the admin scope are allowed to call this endpoint
+    if !oauth_user.scopes.contains(SCOPE_ADMIN) {
         return Err(MyResponder::AccessScopeInsufficient(
-            "Only the google CI account is allowed to call this endpoint".to_owned(),
+            "Requires the admin scope".to_owned(),
         ));
     }
 
-    let mut redis_connection = redis.get_connection()?;
-    redis_connection.set_ex("check", "write", 20)?;
-    let k: Vec<String> = redis_connection.keys("*").unwrap();
-    let mut map: HashMap<String, String> = HashMap::new();
-    for key in k {
-        let v: Option<String> = redis_connection.get(&key).unwrap();
-        if !v.is_some() {
-            continue;
-        }
-        map.insert(key.to_owned(), v.unwrap());
-    }
+    let map: HashMap<String, AuthorizationGrant> = authorization_codes.list()?.into_iter().collect();
     Ok(content::Json(serde_json::to_string(&map)?))
 }
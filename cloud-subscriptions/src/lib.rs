@@ -8,51 +8,75 @@ pub mod routes;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
-use rocket::{catchers, config::Environment, routes, Config};
+use rocket::{catchers, config::Environment, http::Method, routes, Config};
 use std::env;
-use std::sync::Mutex;
+use std::sync::Arc;
 
 use cloud_vault::{
-    credentials::Credentials, error_routes, guard_rate_limiter,
+    error_routes, guard_rate_limiter,
 };
+use cloud_auth_lib::config::ConfigSource;
+use cloud_auth_lib::deny_list::TokenDenyList;
+use cloud_auth_lib::fairing_cors::{CorsFairing, CorsPolicy, OHX_ORIGINS};
+use cloud_auth_lib::guard_ip_addr::TrustedProxies;
+use cloud_auth_lib::rate_limit::{MemoryRateLimitBackend, RateLimits};
+use cloud_auth_lib::storage::{DocumentStore, FirestoreDocumentStore, RedisTokenStore, TokenStore};
 use firestore_db_and_auth::{
     credentials::Credentials as DBCredentials, sessions::service_account::Session as SASession,
 };
 use routes::*;
 
+// The names of the keys in the runtime configuration (secrets directory or environment)
+pub const FIREBASE_CREDENTIALS: &'static str = "firebase-account.json";
+pub const GOOGLE_SERVICE_ACCOUNT_OHX: &'static str = "openhabx-device@openhabx.iam.gserviceaccount.com.json";
+pub const GOOGLE_SERVICE_ACCOUNT_ST: &'static str = "securetoken@system.gserviceaccount.com.json";
+pub const OHX_ADMIN_ACCOUNT: &'static str = "ohx_admin_account.json";
+pub const OHX_AUTH_JWKS: &'static str = "ohx_oauth_key.json";
+pub const BRAINTREE_CREDENTIALS: &'static str = "braintree.json";
 
-pub fn create_rocket(rate_limit: u32) -> Result<rocket::Rocket, failure::Error> {
-    // Rate limit
-    let lim = guard_rate_limiter::RateLimiterMutex::new(rate_limit);
+fn firebase_credentials(config_source: &ConfigSource) -> Result<DBCredentials, failure::Error> {
+    Ok(DBCredentials::new(
+        &config_source.get(FIREBASE_CREDENTIALS)?,
+        &[&config_source.get(GOOGLE_SERVICE_ACCOUNT_OHX)?, &config_source.get(GOOGLE_SERVICE_ACCOUNT_ST)?],
+    )?)
+}
 
-    let (google_credentials, _g_access_token, _g_scopes) = Credentials::load_and_check(
-        include_str!("../secrets/google-ci-key.json"),
-        &[
-            include_str!("../secrets/securetoken@system.gserviceaccount.com.json"),
-            include_str!("../secrets/travisci-deployer@openhabx.iam.gserviceaccount.com.json"),
-        ],
-        None::<&[&str]>,
-    )?;
+/// The production stores: Firestore for documents and the Redis instance of the auth service for the token deny-list
+pub fn google_stores(config_source: &ConfigSource) -> Result<(Arc<dyn DocumentStore>, Arc<dyn TokenStore>), failure::Error> {
+    let documents = FirestoreDocumentStore::new(SASession::new(firebase_credentials(config_source)?)?);
+    let tokens = RedisTokenStore::from_config(config_source)?;
+    Ok((Arc::new(documents), Arc::new(tokens)))
+}
 
-    let (openhabx_credentials, _ohx_access_token, _ohx_scopes) = Credentials::load_and_check(
-        include_str!("../secrets/ohx_admin_account.json"),
-        &[include_str!("../secrets/ohx_oauth_key.json")],
+/// Creates the rocket instance. User entries are persisted in the given document store.
+/// Revoked access tokens are looked up in the given token store.
+pub fn create_rocket(rate_limit: u32, config_source: &ConfigSource, documents: Arc<dyn DocumentStore>, tokens: Arc<dyn TokenStore>)
+                     -> Result<rocket::Rocket, failure::Error> {
+    // Rate limit. Allows `rate_limit` requests per second and client ip, if not configured otherwise.
+    let lim = guard_rate_limiter::RateLimiterState::with_backend(
+        Arc::new(MemoryRateLimitBackend::new()), RateLimits::from_config(config_source, rate_limit)?);
+
+    // Only tokens of the auth service are accepted. Service accounts use its client credentials grant.
+    let (openhabx_credentials, _ohx_access_token, _ohx_scopes) = config_source.credentials(
+        OHX_ADMIN_ACCOUNT,
+        &[OHX_AUTH_JWKS],
         None::<&[&str]>,
     )?;
 
-    let credentials_list = vec![google_credentials, openhabx_credentials];
+    let credentials_list = vec![openhabx_credentials];
 
-    let firebase_credentials = DBCredentials::new(
-        include_str!("../secrets/firebase-account.json"),
-        &[
-            include_str!("../secrets/openhabx-device@openhabx.iam.gserviceaccount.com.json"),
-            include_str!("../secrets/securetoken@system.gserviceaccount.com.json"),
-        ],
-    )?;
+    let deny_list = TokenDenyList::new(tokens);
+    // Required for looking up firebase users by id
+    let firebase_credentials = firebase_credentials(config_source)?;
 
-    let firebase = Mutex::new(SASession::new(firebase_credentials.clone())?);
+    let bt = braintreepayment_graphql::Braintree::new(serde_json::from_str(&config_source.get(BRAINTREE_CREDENTIALS)?)?);
 
-    let bt = braintreepayment_graphql::Braintree::new(serde_json::from_str(include_str!("../secrets/braintree.json"))?);
+    let cors_policy = CorsPolicy::new(OHX_ORIGINS)
+        .with_methods(&[Method::Get])
+        .with_headers(&["authorization"])
+        .with_max_age(3600)
+        .with_origins_from_config(config_source)?;
+    let trusted_proxies = TrustedProxies::from_config(config_source)?;
 
     let config = Config::build(Environment::Development)
         .port(
@@ -67,11 +91,6 @@ pub fn create_rocket(rate_limit: u32) -> Result<rocket::Rocket, failure::Error>
     #[cfg(debug_assertions)]
         {
             info!("Listening on http://localhost:{}", config.port);
-            info!(
-                "Google 1h access code for scopes: {:?}\n\t{}",
-                _g_scopes.get_scopes(),
-                &_g_access_token
-            );
             info!(
                 "OHX 1h access code for scopes: {:?}\n\t{}",
                 _ohx_scopes.get_scopes(),
@@ -82,9 +101,13 @@ pub fn create_rocket(rate_limit: u32) -> Result<rocket::Rocket, failure::Error>
     Ok(rocket::custom(config)
         .manage(credentials_list)
         .manage(lim)
+        .attach(CorsFairing::new(cors_policy))
+        .attach(guard_rate_limiter::RateLimitHeaders)
         .manage(bt)
-        .manage(firebase)
+        .manage(documents)
+        .manage(deny_list)
         .manage(firebase_credentials)
+        .manage(trusted_proxies)
         .register(catchers![
             error_routes::not_found,
             error_routes::access_denied,
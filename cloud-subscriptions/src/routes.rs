@@ -4,10 +4,11 @@ use crate::responder_type::MyResponder;
 
 // External, controlled libraries
 use cloud_vault::{
-    guard_oauth_jwt_access, guard_rate_limiter::RateLimiter,
+    guard_oauth_jwt_access, guard_rate_limiter::{IdentityRateLimiter, RateLimiter},
 };
+use cloud_auth_lib::storage::DocumentStore;
 use firestore_db_and_auth::{
-    rocket::FirestoreAuthSessionGuard, sessions::service_account::Session as SASession, documents,
+    rocket::FirestoreAuthSessionGuard, credentials::Credentials as DBCredentials,
 };
 
 #[allow(unused_imports)]
@@ -17,21 +18,17 @@ use log::{debug, error, info, trace, warn};
 use rocket::{get};
 
 // std
-use std::ops::Deref;
-use std::sync::Mutex;
+use std::sync::Arc;
 
 use braintreepayment_graphql::Braintree;
 
-const CREDENTIALS_GOOGLE_SERVICE_ACCOUNT_INDEX: usize = 0;
-const CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX: usize = 1;
-
 /// Empty default route
 #[get("/")]
 pub fn index() -> &'static str {
     ""
 }
 
-fn client_token_internal(session: &SASession, braintree: &Braintree, doc: db::UserEntry, user_id: &str,
+fn client_token_internal(store: &dyn DocumentStore, braintree: &Braintree, doc: db::UserEntry, user_id: &str,
                          user_email: impl Fn() -> Result<String, MyResponder>) -> Result<String, MyResponder> {
     use braintreepayment_graphql::queries::customer::{customer_client_token, create_customer};
 
@@ -52,7 +49,7 @@ fn client_token_internal(session: &SASession, braintree: &Braintree, doc: db::Us
                 let doc = db::UserEntry {
                     braintree_customer_id: Some(id.clone())
                 };
-                documents::write(session, "users", Some(&user_id), &doc, documents::WriteOptions { merge: true })?;
+                store.merge("users", &user_id, &doc)?;
                 id
             } else {
                 return Err(MyResponder::InternalError("Could not create a customer".to_owned()));
@@ -72,6 +69,10 @@ fn client_token_internal(session: &SASession, braintree: &Braintree, doc: db::Us
     Err(MyResponder::InternalError("Got no token from Braintree".to_owned()))
 }
 
+fn read_user_entry(store: &dyn DocumentStore, user_id: &str) -> Result<db::UserEntry, MyResponder> {
+    store.read("users", user_id)?.ok_or_else(|| MyResponder::NotFound("User entry not found".to_owned()))
+}
+
 fn get_email_for_firebase_auth_user(session: &firestore_db_and_auth::UserSession) -> Result<String, MyResponder> {
     let response = firestore_db_and_auth::users::user_info(session)?.users;
     for entry in response {
@@ -86,25 +87,23 @@ fn get_email_for_firebase_auth_user(session: &firestore_db_and_auth::UserSession
 #[get("/client_token")]
 pub fn client_token(
     oauth_user: guard_oauth_jwt_access::OAuthIdentity,
-    firebase: rocket::State<Mutex<SASession>>,
+    store: rocket::State<Arc<dyn DocumentStore>>,
+    firebase_credentials: rocket::State<DBCredentials>,
     braintree: rocket::State<Braintree>,
-    _rate_limiter: RateLimiter,
+    _rate_limiter: IdentityRateLimiter,
 ) -> Result<String, MyResponder> {
-    // Only the google account is allowed to call this endpoint
-    if oauth_user.credentials_index != CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX || oauth_user.user_id.is_none() {
+    // Only users are allowed to call this endpoint, not service accounts
+    if oauth_user.user_id.is_none() {
         return Err(MyResponder::AccessScopeInsufficient(
             "Only an OHX account is allowed to call this endpoint".to_owned(),
         ));
     }
 
-    let session_mutex = firebase.lock()?;
-    let session: &SASession = session_mutex.deref();
-
     let user_id = oauth_user.user_id.unwrap();
-    let doc: db::UserEntry = documents::read(session, "users", &user_id)?;
+    let doc = read_user_entry(&**store, &user_id)?;
 
-    client_token_internal(session, &braintree, doc, &user_id, || {
-        let user_session = firestore_db_and_auth::UserSession::by_user_id(&session.credentials, &user_id, false)?;
+    client_token_internal(&**store, &braintree, doc, &user_id, || {
+        let user_session = firestore_db_and_auth::UserSession::by_user_id(&firebase_credentials, &user_id, false)?;
         get_email_for_firebase_auth_user(&user_session)
     })
 }
@@ -113,16 +112,13 @@ pub fn client_token(
 #[get("/client_token", rank = 2)]
 pub fn client_token_fire_auth(
     firestore_auth: FirestoreAuthSessionGuard,
-    firebase: rocket::State<Mutex<SASession>>,
+    store: rocket::State<Arc<dyn DocumentStore>>,
     braintree: rocket::State<Braintree>,
     _rate_limiter: RateLimiter,
 ) -> Result<String, MyResponder> {
-    let session_mutex = firebase.lock()?;
-    let session: &SASession = session_mutex.deref();
-
-    let doc: db::UserEntry = documents::read(session, "users", &firestore_auth.0.user_id)?;
+    let doc = read_user_entry(&**store, &firestore_auth.0.user_id)?;
 
-    client_token_internal(session, &braintree, doc, &firestore_auth.0.user_id, || get_email_for_firebase_auth_user(&firestore_auth.0))
+    client_token_internal(&**store, &braintree, doc, &firestore_auth.0.user_id, || get_email_for_firebase_auth_user(&firestore_auth.0))
 }
 
 #[get("/client_token", rank = 3)]
@@ -134,7 +130,7 @@ pub fn client_token_unauthorized() -> MyResponder {
 #[get("/confirm")]
 pub fn confirm(
     _oauth_user: guard_oauth_jwt_access::OAuthIdentity,
-    _firebase: rocket::State<Mutex<SASession>>,
+    _store: rocket::State<Arc<dyn DocumentStore>>,
     _braintree: rocket::State<Braintree>,
 ) -> Result<String, MyResponder> {
     Ok(String::new())
@@ -151,7 +147,7 @@ pub fn confirm_unauthorized() -> MyResponder {
 #[get("/check_payments")]
 pub fn check_payments(
     _oauth_user: guard_oauth_jwt_access::OAuthIdentity,
-    _firebase: rocket::State<Mutex<SASession>>,
+    _store: rocket::State<Arc<dyn DocumentStore>>,
     _braintree: rocket::State<Braintree>,
 ) -> Result<String, MyResponder> {
     Ok(String::new())
@@ -2,14 +2,14 @@
 
 use cloud_subscription::*;
 
-use cloud_vault::credentials::Credentials;
+use cloud_auth_lib::config::ConfigSource;
+use cloud_auth_lib::storage::{MemoryDocumentStore, MemoryTokenStore};
+use std::sync::Arc;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use serde::{ Deserialize};
 
-use firestore_db_and_auth::{credentials::Credentials as DBCredentials, sessions::service_account::Session as SASession};
-
 #[derive(Deserialize)]
 pub struct ErrorResult {
     pub error: String,
@@ -23,27 +23,8 @@ impl From<String> for ErrorResult {
 
 #[test]
 fn integration() -> Result<(), failure::Error> {
-    let rocket = create_rocket(100)?;
-
-    let firebase_credentials = DBCredentials::new(
-        include_str!("../secrets/firebase-account.json"),
-        &[
-            include_str!("../secrets/openhabx-device@openhabx.iam.gserviceaccount.com.json"),
-            include_str!("../secrets/securetoken@system.gserviceaccount.com.json"),
-        ],
-    )?;
-
-    let _firebase = SASession::new(firebase_credentials)?;
-
-
-    let (_, _g_access_token, _) = Credentials::load_and_check(
-        include_str!("../secrets/google-ci-key.json"),
-        &[
-            include_str!("../secrets/securetoken@system.gserviceaccount.com.json"),
-            include_str!("../secrets/travisci-deployer@openhabx.iam.gserviceaccount.com.json"),
-        ],
-        None::<&[&str]>,
-    )?;
+    let config_source = ConfigSource::from_env();
+    let rocket = create_rocket(100, &config_source, Arc::new(MemoryDocumentStore::new()), Arc::new(MemoryTokenStore::new()))?;
 
     let _client = rocket::local::Client::new(rocket).expect("valid rocket instance");
 
@@ -19,25 +19,28 @@ use std::env;
 use rocket::http::RawStr;
 use signal_hook::{iterator::Signals, SIGINT, SIGKILL, SIGTERM, SIGHUP, SIGQUIT};
 use std::ops::Deref;
-use rocket::http::Status;
+use rocket::http::{Method, Status};
 use stackdriver_logger;
 #[allow(unused_imports)]
 use log::{error, info, trace, debug, warn};
 
-use include_dir::Dir;
-use cloud_auth_lib::{guard_rate_limiter, Credentials, fairing_cors, catch_all};
-
-const SECRETS_DIR: Dir = include_dir!("../secrets");
-
-// Embed the allowed oauth clients
+use cloud_auth_lib::{guard_rate_limiter, fairing_cors, catch_all};
+use cloud_auth_lib::audit::AuditLog;
+use cloud_auth_lib::config::ConfigSource;
+use cloud_auth_lib::deny_list::TokenDenyList;
+use cloud_auth_lib::fairing_cors::{CorsPolicy, OHX_ORIGINS};
+use cloud_auth_lib::guard_ip_addr::TrustedProxies;
+use cloud_auth_lib::rate_limit::{MemoryRateLimitBackend, RateLimits};
+use cloud_auth_lib::storage::RedisTokenStore;
+use std::sync::Arc;
+
+// Embed the default access scopes. Used if "access_scopes.json" is not configured.
 const ACCESS_SCOPES: &'static str = include_str!("../../data/access_scopes.json");
 
-// Include all kind of keys from the secret directory
-const KEY_GOOGLE_TRAVIS: &'static str = include_str!("../../secrets/travisci-deployer@openhabx.iam.gserviceaccount.com.key");
-const GOOGLE_SERVICE_ACCOUNT_ST: &'static str = include_str!("../../secrets/securetoken@system.gserviceaccount.com.json");
-const GOOGLE_SERVICE_ACCOUNT_TRAVIS: &'static str = include_str!("../../secrets/travisci-deployer@openhabx.iam.gserviceaccount.com.json");
-const OHX_ADMIN_ACCOUNT: &'static str = include_str!("../../secrets/ohx_oauth_key.key");
-const OHX_AUTH_JWKS: &'static str = include_str!("../../secrets/ohx_oauth_key.json");
+// The names of the keys in the runtime configuration (secrets directory or environment)
+const ACCESS_SCOPES_FILE: &'static str = "access_scopes.json";
+const OHX_ADMIN_ACCOUNT: &'static str = "ohx_oauth_key.key";
+const OHX_AUTH_JWKS: &'static str = "ohx_oauth_key.json";
 
 /// Start rocket. A few states need to be initialized first.
 fn main() -> Result<(), failure::Error> {
@@ -57,18 +60,31 @@ fn main() -> Result<(), failure::Error> {
         }
     });
 
-    let access_scopes = AccessScopes::new(ACCESS_SCOPES)?;
+    // The served secrets and the credentials are read from the secrets directory (SECRETS_DIR)
+    let config_source = ConfigSource::from_env();
 
-    // Rate limit: Allow 5 units per second
-    let lim = guard_rate_limiter::RateLimiterMutex::new(5u32);
+    let access_scopes = AccessScopes::new(&config_source.optional(ACCESS_SCOPES_FILE)?.unwrap_or(ACCESS_SCOPES.to_owned()))?;
 
-    let (google_credentials, _g_access_token, _g_scopes) =
-        Credentials::load_and_check(KEY_GOOGLE_TRAVIS, &[GOOGLE_SERVICE_ACCOUNT_ST, GOOGLE_SERVICE_ACCOUNT_TRAVIS], None::<&[&str]>)?;
+    // Rate limit: Allow 5 units per second and client ip, if not configured otherwise
+    let lim = guard_rate_limiter::RateLimiterState::with_backend(
+        Arc::new(MemoryRateLimitBackend::new()), RateLimits::from_config(&config_source, 5u32)?);
 
+    // Only tokens of the auth service are accepted. The cron job uses its client credentials grant.
     let (openhabx_credentials, _ohx_access_token, _ohx_scopes) =
-        Credentials::load_and_check(OHX_ADMIN_ACCOUNT, &[OHX_AUTH_JWKS], None::<&[&str]>)?;
+        config_source.credentials(OHX_ADMIN_ACCOUNT, &[OHX_AUTH_JWKS], None::<&[&str]>)?;
+
+    let credentials_list = vec![openhabx_credentials];
 
-    let credentials_list = vec![google_credentials, openhabx_credentials];
+    let cors_policy = CorsPolicy::new(OHX_ORIGINS)
+        .with_methods(&[Method::Get])
+        .with_headers(&["authorization"])
+        .with_max_age(3600)
+        .with_origins_from_config(&config_source)?;
+    let trusted_proxies = TrustedProxies::from_config(&config_source)?;
+    // The vault has no document store. Events go to stdout unless configured otherwise.
+    let audit_log = AuditLog::from_config(&config_source, "cloud-vault", None)?;
+    // Access tokens revoked by the auth service are rejected
+    let deny_list = TokenDenyList::new(Arc::new(RedisTokenStore::from_config(&config_source)?));
 
     let config = Config::build(Environment::Development)
         .port(env::var("PORT").unwrap_or("8080".to_owned()).parse::<u16>()?)
@@ -80,15 +96,19 @@ fn main() -> Result<(), failure::Error> {
         {
             info!("Listening on http://localhost:{}", config.port);
             info!("Access scopes {:?}", &access_scopes.0);
-            info!("Google 1h access code for scopes: {:?}\n\t{}", _g_scopes.get_scopes(), &_g_access_token);
             info!("OHX 1h access code for scopes: {:?}\n\t{}", _ohx_scopes.get_scopes(), &_ohx_access_token);
         }
 
     rocket::custom(config)
-        .attach(fairing_cors::CorsFairing)
+        .attach(fairing_cors::CorsFairing::new(cors_policy))
+        .attach(guard_rate_limiter::RateLimitHeaders)
         .manage(credentials_list)
         .manage(lim)
         .manage(access_scopes)
+        .manage(trusted_proxies)
+        .manage(audit_log)
+        .manage(deny_list)
+        .manage(config_source)
         .register(catchers![error_routes::not_found, error_routes::access_denied, error_routes::not_authorized, error_routes::error_rate_limit])
         .mount("/", routes![index, retrieve_oauth, retrieve_not_authorized, renew, renew_unauthorized, list, list_not_authorized])
         .mount("/", catch_all::catch_rest())
@@ -98,7 +118,7 @@ fn main() -> Result<(), failure::Error> {
 
 #[test]
 fn check_credentials() -> Result<(), failure::Error> {
-    Credentials::load_and_check(KEY_GOOGLE_TRAVIS, &[GOOGLE_SERVICE_ACCOUNT_ST, GOOGLE_SERVICE_ACCOUNT_TRAVIS], None::<&[&str]>)?;
-    Credentials::load_and_check(OHX_ADMIN_ACCOUNT, &[OHX_AUTH_JWKS], None::<&[&str]>)?;
+    let config_source = ConfigSource::new(env::var("SECRETS_DIR").unwrap_or("../secrets".to_owned()));
+    config_source.credentials(OHX_ADMIN_ACCOUNT, &[OHX_AUTH_JWKS], None::<&[&str]>)?;
     Ok(())
 }
\ No newline at end of file
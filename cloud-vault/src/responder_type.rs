@@ -22,6 +22,12 @@ impl From<failure::Error> for MyResponder {
     }
 }
 
+impl From<cloud_auth_lib::CloudAuthError> for MyResponder {
+    fn from(err: cloud_auth_lib::CloudAuthError) -> MyResponder {
+        MyResponder::InternalError(err.to_string())
+    }
+}
+
 impl From<serde_json::Error> for MyResponder {
     fn from(err: serde_json::Error) -> MyResponder {
         MyResponder::InternalError(err.to_string())
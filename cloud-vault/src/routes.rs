@@ -1,13 +1,16 @@
 use cloud_auth_lib::guard_oauth_jwt_access;
+use cloud_auth_lib::dto::oauth::SCOPE_ADMIN;
 use crate::responder_type::MyResponder;
-use crate::{SECRETS_DIR, travis};
+use crate::travis;
+use cloud_auth_lib::audit::AuditEvent;
+use cloud_auth_lib::config::ConfigSource;
+use cloud_auth_lib::guard_audit::Auditor;
 use cloud_auth_lib::guard_ip_addr::ClientRealAddr;
-use cloud_auth_lib::guard_rate_limiter::RateLimiter;
+use cloud_auth_lib::guard_rate_limiter::IdentityRateLimiter;
 use crate::access_scopes::AccessScopes;
 use std::ops::Deref;
 
-const CREDENTIALS_GOOGLE_SERVICE_ACCOUNT_INDEX: usize = 0;
-const CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX: usize = 1;
+const CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX: usize = 0;
 
 /// Empty default route
 #[get("/")]
@@ -20,16 +23,18 @@ pub fn index() -> &'static str {
 ///
 /// A token is valid for 6 hours. A cron job must call this endpoint periodically.
 #[get("/renew")]
-pub fn renew(oauth_user: guard_oauth_jwt_access::OAuthIdentity, credentials_list: rocket::State<Vec<credentials::Credentials>>) -> Result<String, MyResponder> {
-    // Only the google account is allowed to call this endpoint
-    if oauth_user.credentials_index != CREDENTIALS_GOOGLE_SERVICE_ACCOUNT_INDEX {
-        return Err(MyResponder::AccessScopeInsufficient("Only the google CI account is allowed to call this endpoint".to_owned()));
+pub fn renew(oauth_user: guard_oauth_jwt_access::OAuthIdentity, credentials_list: rocket::State<Vec<credentials::Credentials>>,
+             config_source: rocket::State<ConfigSource>) -> Result<String, MyResponder> {
+    // Only service accounts (client credentials grant) with the admin scope are allowed to call this endpoint
+    if !oauth_user.scopes.contains(SCOPE_ADMIN) {
+        return Err(MyResponder::AccessScopeInsufficient("Requires the admin scope".to_owned()));
     }
 
     let repositories: Vec<String> = serde_json::from_str(include_str!("../repositories.json"))?;
-    let travis_token = SECRETS_DIR.get_file("travis-token.txt").unwrap().contents_utf8().unwrap();
-    let credentials = credentials_list.get(CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX).unwrap();
-    let response = travis::set_env_var(travis_token, repositories, credentials)?;
+    let travis_token = config_source.get("travis-token.txt")?;
+    let credentials = credentials_list.get(CREDENTIALS_OHX_SERVICE_ACCOUNT_INDEX)
+        .ok_or(MyResponder::InternalError("No signing credentials".to_owned()))?;
+    let response = travis::set_env_var(&travis_token, repositories, credentials)?;
 
     Ok(response)
 }
@@ -41,30 +46,37 @@ pub fn retrieve_oauth(id: &RawStr, auth: Option<&RawStr>,
                   oauth: guard_oauth_jwt_access::OAuthIdentity,
                   client_addr: ClientRealAddr,
                   access_scopes: rocket::State<AccessScopes>,
-                  rate_limiter: RateLimiter) -> Result<&'static str, MyResponder> {
+                  config_source: rocket::State<ConfigSource>,
+                  auditor: Auditor,
+                  rate_limiter: IdentityRateLimiter) -> Result<String, MyResponder> {
     let id = id.as_str();
+    let audit = |event: AuditEvent| event.with_user(oauth.user_id.as_ref()).with_client(oauth.client_id.as_ref()).with_target(id);
     match access_scopes.deref().0.get(id) {
         Some(v) => {
             for scope in &oauth.scopes {
                 if v.contains(scope) {
                     // Access the requested file or return a file not found
-                    let filename = SECRETS_DIR.get_file(id).ok_or(MyResponder::NotFound(format!("File not found {}", id)))?;
-                    return Ok(filename.contents_utf8().unwrap());
+                    let content = config_source.optional(id)?;
+                    if content.is_some() {
+                        auditor.record(audit(AuditEvent::success("vault.retrieve")));
+                    }
+                    return content.ok_or(MyResponder::NotFound(format!("File not found {}", id)));
                 };
             }
         }
         _ => {}
     };
+    auditor.record(audit(AuditEvent::failure("vault.retrieve")));
     Err(MyResponder::AccessScopeInsufficient(format!("Your access token does not allow access to {}. You need one of {:?}", id, &oauth.scopes)))
 }
 
 #[allow(unused_variables)]
 #[get("/list?<auth>")]
-pub fn list(auth: Option<&RawStr>, _oauth_user: guard_oauth_jwt_access::OAuthIdentity) -> Result<String, failure::Error> {
+pub fn list(auth: Option<&RawStr>, _oauth_user: guard_oauth_jwt_access::OAuthIdentity,
+            config_source: rocket::State<ConfigSource>) -> Result<String, MyResponder> {
     let mut response = String::new();
-    for entry in SECRETS_DIR.files() {
-        let path = entry.path();
-        response += path.to_str().unwrap_or("");
+    for name in config_source.list()? {
+        response += &name;
         response += "\n";
     }
     Ok(response)
@@ -48,8 +48,11 @@ strip ohx-app && ./target/upx ohx-app
 gcloud config set builds/use_kaniko False
 gcloud builds submit --timeout=2m --tag "gcr.io/${PROJECT_ID}/${APP}"
 rm ohx-app
-# Deploy new image and delete all but the :latest container images
-gcloud beta run deploy --image "gcr.io/${PROJECT_ID}/${APP}" --platform managed --region us-central1 --allow-unauthenticated "${APP}" --memory 128Mi --timeout 60
+# Deploy new image and delete all but the :latest container images.
+# Cloud Run forwards requests via its front end, the client addresses are only known from the forwarded headers.
+# "^@^" selects another list delimiter, because the json array contains commas.
+gcloud beta run deploy --image "gcr.io/${PROJECT_ID}/${APP}" --platform managed --region us-central1 --allow-unauthenticated "${APP}" --memory 128Mi --timeout 60 \
+    --update-env-vars "^@^TRUSTED_PROXIES_JSON=$(cat scripts/trusted_proxies.json)"
 gcloud container images list-tags "gcr.io/${PROJECT_ID}/${APP}" --filter='-tags:*' --format='get(digest)' --limit=unlimited | \
     awk -v "PROJECT_ID=$PROJECT_ID" -v "APP=$APP" '{print "gcr.io/" PROJECT_ID "/" APP "@" $1}' | xargs gcloud container images delete --quiet
 